@@ -3,7 +3,10 @@ fn main() {
 
     println!("cargo:rerun-if-changed={}", src);
     let file = std::fs::File::open(src).unwrap();
-    let spec = serde_json::from_reader(file).unwrap();
+    let mut spec: serde_json::Value = serde_json::from_reader(file).unwrap();
+    dedent_descriptions(&mut spec);
+
+    let spec = serde_json::from_value(spec).unwrap();
     let mut generator = progenitor::Generator::default();
 
     let tokens = generator.generate_tokens(&spec).unwrap();
@@ -15,3 +18,39 @@ fn main() {
 
     std::fs::write(out_file, content).unwrap();
 }
+
+/// Descriptions become doc comments on the generated code, where an indented description would be
+/// compiled by rustdoc as a doctest.  Removes the indentation common to every line of each
+/// description, along with leading and trailing blank lines.
+fn dedent_descriptions(value: &mut serde_json::Value) {
+    match value {
+        serde_json::Value::Object(object) => {
+            for (key, value) in object.iter_mut() {
+                match value {
+                    serde_json::Value::String(description) if key == "description" => {
+                        *description = dedent(description);
+                    }
+                    value => dedent_descriptions(value),
+                }
+            }
+        }
+        serde_json::Value::Array(array) => array.iter_mut().for_each(dedent_descriptions),
+        _ => {}
+    }
+}
+
+fn dedent(text: &str) -> String {
+    let indent = text
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| line.len() - line.trim_start().len())
+        .min()
+        .unwrap_or(0);
+
+    text.lines()
+        .map(|line| line.get(indent..).unwrap_or("").trim_end())
+        .collect::<Vec<_>>()
+        .join("\n")
+        .trim_matches('\n')
+        .to_string()
+}
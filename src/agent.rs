@@ -1,28 +1,211 @@
-use crate::api::generated::types::{McpToolName, McpToolResult, TelemetryTarget};
+use crate::agent_loop::DEFAULT_ITERATION_TOOL_QUOTA;
+use crate::agent_options::AgentOptionsBuilder;
+use crate::api::generated::types::{AgentClaimAmount, McpToolName, McpToolResult, TelemetryTarget};
 use crate::claim_manager::ClaimManager;
 use crate::completion_evaluated_prompt::CompletionEvaluatedPrompt;
-use crate::error::Error;
+use crate::error::{AgentError, Error};
 use crate::mcp_server::McpServerConnection;
-use crate::telemetry::{TelemetryIdentifier, TelemetryMode, TelemetryRequest};
+use crate::resource_tool::ReadResourceTool;
+use crate::retry::RetryPolicy;
+use crate::session_recorder::{RecordedCompletion, RecordedToolCall, SessionRecorder, ToolReplay};
+use crate::telemetry::{
+    DEFAULT_TELEMETRY_ATTEMPTS, TelemetryDropPolicy, TelemetryIdentifier, TelemetryMode,
+    TelemetryQueue, TelemetryRequest,
+};
+use futures::channel::mpsc::{self, UnboundedSender};
+use futures::{FutureExt, Stream, StreamExt, TryStreamExt, future, stream};
+use regex::Regex;
 use rig::OneOrMany;
-use rig::completion::{AssistantContent, Completion, CompletionModel, Message};
-use rig::message::UserContent;
-use rig::tool::ToolDyn;
-use std::collections::HashSet;
+use rig::completion::{
+    AssistantContent, Completion, CompletionError, CompletionModel, CompletionRequest,
+    CompletionResponse, GetTokenUsage, Message, Usage,
+};
+use rig::message::{ToolCall, UserContent};
+use rig::streaming::{StreamedAssistantContent, StreamingCompletionResponse};
+use rig::tool::{Tool, ToolDyn};
+use ring::hmac;
+use serde::de::DeserializeOwned;
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, Instant};
+use tokio::task::JoinHandle;
 use tracing::{info, warn};
 
+///
+/// A function that parses the output of a tool call into the [`TelemetryTarget`]s that telemetry
+/// should be attached to.  See [`Agent::telemetry_extractor`].
+pub type TelemetryExtractor = Box<dyn Fn(&str) -> Vec<TelemetryTarget> + Send + Sync>;
+
+///
+/// A function that chooses one of several candidate completions by index, given the text of each
+/// candidate.  See [`Agent::candidate_selector`].
+pub type CandidateSelector = Box<dyn Fn(&[String]) -> usize + Send + Sync>;
+
+///
+/// Called with the message history while a completion's tool calls are running.  See
+/// [`Agent::on_tool_checkpoint`].
+pub type ToolCheckpoint = Box<dyn Fn(&[Message]) + Send + Sync>;
+
+///
+/// Called with the message history a completion will return, which can be changed.  See
+/// [`Agent::on_history`].
+pub type HistoryHook = Box<dyn Fn(&mut Vec<Message>) + Send + Sync>;
+
+///
+/// A predicate deciding whether a tool from an MCP server should be given to the completion agent.
+/// See [`Agent::filter_tools`].
+pub type ToolFilter = Box<dyn Fn(&str) -> bool + Send + Sync>;
+
 pub struct Agent<M: CompletionModel> {
     completion_agent: rig::agent::Agent<M>,
+    fallback_models: Vec<(M, String)>,
     mcp_connections: Vec<ValidatedMcpServerConnection>,
     revalidating_tooling: HashSet<String>,
+    tool_filter: Option<ToolFilter>,
     agent_name: String,
     agent_version: String,
     telemetry: TelemetryMode,
     telemetry_url: String,
+    telemetry_url_override: Option<String>,
     telemetry_session_id: String,
     telemetry_model_description: String,
+    telemetry_extractors: HashMap<String, TelemetryExtractor>,
+    telemetry_compression: bool,
+    telemetry_strict: bool,
+    telemetry_attempts: u32,
+    telemetry_signing_key: Option<hmac::Key>,
+    telemetry_redactions: Vec<Regex>,
+    telemetry_queue: Option<TelemetryQueue>,
+    telemetry_batch: Option<usize>,
+    pending_telemetry: Option<PendingTelemetry>,
+    last_telemetry: Option<PendingTelemetry>,
     preamble: Option<CompletionEvaluatedPrompt>,
+    preamble_byte_budget: Option<usize>,
     claim_manager: Option<ClaimManager>,
+    heartbeat: Option<Duration>,
+    heartbeat_task: Option<HeartbeatTask>,
+    tool_call_echo: Option<ToolCallEcho>,
+    session_recorder: Option<SessionRecorder>,
+    tool_replay: Option<ToolReplay>,
+    tool_checkpoint: Option<ToolCheckpoint>,
+    history_hook: Option<HistoryHook>,
+    text_join_strategy: TextJoinStrategy,
+    max_concurrent_tool_calls: usize,
+    tool_call_timeout: Option<Duration>,
+    tool_timeout_policy: ToolTimeoutPolicy,
+    candidates: usize,
+    candidate_selector: Option<CandidateSelector>,
+    retry_policy: RetryPolicy,
+    response_format: Option<serde_json::Value>,
+    prompt_caching: PromptCaching,
+    extract_tool_quota: Option<u32>,
+    interrupted_messages: Option<Vec<Message>>,
+}
+
+///
+/// Which provider's cache-control hints are sent with completion requests.  See
+/// [`Agent::prompt_caching`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum PromptCaching {
+    ///
+    /// No cache-control hints are sent.  Providers that cache prompts automatically (such as
+    /// OpenAI) still do.
+    #[default]
+    None,
+
+    ///
+    /// The preamble is sent as an Anthropic system block marked with an `ephemeral`
+    /// cache-control, which caches the tool definitions and preamble across completions
+    Anthropic,
+}
+
+///
+/// How multiple text parts in one completion response are combined into
+/// [`CompletionResult::texts`].  Some providers split text across parts at arbitrary points, while
+/// others use separate parts for separate paragraphs.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum TextJoinStrategy {
+    ///
+    /// Every text part is kept as a separate entry
+    #[default]
+    Separate,
+
+    ///
+    /// Text parts are concatenated with nothing between them, producing one entry
+    Concatenate,
+
+    ///
+    /// Text parts are joined with a newline, producing one entry
+    Newline,
+}
+
+///
+/// What happens when a tool call takes longer than [`Agent::tool_call_timeout`]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ToolTimeoutPolicy {
+    ///
+    /// The tool result tells the model that the call timed out, so that it can recover, for
+    /// example, by trying again or using a different tool
+    #[default]
+    ReportToModel,
+
+    ///
+    /// The completion fails with [`Error::ToolTimeout`]
+    Fail,
+}
+
+///
+/// Telemetry waiting to be sent as one batch (see [`Agent::telemetry_batch`]), or the most
+/// recently sent telemetry.
+struct PendingTelemetry {
+    targets: Vec<TelemetryTarget>,
+    messages: Vec<Message>,
+    model_description: String,
+}
+
+///
+/// Posts summaries of tool calls to a Coral thread.  See [`Agent::echo_tool_calls`].
+struct ToolCallEcho {
+    connection: McpServerConnection,
+    thread_id: String,
+    min_interval: Duration,
+    pending: Vec<String>,
+    last_post: Option<Instant>,
+}
+
+///
+/// A running heartbeat task.  The task is aborted when this is dropped, which happens when the
+/// owning [`Agent`] is dropped.
+struct HeartbeatTask(JoinHandle<()>);
+
+impl Drop for HeartbeatTask {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+///
+/// An event from [`Agent::stream_completion`]
+#[derive(Debug)]
+pub enum StreamEvent {
+    /// Text from the model, as it is generated
+    Text(String),
+
+    /// A tool call from the model has started running
+    ToolCallStarted {
+        id: String,
+        name: String,
+        arguments: serde_json::Value,
+    },
+
+    /// A tool call has finished
+    ToolResult {
+        id: String,
+        name: String,
+        output: String,
+    },
+
+    /// The completion has finished.  This is always the last event
+    Done(CompletionResult),
 }
 
 struct ValidatedMcpServerConnection {
@@ -30,6 +213,7 @@ struct ValidatedMcpServerConnection {
     tools_validated: bool,
 }
 
+#[derive(Debug)]
 pub struct CompletionResult {
     /// Entire message history
     pub messages: Vec<Message>,
@@ -39,6 +223,21 @@ pub struct CompletionResult {
 
     /// Quantity of tools used. If this is non-zero, it is likely texts are empty.
     pub tools_used: u32,
+
+    /// The names of the tools that were called, in the order they were called
+    pub tools_called: Vec<String>,
+
+    /// Token usage reported by the provider for this completion.  All zeros if the provider did
+    /// not report usage
+    pub usage: Usage,
+
+    /// The cost of [`CompletionResult::usage`] according to the token costs of the attached claim
+    /// manager.  None if there is no claim manager, see [`ClaimManager::estimate_token_cost`]
+    pub estimated_cost: Option<AgentClaimAmount>,
+
+    /// The text of every candidate completion, if more than one was requested (see
+    /// [`Agent::candidates`]).  Empty otherwise
+    pub candidates: Vec<String>,
 }
 
 impl<M: CompletionModel> Agent<M> {
@@ -47,16 +246,48 @@ impl<M: CompletionModel> Agent<M> {
     pub fn new(completion_agent: rig::agent::Agent<M>) -> Self {
         Self {
             completion_agent,
+            fallback_models: Vec::new(),
             mcp_connections: Vec::new(),
             revalidating_tooling: HashSet::new(),
+            tool_filter: None,
             agent_name: env!("CARGO_PKG_NAME").to_string(),
             agent_version: env!("CARGO_PKG_VERSION").to_string(),
             telemetry: TelemetryMode::None,
             telemetry_url: String::new(),
+            telemetry_url_override: None,
             telemetry_session_id: String::new(),
             telemetry_model_description: String::new(),
+            telemetry_extractors: HashMap::new(),
+            telemetry_compression: false,
+            telemetry_strict: false,
+            telemetry_attempts: DEFAULT_TELEMETRY_ATTEMPTS,
+            telemetry_signing_key: None,
+            telemetry_redactions: Vec::new(),
+            telemetry_queue: None,
+            telemetry_batch: None,
+            pending_telemetry: None,
+            last_telemetry: None,
             preamble: None,
+            preamble_byte_budget: None,
             claim_manager: None,
+            heartbeat: None,
+            heartbeat_task: None,
+            tool_call_echo: None,
+            session_recorder: None,
+            tool_replay: None,
+            tool_checkpoint: None,
+            history_hook: None,
+            text_join_strategy: TextJoinStrategy::default(),
+            max_concurrent_tool_calls: 1,
+            tool_call_timeout: None,
+            tool_timeout_policy: ToolTimeoutPolicy::default(),
+            candidates: 1,
+            candidate_selector: None,
+            retry_policy: RetryPolicy::none(),
+            response_format: None,
+            prompt_caching: PromptCaching::default(),
+            extract_tool_quota: DEFAULT_ITERATION_TOOL_QUOTA,
+            interrupted_messages: None,
         }
     }
 
@@ -85,6 +316,45 @@ impl<M: CompletionModel> Agent<M> {
         self
     }
 
+    ///
+    /// Lets the model read resources from an MCP server on demand by adding a
+    /// [`ReadResourceTool`] to the completion agent.  Use this with
+    /// [`CompletionEvaluatedPrompt::resource_index`] in the preamble instead of
+    /// [`CompletionEvaluatedPrompt::all_resources`], so that the model is told which resources
+    /// exist and pulls only the ones it needs, rather than every resource being included in every
+    /// completion request.
+    ///
+    /// The tool is always named `read_resource`, so only one MCP server can be read lazily.
+    /// Calling this again replaces the previous server.
+    pub fn lazy_resources(mut self, connection: McpServerConnection) -> Self {
+        let tool = connection.read_resource_tool();
+        if !self
+            .completion_agent
+            .static_tools
+            .iter()
+            .any(|name| name == ReadResourceTool::NAME)
+        {
+            self.completion_agent
+                .static_tools
+                .push(ReadResourceTool::NAME.to_string());
+        }
+
+        self.completion_agent.tools.add_tool(tool);
+        self
+    }
+
+    ///
+    /// Sets a predicate that decides, by name, whether each tool from the MCP servers connected to
+    /// this agent is given to the completion agent.  Tools for which the predicate returns false
+    /// are never registered.  The predicate is applied whenever tooling is validated, so it can
+    /// consult runtime configuration.
+    ///
+    /// Tools added directly to the underlying completion agent are not filtered.
+    pub fn filter_tools(mut self, filter: impl Fn(&str) -> bool + Send + Sync + 'static) -> Self {
+        self.tool_filter = Some(Box::new(filter));
+        self
+    }
+
     ///
     /// Sets the preamble for this agent to a specific [`CompletionEvaluatedPrompt`] instance.  Note
     /// that if this is not set, the default string provided to the inner agent model will be used.
@@ -95,275 +365,1532 @@ impl<M: CompletionModel> Agent<M> {
         self
     }
 
+    ///
+    /// Sets a size budget, in bytes, for the evaluated preamble.  If the evaluated preamble is over
+    /// this budget, resource parts of the preamble are truncated (with a marker) until it fits.
+    /// See [`CompletionEvaluatedPrompt::evaluate_with_budget`] for details.
+    ///
+    /// Coral preambles built from [`CompletionEvaluatedPrompt::all_resources`] can be very large,
+    /// this is a guard against sending an oversized preamble.  Default is None (no budget).
+    pub fn preamble_byte_budget(mut self, preamble_byte_budget: Option<usize>) -> Self {
+        self.preamble_byte_budget = preamble_byte_budget;
+        self
+    }
+
     ///
     /// Sets the Telemetry mode for this agent.  The default value is [`TelemetryMode::None`]; in
     /// this mode, no telemetry is sent.
     ///
     /// If the value provided is anything but [`TelemetryMode::None`], the following environment
-    /// variables are required (this function will panic if they are not provided):
-    /// - CORAL_API_URL
+    /// variables are required (this function will panic if they are not provided, see
+    /// [`Agent::try_telemetry`]):
+    /// - CORAL_API_URL, unless [`Agent::telemetry_url`] is set
     /// - CORAL_SESSION_ID
-    pub fn telemetry(
+    pub fn telemetry(self, telemetry: TelemetryMode, model_description: impl Into<String>) -> Self {
+        self.try_telemetry(telemetry, model_description)
+            .unwrap_or_else(|e| panic!("{e}"))
+    }
+
+    ///
+    /// The same as [`Agent::telemetry`], but returns [`Error::MissingEnv`] instead of panicking
+    /// if a required environment variable is not set
+    pub fn try_telemetry(
         mut self,
         telemetry: TelemetryMode,
         model_description: impl Into<String>,
-    ) -> Self {
+    ) -> Result<Self, Error> {
+        let env = |name: &str| std::env::var(name).map_err(|_| Error::MissingEnv(name.to_string()));
+
         self.telemetry = telemetry;
-        self.telemetry_url = std::env::var("CORAL_API_URL").expect("CORAL_API_URL not set");
-        self.telemetry_session_id =
-            std::env::var("CORAL_SESSION_ID").expect("CORAL_SESSION_ID not set");
+        self.telemetry_url = match &self.telemetry_url_override {
+            Some(telemetry_url) => telemetry_url.clone(),
+            None => env("CORAL_API_URL")?,
+        };
+        self.telemetry_session_id = env("CORAL_SESSION_ID")?;
         self.telemetry_model_description = model_description.into();
 
-        self
+        Ok(self)
     }
 
     ///
-    /// Sets the claim manager to use it with this Agent.  If no claim manager is set, no claims
-    /// will be made for this agent.  If you plan to export an agent, you must claim from the agent.
-    pub fn claim_manager(mut self, claim_manager: ClaimManager) -> Self {
-        self.claim_manager = Some(claim_manager);
+    /// Sends telemetry to the given API url instead of `CORAL_API_URL`, for example, a dedicated
+    /// telemetry collector.  Claims and every other API call still use `CORAL_API_URL`.  This can
+    /// be set before or after [`Agent::telemetry`].
+    pub fn telemetry_url(mut self, telemetry_url: impl Into<String>) -> Self {
+        let telemetry_url = telemetry_url.into();
+        self.telemetry_url = telemetry_url.clone();
+        self.telemetry_url_override = Some(telemetry_url);
         self
     }
 
     ///
-    /// This function is responsible for making sure every [`McpServerConnection`] provided to this
-    /// agent has their tools validated as requested by the connection for a completion request.
-    ///
-    /// A single [`McpServerConnection`] may choose:
-    /// - To have tooling skipped
-    /// - To have tooling evaluated once
-    /// - To have tooling evaluated before every completion
-    async fn validate_mcp_tooling(&mut self) -> Result<(), Error> {
-        // Remove any tooling that revalidates
-        self.revalidating_tooling.retain(|mcp_tool_name| {
-            self.completion_agent
-                .static_tools
-                .retain(|tool_name| tool_name != mcp_tool_name);
-            self.completion_agent.tools.delete_tool(mcp_tool_name);
-            false
-        });
+    /// The same as [`Agent::telemetry`], but the model description is read from the `CORAL_MODEL`
+    /// environment variable.  In orchestrated sessions the model can be chosen by the server (for
+    /// example, through an agent option), in which case a compile-time model description would be
+    /// wrong.  If `CORAL_MODEL` is not set, `default_model_description` is used.
+    pub fn telemetry_with_env_model(
+        self,
+        telemetry: TelemetryMode,
+        default_model_description: impl Into<String>,
+    ) -> Self {
+        let model_description =
+            std::env::var("CORAL_MODEL").unwrap_or_else(|_| default_model_description.into());
 
-        let mut tools = Vec::new();
-        for mcp in self.mcp_connections.iter_mut() {
-            if (mcp.tools_validated && !mcp.connection.revalidate_tooling)
-                || mcp.connection.skip_tooling
-            {
-                continue;
-            }
+        self.telemetry(telemetry, model_description)
+    }
 
-            let mcp_tools = mcp.connection.get_tools().await?;
-            if !mcp.tools_validated {
-                for tool in mcp_tools.iter() {
-                    info!(
-                        "adding tool \"{}\" from mcp server \"{}\"",
-                        tool.name(),
-                        mcp.connection.identifier
-                    );
-                }
-            }
+    ///
+    /// If set to true, telemetry bodies will be gzip compressed before being sent to the Coral
+    /// server.  Telemetry contains the full message history, which for agents using images or
+    /// documents can be several megabytes.  Default is false.
+    ///
+    /// Compressed bodies are sent with `Content-Encoding: gzip`.  The Coral API specification does
+    /// not say whether the server accepts compressed request bodies, so only enable this for a
+    /// Coral server that is known to decompress them.
+    pub fn telemetry_compression(mut self, telemetry_compression: bool) -> Self {
+        self.telemetry_compression = telemetry_compression;
+        self
+    }
 
-            mcp.tools_validated = true;
+    ///
+    /// If set to true, telemetry is skipped (and an error is logged) when the message history
+    /// cannot be converted into the format requested by [`Agent::telemetry`].  By default,
+    /// telemetry falls back to [`TelemetryMode::Generic`] when conversion fails, which may not be
+    /// parseable by pipelines that strictly require one format.  Default is false.
+    pub fn telemetry_strict(mut self, telemetry_strict: bool) -> Self {
+        self.telemetry_strict = telemetry_strict;
+        self
+    }
 
-            // If this MCP connection revalidates tooling, the list of tools that are revalidated
-            // needs to be recorded so that it can be removed from the completion agent on the next
-            // time this function is called
-            if mcp.connection.revalidate_tooling {
-                self.revalidating_tooling
-                    .extend(mcp_tools.iter().map(|tool| tool.name().clone()))
-            }
+    ///
+    /// The maximum number of attempts made to send each piece of telemetry.  Network errors and
+    /// server errors (HTTP 5xx) are retried after [`crate::telemetry::TELEMETRY_RETRY_DELAY`],
+    /// while telemetry rejected by the server is never retried.  Default is
+    /// [`DEFAULT_TELEMETRY_ATTEMPTS`].
+    pub fn telemetry_attempts(mut self, telemetry_attempts: u32) -> Self {
+        self.telemetry_attempts = telemetry_attempts;
+        self
+    }
 
-            tools.extend(mcp_tools);
-        }
+    ///
+    /// Signs every telemetry post with HMAC-SHA256 using the given key, so that the Coral server
+    /// can verify that telemetry came from this agent.  The signature covers the body as sent
+    /// (after compression, see [`Agent::telemetry_compression`]) and is hex encoded in the
+    /// [`crate::telemetry::TELEMETRY_SIGNATURE_HEADER`] header as `sha256=<signature>`.  By
+    /// default, telemetry is not signed.
+    pub fn telemetry_signing_key(mut self, key: impl AsRef<[u8]>) -> Self {
+        self.telemetry_signing_key = Some(hmac::Key::new(hmac::HMAC_SHA256, key.as_ref()));
+        self
+    }
 
-        // Add new or revalidated tooling to the completion agent's tooling
-        let agent_tools = std::mem::take(&mut self.completion_agent.tools);
-        self.completion_agent
-            .static_tools
-            .extend(tools.iter().map(|tool| tool.name().clone()));
-        self.completion_agent.tools = tools.into_iter().fold(agent_tools, |mut toolset, tool| {
-            toolset.add_tool(tool);
-            toolset
-        });
+    ///
+    /// Replaces every match of the given patterns with `***` in the preamble, messages and tool
+    /// arguments of telemetry before it is sent.  Can be called more than once, patterns are
+    /// added to those given previously.  Use [`Agent::telemetry_redact_secrets`] to redact the
+    /// values of secret options.
+    pub fn telemetry_redact(mut self, patterns: Vec<Regex>) -> Self {
+        self.telemetry_redactions.extend(patterns);
+        self
+    }
 
-        Ok(())
+    ///
+    /// Redacts the value of every option declared as a secret in the agent definition from
+    /// telemetry, see [`Agent::telemetry_redact`].  Option values are given to the agent as
+    /// environment variables, secrets that are not set in this agent's environment are ignored.
+    ///
+    /// This is not done automatically because an agent is never built from an
+    /// [`AgentOptionsBuilder`]: the builder produces the options sent when a session is created,
+    /// while the agent itself only receives option values as environment variables, with nothing
+    /// to say which of them are secrets.  The agent definition has to be loaded to find out.
+    ///
+    /// ```ignore
+    /// let options = AgentOptionsBuilder::from_agent_toml("coral-agent.toml")?;
+    /// let agent = agent.telemetry_redact_secrets(&options);
+    /// ```
+    pub fn telemetry_redact_secrets(self, options: &AgentOptionsBuilder) -> Self {
+        let patterns = options
+            .secret_names()
+            .into_iter()
+            .filter_map(|name| std::env::var(name).ok())
+            .filter(|value| !value.is_empty())
+            .filter_map(|value| Regex::new(&regex::escape(&value)).ok())
+            .collect();
+
+        self.telemetry_redact(patterns)
     }
 
     ///
-    /// If there was a preamble provided to this agent, this function will evaluate it, and if the
-    /// evaluation succeeds, the inner model's preamble field will be overwritten to this newly
-    /// evaluated prompt.
+    /// Batches telemetry instead of sending one telemetry post for every completion that sent a
+    /// Coral message.  Telemetry targets are buffered until at least `size` targets are waiting,
+    /// or [`Agent::flush_telemetry`] is called, and are then sent as one post with the most recent
+    /// message history.  [`crate::agent_loop::AgentLoop`] flushes at the end of every prompt
+    /// iteration and when the loop ends.
     ///
-    /// If there was no preamble provided to this agent, nothing will happen here.
+    /// Because every target in a batch shares one message history, earlier messages in a batch
+    /// have telemetry that includes the completions that came after them.  Telemetry still
+    /// buffered when this agent is dropped is not sent.  By default, telemetry is not batched.
+    pub fn telemetry_batch(mut self, size: usize) -> Self {
+        self.telemetry_batch = Some(size.max(1));
+        self
+    }
+
     ///
-    /// If the evaluation of the prompt fails (e.g., failure to locate a resource), this function will
-    /// return an error.
-    async fn validate_preamble(&mut self) -> Result<(), Error> {
-        if let Some(prompt) = &self.preamble {
-            match prompt.evaluate().await {
-                Ok(prompt) => self.completion_agent.preamble = prompt,
-                Err(e) => return Err(e),
-            }
+    /// Sends any telemetry buffered by [`Agent::telemetry_batch`]
+    pub async fn flush_telemetry(&mut self) {
+        if let Some(pending) = self.pending_telemetry.take() {
+            self.send_telemetry(pending.targets, pending.messages, pending.model_description)
+                .await;
         }
+    }
 
-        Ok(())
+    ///
+    /// Sends any telemetry buffered by [`Agent::telemetry_batch`], then waits until everything in
+    /// the [`Agent::telemetry_queue`] has been sent.  This should be called before the agent
+    /// exits, otherwise queued telemetry is lost.
+    pub async fn drain_telemetry(&mut self) {
+        self.flush_telemetry().await;
+        if let Some(telemetry_queue) = &self.telemetry_queue {
+            telemetry_queue.drain().await;
+        }
     }
 
     ///
-    /// Sends telemetry data to the Coral server.  The coral server is identified by the
-    /// CORAL_API_URL environment variable, which is automatically passed to agents orchestrated by
-    /// Coral server
-    async fn send_telemetry(&self, targets: Vec<TelemetryTarget>, messages: Vec<Message>) {
-        let target_count = targets.len();
-        let id = TelemetryIdentifier {
-            targets,
-            session_id: self.telemetry_session_id.clone(),
+    /// Sends any claims accumulated by the attached claim manager, see
+    /// [`ClaimManager::min_claim`]
+    pub async fn flush_claims(&self) -> Result<(), Error> {
+        match &self.claim_manager {
+            Some(claim_manager) => claim_manager.flush_claims().await,
+            None => Ok(()),
+        }
+    }
+
+    ///
+    /// Sends telemetry, or buffers it if telemetry is batched
+    async fn queue_telemetry(
+        &mut self,
+        targets: Vec<TelemetryTarget>,
+        messages: Vec<Message>,
+        model_description: String,
+    ) {
+        let Some(batch_size) = self.telemetry_batch else {
+            self.send_telemetry(targets, messages, model_description)
+                .await;
+            return;
         };
 
-        let res = TelemetryRequest::new(
-            id,
-            self.telemetry_url.clone(),
-            &self.completion_agent,
-            self.telemetry_model_description.clone(),
-            messages,
-        )
-        .telemetry_mode(self.telemetry.clone())
-        .send()
-        .await;
+        let pending = self
+            .pending_telemetry
+            .get_or_insert_with(|| PendingTelemetry {
+                targets: Vec::new(),
+                messages: Vec::new(),
+                model_description: String::new(),
+            });
 
-        if let Err(e) = res {
-            warn!("Error sending telemetry: {e}")
-        } else {
-            info!("Telemetry attached to {target_count} messages");
+        pending.targets.extend(targets);
+        pending.messages = messages;
+        pending.model_description = model_description;
+        if pending.targets.len() >= batch_size {
+            self.flush_telemetry().await;
         }
     }
 
     ///
-    /// Gathers a list of places that telemetry could be attached to when given a tool call (name
-    /// and output from tool).
+    /// Sends telemetry from a background task through a bounded queue, instead of waiting for the
+    /// Coral server to accept telemetry before a completion finishes.  When the queue already
+    /// holds `capacity` telemetry posts, `policy` decides whether to wait for space or to drop
+    /// telemetry; see [`Agent::telemetry_dropped`].  Telemetry still in the queue when this agent
+    /// is dropped is not sent, see [`Agent::drain_telemetry`].
     ///
-    /// At the moment, telemetry is only attached to Coral messages.  So this function will return
-    /// a TelemetryTarget from a Coral message if passed a call to [`McpTooling::CoralSendMessage`]
-    fn find_telemetry_targets(name: &String, output: &String) -> Vec<TelemetryTarget> {
-        let mut telemetry_targets = Vec::new();
-
-        match serde_json::from_str::<McpToolName>(format!("\"{name}\"").as_str()) {
-            Ok(McpToolName::CoralSendMessage) => {
-                match serde_json::from_str::<McpToolResult>(output) {
-                    Ok(McpToolResult::SendMessageSuccess { message }) => {
-                        telemetry_targets.push(TelemetryTarget {
-                            message_id: message.id,
-                            thread_id: message.thread_id,
-                        })
-                    }
-                    Err(e) => {
-                        warn!(
-                            "Identified CoralSendMessage tool call, but couldn't parse the output: {e}"
-                        );
-                    }
-                    Ok(other) => {
-                        warn!(
-                            "Identified CoralSendMessage tool call, but got a non SendMessageSuccess return: {other:#?}"
-                        );
-                    }
-                }
-            }
-            _ => {}
-        }
+    /// This must be called from within a Tokio runtime.  By default, there is no queue.
+    pub fn telemetry_queue(mut self, capacity: usize, policy: TelemetryDropPolicy) -> Self {
+        self.telemetry_queue = Some(TelemetryQueue::new(capacity, policy));
+        self
+    }
 
-        telemetry_targets
+    ///
+    /// The number of telemetry posts dropped because the telemetry queue was full.  Always zero
+    /// if [`Agent::telemetry_queue`] was not set.
+    pub fn telemetry_dropped(&self) -> u64 {
+        self.telemetry_queue
+            .as_ref()
+            .map_or(0, |queue| queue.dropped())
     }
 
-    /// Performs a completion request
     ///
-    /// This function, in order:
-    /// 1. Validates all tooling and documents on any connected MCP server (that require validation)
-    /// 2. Performs one completion request to the underlying completion agent
-    /// 3. Runs any tool calls that came back from the request
-    /// 4. Appends all messages in the response and any tool call results to the message history
+    /// Registers a telemetry extractor for a tool.  When the named tool is called, the extractor is
+    /// given the output of the tool and returns the [`TelemetryTarget`]s (Coral messages) that
+    /// telemetry should be attached to.
     ///
-    /// If telemetry is enabled, the last step of this function will be to post telemetry data
-    ///  to the Coral server.
+    /// By default, telemetry is only attached to messages sent with
+    /// [`McpToolName::CoralSendMessage`].  This can be used to attach telemetry to messages sent by
+    /// other tools, for example, a custom MCP server that posts Coral messages.  Registering an
+    /// extractor for `coral_send_message` replaces the default behaviour for that tool.
+    pub fn telemetry_extractor(
+        mut self,
+        tool_name: impl Into<String>,
+        extractor: impl Fn(&str) -> Vec<TelemetryTarget> + Send + Sync + 'static,
+    ) -> Self {
+        self.telemetry_extractors
+            .insert(tool_name.into(), Box::new(extractor));
+        self
+    }
+
     ///
-    /// # Arguments
-    /// * `messages` - The full message history for this completion request.  It is assumed that
-    /// this contains the necessary prompts for the completion.  This function will panic if given
-    /// an empty message history.
+    /// Adds a model to the fallback chain.  If a completion request to the completion agent's
+    /// model fails with a retryable error (e.g. rate limiting or a provider outage), the same
+    /// request is sent to each fallback model in the order they were added until one succeeds.
     ///
-    pub async fn run_completion(
-        &mut self,
-        mut messages: Vec<Message>,
-    ) -> Result<CompletionResult, Error> {
-        self.validate_mcp_tooling().await?;
-        self.validate_preamble().await?;
-
-        // Take the last message from the stack as a prompt
-        let prompt = messages
-            .pop()
-            .expect("cannot send completion with no messages");
+    /// The completion agent's preamble, tools, temperature, etc. are used for every model in the
+    /// chain.  The model description is sent with telemetry in place of the description given to
+    /// [`Agent::telemetry`] when this model produced the response.
+    pub fn fallback_model(mut self, model: M, model_description: impl Into<String>) -> Self {
+        self.fallback_models.push((model, model_description.into()));
+        self
+    }
 
-        let resp = self
-            .completion_agent
-            .completion(prompt.clone(), messages.clone())
-            .await
-            .map_err(Error::CompletionError)?
-            .send()
-            .await
-            .map_err(Error::CompletionError)?;
+    ///
+    /// Records every completion this agent makes into a [`SessionRecorder`].  The recorder can be
+    /// cloned before it is given to the agent so that the recording can be saved afterward.
+    pub fn session_recorder(mut self, session_recorder: SessionRecorder) -> Self {
+        self.session_recorder = Some(session_recorder);
+        self
+    }
 
-        messages.push(prompt);
-        messages.push(Message::Assistant {
-            id: None,
-            content: resp.choice.clone(),
-        });
+    ///
+    /// Returns recorded tool outputs instead of running tools, see
+    /// [`crate::session_recorder::SessionRecording::tool_replay`].  A tool call that doesn't match
+    /// the next recorded tool call fails with [`Error::ReplayMismatch`].
+    pub fn tool_replay(mut self, tool_replay: ToolReplay) -> Self {
+        self.tool_replay = Some(tool_replay);
+        self
+    }
 
-        if let Some(claim_manager) = &self.claim_manager {
-            claim_manager.claim_tokens(&resp.usage).await?;
-        }
+    ///
+    /// Sets a callback that is called with the message history once a completion's tool calls
+    /// have been added to it, and again after every tool call finishes with its result added.
+    /// Persisting these histories means that an agent that dies in the middle of a tool iteration
+    /// knows which tool calls already ran.
+    ///
+    /// Give a persisted history to [`Self::resume_tool_calls`] (or
+    /// [`crate::agent_loop::AgentLoop::with_initial_messages`]) after a restart to run only the
+    /// tool calls that have no result, so that side-effectful tools are not run twice.  A tool
+    /// call that was running when the agent died has no result and will be run again.
+    pub fn on_tool_checkpoint(
+        mut self,
+        on_tool_checkpoint: impl Fn(&[Message]) + Send + Sync + 'static,
+    ) -> Self {
+        self.tool_checkpoint = Some(Box::new(on_tool_checkpoint));
+        self
+    }
+
+    ///
+    /// Sets a hook that can change the message history a completion returns in
+    /// [`CompletionResult::messages`], which becomes the context of the next completion.  The hook
+    /// is called after tool calls, claims and telemetry, right before the result is returned, so
+    /// it can be used to annotate or redact the assistant's response before it persists in the
+    /// conversation.
+    ///
+    /// Telemetry for the completion is not affected (see [`Agent::telemetry_redact`] for
+    /// redacting telemetry).
+    pub fn on_history(
+        mut self,
+        on_history: impl Fn(&mut Vec<Message>) + Send + Sync + 'static,
+    ) -> Self {
+        self.history_hook = Some(Box::new(on_history));
+        self
+    }
+
+    ///
+    /// Sets how multiple text parts in one completion response are combined into
+    /// [`CompletionResult::texts`].  Default is [`TextJoinStrategy::Separate`].
+    pub fn text_join_strategy(mut self, text_join_strategy: TextJoinStrategy) -> Self {
+        self.text_join_strategy = text_join_strategy;
+        self
+    }
+
+    ///
+    /// The maximum number of tool calls from a single completion response that are executed
+    /// concurrently.  Executing tool calls concurrently improves throughput when a model requests
+    /// many tools at once, while this limit protects MCP servers from being overwhelmed.
+    ///
+    /// Tool results are always added to the message history in the order the model requested them.
+    /// Default is 1 (tool calls are executed one at a time).  Zero is treated as 1.
+    pub fn max_concurrent_tool_calls(mut self, max_concurrent_tool_calls: usize) -> Self {
+        self.max_concurrent_tool_calls = max_concurrent_tool_calls.max(1);
+        self
+    }
+
+    ///
+    /// The maximum time a single tool call may take.  A tool call that takes longer is abandoned
+    /// and handled according to [`Self::tool_timeout_policy`], so that a hung MCP server cannot
+    /// stall the agent forever.  Default is no timeout.
+    pub fn tool_call_timeout(mut self, tool_call_timeout: Duration) -> Self {
+        self.tool_call_timeout = Some(tool_call_timeout);
+        self
+    }
+
+    ///
+    /// Sets what happens when a tool call exceeds the [`Self::tool_call_timeout`].  Default is
+    /// [`ToolTimeoutPolicy::ReportToModel`].
+    pub fn tool_timeout_policy(mut self, tool_timeout_policy: ToolTimeoutPolicy) -> Self {
+        self.tool_timeout_policy = tool_timeout_policy;
+        self
+    }
+
+    ///
+    /// The number of candidate completions to request in [`Self::run_completion`], for
+    /// self-consistency or best-of-n strategies.  The candidates are requested concurrently, one
+    /// of them is chosen (see [`Self::candidate_selector`]), and only the chosen candidate's tool
+    /// calls are run and added to the message history.  The text of every candidate is returned
+    /// in [`CompletionResult::candidates`].
+    ///
+    /// Every candidate is a separate completion request, so token usage (and its claims) is the
+    /// sum of every candidate.  Streaming completions always use one candidate.  Default is 1.
+    /// Zero is treated as 1.
+    pub fn candidates(mut self, candidates: usize) -> Self {
+        self.candidates = candidates.max(1);
+        self
+    }
+
+    ///
+    /// Sets the function that chooses which candidate completion is used when
+    /// [`Self::candidates`] is more than one.  An index out of range chooses the last candidate.
+    /// By default, the first candidate is chosen.
+    pub fn candidate_selector(
+        mut self,
+        candidate_selector: impl Fn(&[String]) -> usize + Send + Sync + 'static,
+    ) -> Self {
+        self.candidate_selector = Some(Box::new(candidate_selector));
+        self
+    }
+
+    ///
+    /// Sets how failed completion requests are retried, for example, when the model provider is
+    /// rate limiting requests.  Only the request to the model is retried; tool calls are never run
+    /// more than once.  Each model is retried according to this policy before falling back to the
+    /// next model in [`Agent::fallback_model`].  Default is [`RetryPolicy::none`].
+    pub fn retry_policy(mut self, retry_policy: RetryPolicy) -> Self {
+        self.retry_policy = retry_policy;
+        self
+    }
+
+    ///
+    /// Sends provider cache-control hints marking the preamble as cacheable.  An agent's preamble
+    /// (including Coral resources) is usually large and only changes when a resource changes, so
+    /// caching it can greatly reduce the cost of input tokens.
+    ///
+    /// The hints are provider specific and are sent as additional parameters, so they must match
+    /// the completion model's provider.  Default is [`PromptCaching::None`].
+    pub fn prompt_caching(mut self, prompt_caching: PromptCaching) -> Self {
+        self.prompt_caching = prompt_caching;
+        self
+    }
+
+    ///
+    /// Constrains every completion to JSON matching the given JSON schema, using the provider's
+    /// structured output mode.  This is more reliable than parsing free-form text (see
+    /// [`Agent::extract`]) for agents that must produce machine-readable output.
+    ///
+    /// The schema is sent as the OpenAI `response_format` parameter (`json_schema` type, strict),
+    /// which is understood by OpenAI and OpenAI-compatible providers.  Other providers may ignore
+    /// it.  The response format is included in telemetry.
+    pub fn response_format(mut self, name: impl Into<String>, schema: serde_json::Value) -> Self {
+        self.response_format = Some(serde_json::json!({
+            "type": "json_schema",
+            "json_schema": {
+                "name": name.into(),
+                "schema": schema,
+                "strict": true,
+            }
+        }));
+        self
+    }
+
+    ///
+    /// The maximum number of completions that may use tools in one call to [`Self::extract`].
+    /// This should be large enough for the model to gather what it needs, but small enough to
+    /// catch a model that never stops using tools.
+    ///
+    /// If None is provided, there will be no limit to tool usage.  Default is
+    /// [`DEFAULT_ITERATION_TOOL_QUOTA`]
+    pub fn extract_tool_quota(mut self, extract_tool_quota: Option<u32>) -> Self {
+        self.extract_tool_quota = extract_tool_quota;
+        self
+    }
+
+    ///
+    /// Sets the claim manager to use it with this Agent.  If no claim manager is set, no claims
+    /// will be made for this agent.  If you plan to export an agent, you must claim from the agent.
+    pub fn claim_manager(mut self, claim_manager: ClaimManager) -> Self {
+        self.claim_manager = Some(claim_manager);
+        self
+    }
+
+    ///
+    /// The preamble currently set on the inner completion agent.  If a preamble was provided with
+    /// [`Self::preamble`], this is the result of its most recent evaluation.
+    pub fn current_preamble(&self) -> &str {
+        &self.completion_agent.preamble
+    }
+
+    ///
+    /// The names of every tool currently registered with the inner completion agent.  This
+    /// includes any tools added from MCP servers during the most recent completion.
+    pub fn tool_names(&self) -> Vec<String> {
+        self.completion_agent.static_tools.clone()
+    }
+
+    ///
+    /// The name and JSON schema of the parameters of every tool currently registered with the inner
+    /// completion agent, sorted by name.  These are the exact schemas the model sees, which is
+    /// useful for prompt engineering and generating documentation.  MCP tools are only registered
+    /// once tooling has been validated, see [`Self::validate`].
+    pub async fn tool_schemas(&self) -> Vec<(String, serde_json::Value)> {
+        let mut schemas = self
+            .completion_agent
+            .tools
+            .get_tool_definitions()
+            .await
+            .unwrap_or_default()
+            .into_iter()
+            .map(|definition| (definition.name, definition.parameters))
+            .collect::<Vec<_>>();
+
+        schemas.sort_by(|(a, _), (b, _)| a.cmp(b));
+        schemas
+    }
+
+    ///
+    /// Sets the interval for liveness heartbeats.  When set, a background task pings every MCP
+    /// server connected to this agent at this interval, so that an idle agent (for example, one
+    /// waiting on a prompt stream) is not mistaken for a hung one by the Coral server.
+    ///
+    /// The heartbeat task is started by [`crate::agent_loop::AgentLoop::execute`] or the first call
+    /// to [`Self::run_completion`], and stops when this agent is dropped.  By default, no heartbeat
+    /// is sent.
+    pub fn heartbeat(mut self, interval: Duration) -> Self {
+        self.heartbeat = Some(interval);
+        self
+    }
+
+    ///
+    /// Posts a brief summary of every tool call this agent makes (tool name and outcome) to a Coral
+    /// thread, so that other agents in the session can see what this agent is doing.
+    ///
+    /// Summaries are batched into one message per completion.  At most one message is posted per
+    /// `min_interval`; summaries from completions inside the interval are held and included in the
+    /// next message.  By default, tool calls are not echoed.
+    pub fn echo_tool_calls(
+        mut self,
+        connection: McpServerConnection,
+        thread_id: impl Into<String>,
+        min_interval: Duration,
+    ) -> Self {
+        self.tool_call_echo = Some(ToolCallEcho {
+            connection,
+            thread_id: thread_id.into(),
+            min_interval,
+            pending: Vec::new(),
+            last_post: None,
+        });
+        self
+    }
+
+    ///
+    /// Queues tool call summaries for [`Agent::echo_tool_calls`] and posts them if the minimum
+    /// interval has passed since the last post.  Failing to post generates a warning.
+    async fn echo_tool_call_summaries(&mut self, summaries: Vec<String>) {
+        let Some(echo) = &mut self.tool_call_echo else {
+            return;
+        };
+
+        echo.pending.extend(summaries);
+        if echo.pending.is_empty()
+            || echo
+                .last_post
+                .is_some_and(|last_post| last_post.elapsed() < echo.min_interval)
+        {
+            return;
+        }
+
+        let content = format!("Tool calls:\n{}", echo.pending.join("\n"));
+        let res = echo
+            .connection
+            .send_message(echo.thread_id.clone(), content, Vec::<String>::new())
+            .await;
+
+        echo.last_post = Some(Instant::now());
+        match res {
+            Ok(_) => echo.pending.clear(),
+            Err(e) => warn!("Failed to echo tool calls: {e}"),
+        }
+    }
+
+    ///
+    /// Summarises the outcome of a tool call for [`Agent::echo_tool_calls`]
+    fn summarize_tool_call(name: &str, output: &str) -> String {
+        match serde_json::from_str::<McpToolResult>(output)
+            .ok()
+            .as_ref()
+            .and_then(McpToolResult::error_message)
+        {
+            Some(message) => format!("- {name}: failed ({message})"),
+            None => format!("- {name}: succeeded"),
+        }
+    }
+
+    ///
+    /// Starts the heartbeat task if a heartbeat interval was set and the task is not already
+    /// running.
+    pub(crate) fn ensure_heartbeat(&mut self) {
+        let Some(period) = self.heartbeat else {
+            return;
+        };
+
+        if self.heartbeat_task.is_some() {
+            return;
+        }
+
+        let connections: Vec<McpServerConnection> = self
+            .mcp_connections
+            .iter()
+            .map(|mcp| mcp.connection.clone())
+            .collect();
+
+        self.heartbeat_task = Some(HeartbeatTask(tokio::spawn(async move {
+            let mut interval = tokio::time::interval(period);
+
+            // The first tick completes immediately
+            interval.tick().await;
+            loop {
+                interval.tick().await;
+                for connection in &connections {
+                    if let Err(e) = connection.ping().await {
+                        warn!(
+                            "heartbeat to mcp server \"{}\" failed: {e}",
+                            connection.identifier
+                        );
+                    }
+                }
+            }
+        })));
+    }
+
+    ///
+    /// This function is responsible for making sure every [`McpServerConnection`] provided to this
+    /// agent has their tools validated as requested by the connection for a completion request.
+    ///
+    /// A single [`McpServerConnection`] may choose:
+    /// - To have tooling skipped
+    /// - To have tooling evaluated once
+    /// - To have tooling evaluated before every completion
+    async fn validate_mcp_tooling(&mut self) -> Result<(), Error> {
+        // Remove any tooling that revalidates
+        self.revalidating_tooling.retain(|mcp_tool_name| {
+            self.completion_agent
+                .static_tools
+                .retain(|tool_name| tool_name != mcp_tool_name);
+            self.completion_agent.tools.delete_tool(mcp_tool_name);
+            false
+        });
+
+        let mut tools = Vec::new();
+        for mcp in self.mcp_connections.iter_mut() {
+            if (mcp.tools_validated && !mcp.connection.revalidate_tooling)
+                || mcp.connection.skip_tooling
+            {
+                continue;
+            }
+
+            let mut mcp_tools = mcp.connection.get_tools().await?;
+            if let Some(filter) = &self.tool_filter {
+                mcp_tools.retain(|tool| {
+                    let keep = filter(tool.name().as_str());
+                    if !keep && !mcp.tools_validated {
+                        info!(
+                            "skipping tool \"{}\" from mcp server \"{}\"",
+                            tool.name(),
+                            mcp.connection.identifier
+                        );
+                    }
+
+                    keep
+                });
+            }
+
+            if !mcp.tools_validated {
+                for tool in mcp_tools.iter() {
+                    info!(
+                        "adding tool \"{}\" from mcp server \"{}\"",
+                        tool.name(),
+                        mcp.connection.identifier
+                    );
+                }
+            }
+
+            mcp.tools_validated = true;
+
+            // If this MCP connection revalidates tooling, the list of tools that are revalidated
+            // needs to be recorded so that it can be removed from the completion agent on the next
+            // time this function is called
+            if mcp.connection.revalidate_tooling {
+                self.revalidating_tooling
+                    .extend(mcp_tools.iter().map(|tool| tool.name().clone()))
+            }
+
+            tools.extend(mcp_tools);
+        }
+
+        // Add new or revalidated tooling to the completion agent's tooling
+        let agent_tools = std::mem::take(&mut self.completion_agent.tools);
+        self.completion_agent
+            .static_tools
+            .extend(tools.iter().map(|tool| tool.name().clone()));
+        self.completion_agent.tools = tools.into_iter().fold(agent_tools, |mut toolset, tool| {
+            toolset.add_tool(tool);
+            toolset
+        });
+
+        Ok(())
+    }
+
+    ///
+    /// If there was a preamble provided to this agent, this function will evaluate it, and if the
+    /// evaluation succeeds, the inner model's preamble field will be overwritten to this newly
+    /// evaluated prompt.
+    ///
+    /// If there was no preamble provided to this agent, nothing will happen here.
+    ///
+    /// If a preamble byte budget was set, resource parts of the preamble will be truncated to fit
+    /// within it.
+    ///
+    /// If the evaluation of the prompt fails (e.g., failure to locate a resource), this function will
+    /// return an error.
+    async fn validate_preamble(&mut self) -> Result<(), Error> {
+        if let Some(prompt) = &self.preamble {
+            let evaluated = match self.preamble_byte_budget {
+                Some(max_bytes) => prompt.evaluate_with_budget(max_bytes).await,
+                None => prompt.evaluate().await,
+            };
+
+            match evaluated {
+                Ok(prompt) => self.completion_agent.preamble = prompt,
+                Err(e) => return Err(e),
+            }
+        }
+
+        Ok(())
+    }
+
+    ///
+    /// Eagerly validates MCP tooling, the preamble and the claim manager configuration (see
+    /// [`ClaimManager::verify`]).  This is normally done lazily, before each completion in
+    /// [`Self::run_completion`], which means that configuration errors (an unreachable MCP server,
+    /// a missing resource) only surface when the first completion is attempted.
+    ///
+    /// Calling this function at startup allows these errors to surface immediately, for example,
+    /// as part of a deployment health check.
+    pub async fn validate(&mut self) -> Result<(), Error> {
+        if let Some(claim_manager) = &self.claim_manager {
+            claim_manager.verify()?;
+        }
+
+        self.validate_mcp_tooling().await?;
+        self.validate_preamble().await
+    }
+
+    ///
+    /// Runs the agent against a single prompt until the completion stops using tools, then parses
+    /// the final text as JSON into `T`.  This is a typed alternative to reading
+    /// [`CompletionResult::texts`] for agents that are expected to produce structured data.
+    ///
+    /// The preamble or prompt should instruct the model to respond with JSON matching `T`.  A
+    /// markdown code fence around the JSON is tolerated.  If the final text cannot be parsed,
+    /// [`Error::ExtractError`] is returned.  If the model is still using tools after
+    /// [`Self::extract_tool_quota`] completions, [`Error::ToolQuotaReached`] is returned.
+    pub async fn extract<T: DeserializeOwned>(
+        &mut self,
+        prompt: impl Into<CompletionEvaluatedPrompt>,
+    ) -> Result<T, Error> {
+        let mut messages = vec![prompt.into().evaluate().await?.into()];
+
+        let mut tool_iterations = 0;
+        let texts = loop {
+            let res = self.run_completion(messages).await?;
+            if res.tools_used == 0 {
+                break res.texts;
+            }
+
+            tool_iterations += 1;
+            if let Some(quota) = self.extract_tool_quota
+                && tool_iterations >= quota
+            {
+                return Err(Error::ToolQuotaReached(quota));
+            }
+
+            messages = res.messages;
+        };
+
+        let text = texts.join("");
+        let text = text.trim();
+        let json = text
+            .strip_prefix("```json")
+            .or_else(|| text.strip_prefix("```"))
+            .and_then(|text| text.strip_suffix("```"))
+            .unwrap_or(text);
+
+        serde_json::from_str(json).map_err(Error::ExtractError)
+    }
+
+    ///
+    /// Consumes the agent and closes every MCP connection it holds, after sending any batched or
+    /// queued telemetry and accumulated claims.  See [`McpServerConnection::close`].
+    pub async fn close(mut self) -> Result<(), Error> {
+        self.drain_telemetry().await;
+        self.flush_claims().await?;
+        for validated in self.mcp_connections {
+            validated.connection.close().await?;
+        }
+
+        Ok(())
+    }
+
+    ///
+    /// Sends telemetry data to the Coral server.  The coral server is identified by the
+    /// CORAL_API_URL environment variable, which is automatically passed to agents orchestrated by
+    /// Coral server, unless [`Agent::telemetry_url`] is set
+    async fn send_telemetry(
+        &mut self,
+        targets: Vec<TelemetryTarget>,
+        messages: Vec<Message>,
+        model_description: String,
+    ) {
+        self.last_telemetry = Some(PendingTelemetry {
+            targets: targets.clone(),
+            messages: messages.clone(),
+            model_description: model_description.clone(),
+        });
+
+        let target_count = targets.len();
+        let request = self.telemetry_request(targets, messages, model_description);
+
+        let res = match &self.telemetry_queue {
+            Some(queue) => match request.prepare().await {
+                Ok(telemetry) => {
+                    queue.push(telemetry).await;
+                    info!("Telemetry for {target_count} messages queued");
+                    return;
+                }
+                Err(e) => Err(e),
+            },
+            None => request.send().await,
+        };
+
+        if let Err(e) = res {
+            warn!("Error sending telemetry: {e}")
+        } else {
+            info!("Telemetry attached to {target_count} messages");
+        }
+    }
+
+    ///
+    /// Builds a telemetry request with this agent's telemetry settings
+    fn telemetry_request(
+        &self,
+        targets: Vec<TelemetryTarget>,
+        messages: Vec<Message>,
+        model_description: String,
+    ) -> TelemetryRequest<'_, M> {
+        let id = TelemetryIdentifier {
+            targets,
+            session_id: self.telemetry_session_id.clone(),
+        };
+
+        TelemetryRequest::new(
+            id,
+            self.telemetry_url.clone(),
+            &self.completion_agent,
+            model_description,
+            messages,
+        )
+        .telemetry_mode(self.telemetry)
+        .compression(self.telemetry_compression)
+        .strict(self.telemetry_strict)
+        .signing_key(self.telemetry_signing_key.clone())
+        .redact(self.telemetry_redactions.clone())
+        .attempts(self.telemetry_attempts)
+        .response_format(self.response_format.clone())
+    }
+
+    ///
+    /// Reports the error that terminated this agent to the Coral server as telemetry, with the
+    /// error under [`crate::telemetry::AGENT_ERROR_PARAM`] in its additional parameters.  The
+    /// report is attached to the Coral messages the most recent telemetry was attached to, with
+    /// that telemetry's message history.
+    ///
+    /// Returns false if nothing was sent: telemetry is disabled, no telemetry has been sent yet (so
+    /// there is no Coral message to attach the report to), or the Coral server rejected it.
+    pub(crate) async fn report_error(&self, error: AgentError) -> bool {
+        if matches!(self.telemetry, TelemetryMode::None) {
+            return false;
+        }
+
+        let Some(last_telemetry) = &self.last_telemetry else {
+            return false;
+        };
+
+        let res = self
+            .telemetry_request(
+                last_telemetry.targets.clone(),
+                last_telemetry.messages.clone(),
+                last_telemetry.model_description.clone(),
+            )
+            .error(error)
+            .send()
+            .await;
+
+        if let Err(e) = &res {
+            warn!("Failed to report fatal error as telemetry: {e}");
+        }
+
+        res.is_ok()
+    }
+
+    ///
+    /// Gathers a list of places that telemetry could be attached to when given a tool call (name
+    /// and output from tool).
+    ///
+    /// If a [`TelemetryExtractor`] was registered for the tool, it is used.  Otherwise, telemetry
+    /// is only attached to Coral messages, so this function will return a TelemetryTarget from a
+    /// Coral message if passed a call to [`McpToolName::CoralSendMessage`]
+    fn find_telemetry_targets(&self, name: &str, output: &str) -> Vec<TelemetryTarget> {
+        if let Some(extractor) = self.telemetry_extractors.get(name) {
+            return extractor(output);
+        }
+
+        let mut telemetry_targets = Vec::new();
+
+        if let Ok(McpToolName::CoralSendMessage) =
+            serde_json::from_str::<McpToolName>(format!("\"{name}\"").as_str())
+        {
+            match serde_json::from_str::<McpToolResult>(output) {
+                Ok(McpToolResult::SendMessageSuccess { message }) => {
+                    telemetry_targets.push(TelemetryTarget {
+                        message_id: message.id,
+                        thread_id: message.thread_id,
+                    })
+                }
+                Err(e) => {
+                    warn!(
+                        "Identified CoralSendMessage tool call, but couldn't parse the output: {e}"
+                    );
+                }
+                Ok(other) => {
+                    warn!(
+                        "Identified CoralSendMessage tool call, but got a non SendMessageSuccess return: {other:#?}"
+                    );
+                }
+            }
+        }
+
+        telemetry_targets
+    }
+
+    ///
+    /// Sends a completion request to the completion agent's model, falling back to each model in
+    /// [`Agent::fallback_model`] on retryable errors.  Returns the response and the description of
+    /// the model that produced it.
+    async fn complete_with_fallback(
+        &self,
+        request: CompletionRequest,
+    ) -> Result<(CompletionResponse<M::Response>, String), Error> {
+        self.with_fallback(|model| {
+            let request = request.clone();
+            async move { model.completion(request).await }
+        })
+        .await
+    }
+
+    ///
+    /// The same as [`Self::complete_with_fallback`], but for a streaming completion.  Only errors
+    /// starting the stream fall back to another model; an error part way through a stream is
+    /// returned from the stream.
+    async fn stream_with_fallback(
+        &self,
+        request: CompletionRequest,
+    ) -> Result<(StreamingCompletionResponse<M::StreamingResponse>, String), Error> {
+        self.with_fallback(|model| {
+            let request = request.clone();
+            async move { model.stream(request).await }
+        })
+        .await
+    }
+
+    ///
+    /// Makes an attempt with the completion agent's model, then with each model in
+    /// [`Agent::fallback_model`] until an attempt succeeds or fails with an error that is not
+    /// retryable.
+    async fn with_fallback<T, F>(&self, attempt: impl Fn(M) -> F) -> Result<(T, String), Error>
+    where
+        F: Future<Output = Result<T, CompletionError>>,
+    {
+        let models = std::iter::once((
+            &self.completion_agent.model,
+            &self.telemetry_model_description,
+        ))
+        .chain(
+            self.fallback_models
+                .iter()
+                .map(|(model, description)| (model, description)),
+        );
+
+        let mut last_error = None;
+        for (model, description) in models {
+            if let Some(e) = &last_error {
+                warn!("Completion failed ({e}), falling back to model \"{description}\"");
+            }
+
+            match self.retry_policy.run(|| attempt(model.clone())).await {
+                Ok(resp) => return Ok((resp, description.clone())),
+                Err(e) if Self::is_retryable(&e) => last_error = Some(e),
+                Err(e) => return Err(Error::CompletionError(Box::new(e))),
+            }
+        }
+
+        Err(Error::CompletionError(Box::new(
+            last_error.expect("there is always at least one model"),
+        )))
+    }
+
+    ///
+    /// Returns true if a completion error may succeed with another attempt or another model.
+    /// Errors that are caused by the request itself (e.g. it could not be serialized) will fail
+    /// the same way with every model.
+    fn is_retryable(error: &CompletionError) -> bool {
+        matches!(
+            error,
+            CompletionError::HttpError(_)
+                | CompletionError::ProviderError(_)
+                | CompletionError::ResponseError(_)
+        )
+    }
+
+    ///
+    /// Some models reuse the same tool call id for more than one tool call in a single response.
+    /// Tool results reference their tool call by id, so a message history containing duplicate ids
+    /// is ambiguous, and some providers will reject it.  This function gives every duplicated id in
+    /// a response a unique index suffix (e.g. `call_1` -> `call_1_1`).
+    fn disambiguate_tool_call_ids(choice: &mut OneOrMany<AssistantContent>) {
+        let mut seen = HashSet::new();
+        for content in choice.iter_mut() {
+            if let AssistantContent::ToolCall(tool_call) = content {
+                if seen.insert(tool_call.id.clone()) {
+                    continue;
+                }
+
+                let mut index = 1;
+                while seen.contains(&format!("{}_{index}", tool_call.id)) {
+                    index += 1;
+                }
+
+                let id = format!("{}_{index}", tool_call.id);
+                warn!(
+                    "completion response reused tool call id \"{}\", renaming to \"{id}\"",
+                    tool_call.id
+                );
+
+                seen.insert(id.clone());
+                tool_call.id = id;
+            }
+        }
+    }
+
+    /// Performs a completion request
+    ///
+    /// This function, in order:
+    /// 1. Validates all tooling and documents on any connected MCP server (that require validation)
+    /// 2. Performs one completion request to the underlying completion agent
+    /// 3. Runs any tool calls that came back from the request
+    /// 4. Appends all messages in the response and any tool call results to the message history
+    ///
+    /// If telemetry is enabled, the last step of this function will be to post telemetry data
+    ///  to the Coral server.
+    ///
+    /// # Arguments
+    /// * `messages` - The full message history for this completion request.  It is assumed that
+    ///   this contains the necessary prompts for the completion.  This function will panic if given
+    ///   an empty message history.
+    ///
+    pub async fn run_completion(
+        &mut self,
+        mut messages: Vec<Message>,
+    ) -> Result<CompletionResult, Error> {
+        let (prompt, request) = self.prepare_completion(&mut messages).await?;
+        if self.candidates > 1 {
+            return self.run_candidates(prompt, messages, request).await;
+        }
+
+        let (resp, model_description) = self.complete_with_fallback(request).await?;
+
+        self.finish_completion(
+            prompt,
+            messages,
+            resp.choice,
+            resp.usage,
+            model_description,
+            &|_| {},
+        )
+        .await
+    }
+
+    ///
+    /// Requests every candidate completion concurrently, then finishes the completion with the
+    /// candidate chosen by the [`Self::candidate_selector`]
+    async fn run_candidates(
+        &mut self,
+        prompt: Message,
+        messages: Vec<Message>,
+        request: CompletionRequest,
+    ) -> Result<CompletionResult, Error> {
+        let responses = future::try_join_all(
+            (0..self.candidates).map(|_| self.complete_with_fallback(request.clone())),
+        )
+        .await?;
+
+        let candidates = responses
+            .iter()
+            .map(|(resp, _)| {
+                resp.choice
+                    .iter()
+                    .filter_map(|content| match content {
+                        AssistantContent::Text(text) => Some(text.text.as_str()),
+                        _ => None,
+                    })
+                    .collect::<String>()
+            })
+            .collect::<Vec<_>>();
+
+        let chosen = self
+            .candidate_selector
+            .as_ref()
+            .map_or(0, |selector| selector(&candidates))
+            .min(candidates.len() - 1);
+
+        let usage = responses
+            .iter()
+            .fold(Usage::new(), |usage, (resp, _)| usage + resp.usage);
+
+        let (resp, model_description) = responses
+            .into_iter()
+            .nth(chosen)
+            .expect("chosen candidate is in range");
+
+        let mut result = self
+            .finish_completion(
+                prompt,
+                messages,
+                resp.choice,
+                usage,
+                model_description,
+                &|_| {},
+            )
+            .await?;
+
+        result.candidates = candidates;
+        Ok(result)
+    }
+
+    /// Performs a completion request in the same way as [`Self::run_completion`], but streams the
+    /// completion as it is generated.
+    ///
+    /// Text is yielded as [`StreamEvent::Text`] as soon as the model produces it.  Once the model
+    /// has finished, tool calls are run, yielding [`StreamEvent::ToolCallStarted`] and
+    /// [`StreamEvent::ToolResult`] for each tool call.  Claims, telemetry and every other step of
+    /// [`Self::run_completion`] happen the same way.  The last item is always
+    /// [`StreamEvent::Done`] with the same result [`Self::run_completion`] would have returned, or
+    /// an error.
+    ///
+    /// Nothing happens until the stream is polled.
+    pub fn stream_completion(
+        &mut self,
+        messages: Vec<Message>,
+    ) -> impl Stream<Item = Result<StreamEvent, Error>> + '_ {
+        let (sender, receiver) = mpsc::unbounded();
+        let completion = async move {
+            let res = self.run_streaming_completion(messages, &sender).await;
+            let _ = sender.unbounded_send(res.map(StreamEvent::Done));
+        };
+
+        // The events are sent from the completion future, which must be polled alongside the
+        // receiver.  The receiver ends when the completion future finishes and drops the sender.
+        stream::select(
+            receiver,
+            completion
+                .into_stream()
+                .filter_map(|_| futures::future::ready(None)),
+        )
+    }
+
+    ///
+    /// Drives a streaming completion, see [`Self::stream_completion`]
+    async fn run_streaming_completion(
+        &mut self,
+        mut messages: Vec<Message>,
+        sender: &UnboundedSender<Result<StreamEvent, Error>>,
+    ) -> Result<CompletionResult, Error> {
+        let (prompt, request) = self.prepare_completion(&mut messages).await?;
+        let (mut resp, model_description) = self.stream_with_fallback(request).await?;
+
+        while let Some(content) = resp.next().await {
+            if let StreamedAssistantContent::Text(text) =
+                content.map_err(|e| Error::CompletionError(Box::new(e)))?
+            {
+                let _ = sender.unbounded_send(Ok(StreamEvent::Text(text.text)));
+            }
+        }
+
+        let usage = resp
+            .response
+            .as_ref()
+            .and_then(|response| response.token_usage())
+            .unwrap_or_default();
+
+        self.finish_completion(
+            prompt,
+            messages,
+            resp.choice,
+            usage,
+            model_description,
+            &|event| {
+                let _ = sender.unbounded_send(Ok(event));
+            },
+        )
+        .await
+    }
+
+    ///
+    /// The steps of a completion that happen before the request is sent: validation and building
+    /// the request.  The prompt is removed from `messages` and returned with the request.
+    async fn prepare_completion(
+        &mut self,
+        messages: &mut Vec<Message>,
+    ) -> Result<(Message, CompletionRequest), Error> {
+        self.ensure_heartbeat();
+        if let Some(claim_manager) = &self.claim_manager {
+            claim_manager.verify()?;
+        }
+
+        self.validate_mcp_tooling().await?;
+        self.validate_preamble().await?;
+
+        // Take the last message from the stack as a prompt
+        let prompt = messages
+            .pop()
+            .expect("cannot send completion with no messages");
+
+        let mut request = self
+            .completion_agent
+            .completion(prompt.clone(), messages.clone())
+            .await
+            .map_err(|e| Error::CompletionError(Box::new(e)))?;
+
+        if let Some(response_format) = &self.response_format {
+            request = request.additional_params(serde_json::json!({
+                "response_format": response_format,
+            }));
+        }
+
+        if self.prompt_caching == PromptCaching::Anthropic && !self.current_preamble().is_empty() {
+            request = request.additional_params(serde_json::json!({
+                "system": [{
+                    "type": "text",
+                    "text": self.current_preamble(),
+                    "cache_control": { "type": "ephemeral" },
+                }],
+            }));
+        }
+
+        let request = request.build();
+
+        Ok((prompt, request))
+    }
+
+    ///
+    /// Calls a tool from the completion agent's toolset, applying the [`Self::tool_call_timeout`].
+    /// When replaying a session (see [`Self::tool_replay`]), the recorded output is returned
+    /// instead.
+    async fn call_tool(&self, name: &str, arguments: String) -> Result<String, Error> {
+        if let Some(tool_replay) = &self.tool_replay {
+            return tool_replay.next_output(name, &arguments);
+        }
+
+        let call = self.completion_agent.tools.call(name, arguments);
+        let Some(timeout) = self.tool_call_timeout else {
+            return call.await.map_err(Error::ToolsetError);
+        };
+
+        match tokio::time::timeout(timeout, call).await {
+            Ok(res) => res.map_err(Error::ToolsetError),
+            Err(_) => match self.tool_timeout_policy {
+                ToolTimeoutPolicy::Fail => Err(Error::ToolTimeout {
+                    tool: name.to_string(),
+                }),
+                ToolTimeoutPolicy::ReportToModel => {
+                    warn!("Tool \"{name}\" timed out after {timeout:?}");
+                    Ok(format!(
+                        "The tool call timed out after {timeout:?} and did not return a result"
+                    ))
+                }
+            },
+        }
+    }
+
+    ///
+    /// Runs tool calls, adding each result to the message history in the order the model
+    /// requested them.  The [`Self::on_tool_checkpoint`] callback is called before the first tool
+    /// call and after every result.
+    ///
+    /// Each tool call is claimed as soon as its result is added, so tools that completed are
+    /// charged for even if a later tool fails, and a claim that exhausts the budget stops the
+    /// remaining tools.  With a [`Self::max_concurrent_tool_calls`] of 1, the next tool call is
+    /// not started until the previous one has been claimed.
+    async fn execute_tool_calls(
+        &self,
+        messages: &mut Vec<Message>,
+        tool_calls: &[ToolCall],
+        on_event: &(dyn Fn(StreamEvent) + Send + Sync),
+    ) -> Result<Vec<String>, Error> {
+        let checkpoint = |messages: &[Message]| {
+            if let Some(tool_checkpoint) = &self.tool_checkpoint {
+                tool_checkpoint(messages);
+            }
+        };
+
+        if !tool_calls.is_empty() {
+            checkpoint(messages);
+        }
+
+        let mut outputs = Vec::with_capacity(tool_calls.len());
+        let mut results = stream::iter(tool_calls)
+            .map(|tool_call| {
+                on_event(StreamEvent::ToolCallStarted {
+                    id: tool_call.id.clone(),
+                    name: tool_call.function.name.clone(),
+                    arguments: tool_call.function.arguments.clone(),
+                });
+
+                self.call_tool(
+                    &tool_call.function.name,
+                    tool_call.function.arguments.to_string(),
+                )
+            })
+            .buffered(self.max_concurrent_tool_calls);
+
+        for tool_call in tool_calls {
+            let Some(output) = results.try_next().await? else {
+                break;
+            };
+
+            messages.push(Self::tool_result_message(tool_call, output.clone()));
+            checkpoint(messages);
+            outputs.push(output);
+
+            if let Some(claim_manager) = &self.claim_manager {
+                claim_manager
+                    .claim_tool_call(tool_call.function.name.clone())
+                    .await?;
+            }
+        }
+
+        Ok(outputs)
+    }
+
+    ///
+    /// The message containing the result of a tool call
+    fn tool_result_message(tool_call: &ToolCall, output: String) -> Message {
+        if let Some(call_id) = tool_call.call_id.clone() {
+            UserContent::tool_result_with_call_id(
+                tool_call.id.clone(),
+                call_id,
+                OneOrMany::one(output.into()),
+            )
+            .into()
+        } else {
+            UserContent::tool_result(tool_call.id.clone(), OneOrMany::one(output.into())).into()
+        }
+    }
+
+    ///
+    /// Takes the message history of the last completion that failed while its tool calls were
+    /// running, including the results of the tool calls that finished.  Passing this to
+    /// [`Self::resume_tool_calls`] finishes the completion without running those tool calls again.
+    pub(crate) fn take_interrupted_messages(&mut self) -> Option<Vec<Message>> {
+        self.interrupted_messages.take()
+    }
+
+    ///
+    /// Finishes a completion that was interrupted while its tool calls were running, for example,
+    /// by the agent process being restarted.  If the message history ends with an assistant
+    /// message containing tool calls, followed only by tool results, every tool call without a
+    /// result is run and its result added to the history.  Tool calls that already have a result
+    /// are not run again.  See [`Self::on_tool_checkpoint`].
+    ///
+    /// Returns None if the history does not end in the middle of a tool iteration, or if every
+    /// tool call in it already has a result.  Otherwise, the result describes every tool call in
+    /// the interrupted completion, including those that had already run, so that it can be
+    /// handled like the result of [`Self::run_completion`].
+    pub async fn resume_tool_calls(
+        &mut self,
+        mut messages: Vec<Message>,
+    ) -> Result<Option<CompletionResult>, Error> {
+        let is_tool_result = |message: &Message| match message {
+            Message::User { content } => content
+                .iter()
+                .all(|content| matches!(content, UserContent::ToolResult(_))),
+            Message::Assistant { .. } => false,
+        };
+
+        let Some(start) = messages
+            .iter()
+            .rposition(|message| !is_tool_result(message))
+        else {
+            return Ok(None);
+        };
+
+        let Message::Assistant { content, .. } = &messages[start] else {
+            return Ok(None);
+        };
+
+        let tool_calls = content
+            .iter()
+            .filter_map(|content| match content {
+                AssistantContent::ToolCall(tool_call) => Some(tool_call.clone()),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+
+        if tool_calls.is_empty() {
+            return Ok(None);
+        }
+
+        let completed = messages[start + 1..]
+            .iter()
+            .flat_map(|message| match message {
+                Message::User { content } => content.iter().cloned().collect(),
+                Message::Assistant { .. } => Vec::new(),
+            })
+            .filter_map(|content| match content {
+                UserContent::ToolResult(result) => Some(result.id),
+                _ => None,
+            })
+            .collect::<HashSet<_>>();
+
+        let pending = tool_calls
+            .iter()
+            .filter(|tool_call| !completed.contains(&tool_call.id))
+            .cloned()
+            .collect::<Vec<_>>();
+
+        if pending.is_empty() {
+            return Ok(None);
+        }
+
+        info!(
+            "Resuming interrupted tool iteration - {} of {} tool calls already ran",
+            tool_calls.len() - pending.len(),
+            tool_calls.len()
+        );
+
+        let outputs = match self
+            .execute_tool_calls(&mut messages, &pending, &|_| {})
+            .await
+        {
+            Ok(outputs) => outputs,
+            Err(e) => {
+                self.interrupted_messages = Some(messages);
+                return Err(e);
+            }
+        };
+
+        let mut telemetry_targets = Vec::new();
+        for (tool_call, output) in pending.iter().zip(&outputs) {
+            telemetry_targets.extend(self.find_telemetry_targets(&tool_call.function.name, output));
+        }
+
+        if !telemetry_targets.is_empty() && !matches!(self.telemetry, TelemetryMode::None) {
+            let model_description = self.telemetry_model_description.clone();
+            self.queue_telemetry(telemetry_targets, messages.clone(), model_description)
+                .await;
+        }
+
+        if let Some(claim_manager) = &self.claim_manager {
+            claim_manager.claim_tool_iteration().await?;
+        }
+
+        if let Some(history_hook) = &self.history_hook {
+            history_hook(&mut messages);
+        }
+
+        Ok(Some(CompletionResult {
+            messages,
+            texts: Vec::new(),
+            tools_used: tool_calls.len() as u32,
+            tools_called: tool_calls
+                .into_iter()
+                .map(|tool_call| tool_call.function.name)
+                .collect(),
+            usage: Usage::new(),
+            estimated_cost: None,
+            candidates: Vec::new(),
+        }))
+    }
+
+    ///
+    /// The steps of a completion that happen after the model has responded: claims, tool calls,
+    /// recording, telemetry and building the result.  Tool call events are passed to `on_event`.
+    async fn finish_completion(
+        &mut self,
+        prompt: Message,
+        mut messages: Vec<Message>,
+        mut choice: OneOrMany<AssistantContent>,
+        usage: Usage,
+        model_description: String,
+        on_event: &(dyn Fn(StreamEvent) + Send + Sync),
+    ) -> Result<CompletionResult, Error> {
+        Self::disambiguate_tool_call_ids(&mut choice);
+        self.interrupted_messages = None;
+
+        let mut recorded_completion = self.session_recorder.as_ref().map(|_| RecordedCompletion {
+            messages: messages.iter().chain([&prompt]).cloned().collect(),
+            response: choice.clone(),
+            usage,
+            tool_calls: Vec::new(),
+        });
+
+        messages.push(prompt);
+        messages.push(Message::Assistant {
+            id: None,
+            content: choice.clone(),
+        });
+
+        let estimated_cost = self
+            .claim_manager
+            .as_ref()
+            .and_then(|claim_manager| claim_manager.estimate_token_cost(&usage));
+
+        if let Some(claim_manager) = &self.claim_manager {
+            claim_manager.claim_tokens(&usage).await?;
+        }
 
         let mut tools_used = 0;
+        let mut tools_called = Vec::new();
+        let mut tool_call_summaries = Vec::new();
         let mut texts = Vec::new();
         let mut telemetry_targets = Vec::new();
-        for choice in resp.choice {
+        let mut tool_calls = Vec::new();
+        for choice in choice {
             match choice {
-                AssistantContent::ToolCall(tool_call) => {
-                    tools_used = tools_used + 1;
-
-                    let output = self
-                        .completion_agent
-                        .tools
-                        .call(
-                            &tool_call.function.name,
-                            tool_call.function.arguments.to_string(),
-                        )
-                        .await
-                        .map_err(Error::ToolsetError)?;
-
-                    if let Some(claim_manager) = &self.claim_manager {
-                        claim_manager
-                            .claim_tool_call(tool_call.function.name.clone())
-                            .await?;
-                    }
-
-                    telemetry_targets.extend(Self::find_telemetry_targets(
-                        &tool_call.function.name,
-                        &output,
-                    ));
-
-                    messages.push(if let Some(call_id) = tool_call.call_id {
-                        UserContent::tool_result_with_call_id(
-                            tool_call.id.clone(),
-                            call_id,
-                            OneOrMany::one(output.into()),
-                        )
-                        .into()
-                    } else {
-                        UserContent::tool_result(
-                            tool_call.id.clone(),
-                            OneOrMany::one(output.into()),
-                        )
-                        .into()
-                    })
-                }
+                AssistantContent::ToolCall(tool_call) => tool_calls.push(tool_call),
                 AssistantContent::Text(text) => {
                     texts.push(text.text.clone());
                 }
@@ -371,8 +1898,61 @@ impl<M: CompletionModel> Agent<M> {
             }
         }
 
+        let outputs = match self
+            .execute_tool_calls(&mut messages, &tool_calls, on_event)
+            .await
+        {
+            Ok(outputs) => outputs,
+            Err(e) => {
+                self.interrupted_messages = Some(messages);
+                return Err(e);
+            }
+        };
+
+        for (tool_call, output) in tool_calls.into_iter().zip(outputs) {
+            tools_used += 1;
+            tools_called.push(tool_call.function.name.clone());
+
+            on_event(StreamEvent::ToolResult {
+                id: tool_call.id.clone(),
+                name: tool_call.function.name.clone(),
+                output: output.clone(),
+            });
+
+            telemetry_targets
+                .extend(self.find_telemetry_targets(&tool_call.function.name, &output));
+
+            if let Some(recorded_completion) = &mut recorded_completion {
+                recorded_completion.tool_calls.push(RecordedToolCall {
+                    name: tool_call.function.name.clone(),
+                    arguments: tool_call.function.arguments.clone(),
+                    output: output.clone(),
+                });
+            }
+
+            if self.tool_call_echo.is_some() {
+                tool_call_summaries
+                    .push(Self::summarize_tool_call(&tool_call.function.name, &output));
+            }
+        }
+
+        let texts = match self.text_join_strategy {
+            TextJoinStrategy::Separate => texts,
+            _ if texts.is_empty() => texts,
+            TextJoinStrategy::Concatenate => vec![texts.join("")],
+            TextJoinStrategy::Newline => vec![texts.join("\n")],
+        };
+
+        if let Some(session_recorder) = &self.session_recorder
+            && let Some(recorded_completion) = recorded_completion
+        {
+            session_recorder.record(recorded_completion);
+        }
+
+        self.echo_tool_call_summaries(tool_call_summaries).await;
+
         if !telemetry_targets.is_empty() && !matches!(self.telemetry, TelemetryMode::None) {
-            self.send_telemetry(telemetry_targets, messages.clone())
+            self.queue_telemetry(telemetry_targets, messages.clone(), model_description)
                 .await;
         }
 
@@ -384,10 +1964,100 @@ impl<M: CompletionModel> Agent<M> {
             }
         }
 
+        if let Some(history_hook) = &self.history_hook {
+            history_hook(&mut messages);
+        }
+
         Ok(CompletionResult {
             messages,
             texts,
             tools_used,
+            tools_called,
+            usage,
+            estimated_cost,
+            candidates: Vec::new(),
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock_completion_model::MockCompletionModel;
+    use crate::test_util::{TestTool, test_agent, tool_calls};
+
+    #[tokio::test]
+    async fn extract_parses_fenced_json() {
+        let model = MockCompletionModel::default();
+        model.push_response(tool_calls(&["lookup"]), Usage::new());
+        model.push_text("```json\n{\"answer\": 42}\n```");
+
+        let mut agent = test_agent(model, [TestTool::new("lookup")]);
+        let value: serde_json::Value = agent
+            .extract(CompletionEvaluatedPrompt::new().string("question"))
+            .await
+            .unwrap();
+
+        assert_eq!(value, serde_json::json!({ "answer": 42 }));
+    }
+
+    #[tokio::test]
+    async fn extract_stops_at_tool_quota() {
+        let model = MockCompletionModel::default();
+        for _ in 0..3 {
+            model.push_response(tool_calls(&["lookup"]), Usage::new());
+        }
+
+        let lookup = TestTool::new("lookup");
+        let mut agent = test_agent(model, [lookup.clone()]).extract_tool_quota(Some(2));
+        let res = agent
+            .extract::<serde_json::Value>(CompletionEvaluatedPrompt::new().string("question"))
+            .await;
+
+        assert!(matches!(res, Err(Error::ToolQuotaReached(2))));
+        assert_eq!(lookup.calls(), 2);
+    }
+
+    fn slow_tool_agent(policy: ToolTimeoutPolicy) -> Agent<MockCompletionModel> {
+        let model = MockCompletionModel::new([tool_calls(&["slow", "fast"])]);
+        test_agent(
+            model,
+            [
+                TestTool::new("slow").delay(Duration::from_secs(30)),
+                TestTool::new("fast"),
+            ],
+        )
+        .tool_call_timeout(Duration::from_millis(50))
+        .tool_timeout_policy(policy)
+    }
+
+    #[tokio::test]
+    async fn tool_timeout_is_reported_to_the_model() {
+        let mut agent = slow_tool_agent(ToolTimeoutPolicy::ReportToModel);
+        let result = tokio::time::timeout(
+            Duration::from_secs(5),
+            agent.run_completion(vec![Message::user("question")]),
+        )
+        .await
+        .unwrap()
+        .unwrap();
+
+        assert_eq!(result.tools_called, vec!["slow", "fast"]);
+        let history = serde_json::to_string(&result.messages).unwrap();
+        assert!(history.contains("The tool call timed out after 50ms"));
+        assert!(history.contains("fast output"));
+    }
+
+    #[tokio::test]
+    async fn tool_timeout_fails_the_completion() {
+        let mut agent = slow_tool_agent(ToolTimeoutPolicy::Fail);
+        let result = tokio::time::timeout(
+            Duration::from_secs(5),
+            agent.run_completion(vec![Message::user("question")]),
+        )
+        .await
+        .unwrap();
+
+        assert!(matches!(result, Err(Error::ToolTimeout { tool }) if tool == "slow"));
+    }
+}
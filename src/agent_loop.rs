@@ -1,17 +1,161 @@
-use crate::agent::Agent;
+use crate::agent::{Agent, CompletionResult};
+use crate::api::generated::Client;
+use crate::api::generated::types::{AgentClaimAmount, AgentPaymentClaimRequest};
+use crate::claim_manager::ClaimManager;
 use crate::completion_evaluated_prompt::CompletionEvaluatedPrompt;
-use crate::error::Error;
-use futures::{Stream, StreamExt};
-use rig::completion::CompletionModel;
+use crate::error::{AgentError, Error};
+use crate::repeating_prompt_stream::PromptFeedback;
+use crate::training_data::{TrainingDataExporter, TrainingExample};
+use futures::{FutureExt, Stream, StreamExt, future};
+use reqwest::StatusCode;
+use rig::completion::{CompletionModel, Message};
+use rig::message::UserContent;
+use serde::Serialize;
+use std::collections::HashSet;
+use std::path::PathBuf;
 use std::pin::Pin;
-use tracing::{info, warn};
+use std::time::Duration;
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
+use tracing::{error, info, warn};
 
 pub const DEFAULT_ITERATION_TOOL_QUOTA: Option<u32> = Some(64);
 
+///
+/// Prefixes the error logged when an agent loop is terminated by an error.  The rest of the line
+/// is the [`AgentError`] as JSON.  See [`AgentLoop::report_fatal_error`].
+pub const FATAL_ERROR_PREFIX: &str = "CORAL_AGENT_ERROR ";
+
+///
+/// Called with the message history at the end of every prompt iteration.  See
+/// [`AgentLoop::on_iteration_end`].
+pub type IterationEndCallback = Box<dyn Fn(&[Message])>;
+
+///
+/// Whether the [`AgentLoop::iteration_tool_quota`] applies to each prompt iteration or to the
+/// whole run.  See [`AgentLoop::quota_scope`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum QuotaScope {
+    ///
+    /// The tool iteration count resets at the start of every prompt iteration.  Reaching the
+    /// quota moves on to the next prompt iteration.
+    #[default]
+    PerPrompt,
+
+    ///
+    /// The tool iteration count accumulates for the life of the loop.  Reaching the quota ends the
+    /// loop.
+    PerRun,
+}
+
+///
+/// What an [`AgentLoop`] does when a prompt evaluates to an empty or whitespace-only string, for
+/// example, because every resource in it was empty.  See [`AgentLoop::empty_prompt_policy`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum EmptyPromptPolicy {
+    ///
+    /// The prompt is skipped and the loop waits for the next prompt
+    #[default]
+    Skip,
+
+    ///
+    /// The given prompt is used instead
+    Substitute(String),
+
+    ///
+    /// The loop ends with [`Error::EmptyPrompt`]
+    Fail,
+}
+
+///
+/// What an [`AgentLoop`] does when a completion fails.  See [`AgentLoop::on_error`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ErrorPolicy {
+    ///
+    /// The loop ends with the error
+    #[default]
+    Abort,
+
+    ///
+    /// The failed prompt iteration is abandoned, and the loop continues with the next prompt.
+    /// The message history is reset to what it was before the failed iteration, so that a
+    /// partial iteration does not end up in the context of the next one.
+    SkipIteration,
+
+    ///
+    /// The completion is retried up to `max` times, then the loop ends with the error.  If the
+    /// model request failed, it is sent again with the same message history.  If a tool call
+    /// failed, only the tool calls without a result are run again, so tools that already
+    /// succeeded are not run or claimed twice, see [`Agent::resume_tool_calls`].
+    Retry { max: u32 },
+}
+
 pub struct AgentLoop<M: CompletionModel> {
     agent: Agent<M>,
     prompt_stream: Pin<Box<dyn Stream<Item = CompletionEvaluatedPrompt>>>,
     iteration_tool_quota: Option<u32>,
+    max_iterations: Option<usize>,
+    quota_scope: QuotaScope,
+    interrupt_stream: Option<Pin<Box<dyn Stream<Item = CompletionEvaluatedPrompt>>>>,
+    summarize_configuration: bool,
+    history_directory: Option<PathBuf>,
+    max_history_bytes: Option<usize>,
+    stop_tools: HashSet<String>,
+    end_tools: HashSet<String>,
+    session_end_poll_interval: Option<Duration>,
+    shutdown: Option<Pin<Box<dyn Future<Output = ()>>>>,
+    training_data_exporter: Option<TrainingDataExporter>,
+    report_fatal_error: bool,
+    empty_prompt_policy: EmptyPromptPolicy,
+    initial_messages: Vec<Message>,
+    on_iteration_end: Option<IterationEndCallback>,
+    prompt_feedback: Option<PromptFeedback>,
+    error_policy: ErrorPolicy,
+}
+
+///
+/// A running task that watches for the end of the Coral session.  The task is aborted when this is
+/// dropped.
+struct SessionWatchTask(JoinHandle<()>);
+
+impl Drop for SessionWatchTask {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+///
+/// A local, in-process record of an [`AgentLoop`] run, returned by [`AgentLoop::execute`].  This is
+/// not sent anywhere; it is intended to be logged or persisted by the application.
+#[derive(Debug, Clone, Serialize)]
+pub struct AgentLoopSummary {
+    /// The number of prompt iterations that were started
+    pub prompt_iterations: usize,
+
+    /// The number of completions (tool iterations) made across every prompt iteration
+    pub tool_iterations: usize,
+
+    /// The number of tools used across every prompt iteration
+    pub tools_used: u32,
+
+    /// The message history when the loop ended
+    pub messages: Vec<Message>,
+
+    /// What the agent was configured to do.  Only present if
+    /// [`AgentLoop::summarize_configuration`] was enabled.
+    pub configuration: Option<AgentConfigurationSummary>,
+}
+
+///
+/// The configuration an [`Agent`] finished an [`AgentLoop`] run with
+#[derive(Debug, Clone, Serialize)]
+pub struct AgentConfigurationSummary {
+    /// The final evaluated preamble
+    pub preamble: String,
+
+    /// The names of the tools that were active
+    pub tools: Vec<String>,
 }
 
 impl<M: CompletionModel> AgentLoop<M> {
@@ -25,6 +169,23 @@ impl<M: CompletionModel> AgentLoop<M> {
             agent,
             prompt_stream: Box::pin(prompt_stream),
             iteration_tool_quota: DEFAULT_ITERATION_TOOL_QUOTA,
+            max_iterations: None,
+            quota_scope: QuotaScope::default(),
+            interrupt_stream: None,
+            summarize_configuration: false,
+            history_directory: None,
+            max_history_bytes: None,
+            stop_tools: HashSet::new(),
+            end_tools: HashSet::new(),
+            session_end_poll_interval: None,
+            shutdown: None,
+            training_data_exporter: None,
+            report_fatal_error: std::env::var("CORAL_ORCHESTRATION_RUNTIME").is_ok(),
+            empty_prompt_policy: EmptyPromptPolicy::default(),
+            initial_messages: Vec::new(),
+            on_iteration_end: None,
+            prompt_feedback: None,
+            error_policy: ErrorPolicy::default(),
         }
     }
 
@@ -43,21 +204,557 @@ impl<M: CompletionModel> AgentLoop<M> {
     }
 
     ///
-    /// Executes the loop, consuming self
-    pub async fn execute(mut self) -> Result<(), Error> {
+    /// The maximum number of prompt iterations.  Once this many prompt iterations have finished,
+    /// the loop ends as if the prompt stream had ended.  This is a safety net against a runaway
+    /// agent with an infinite prompt stream, independent of any claim manager.  Default is None
+    /// (no limit).
+    pub fn max_iterations(mut self, max_iterations: Option<usize>) -> Self {
+        self.max_iterations = max_iterations;
+        self
+    }
+
+    ///
+    /// Sets whether the [`AgentLoop::iteration_tool_quota`] counts tool iterations per prompt
+    /// iteration or across the whole run.  A per-run quota bounds the total tool usage of a
+    /// long-lived agent.  Default is [`QuotaScope::PerPrompt`].
+    pub fn quota_scope(mut self, quota_scope: QuotaScope) -> Self {
+        self.quota_scope = quota_scope;
+        self
+    }
+
+    ///
+    /// Sets a stream of interrupting prompts.  Unlike the main prompt stream, which is only polled
+    /// once the previous prompt iteration has finished, the interrupt stream is checked after every
+    /// completion (the next safe point, when all tool calls have their results in the history).
+    ///
+    /// When an interrupting prompt is available, it is evaluated and appended to the message
+    /// history, and the current prompt iteration continues with the new instruction in context.
+    /// This allows an agent to be steered while it is in the middle of a tool loop.
+    pub fn interrupt_stream(
+        mut self,
+        interrupt_stream: impl Stream<Item = CompletionEvaluatedPrompt> + 'static,
+    ) -> Self {
+        self.interrupt_stream = Some(Box::pin(interrupt_stream));
+        self
+    }
+
+    ///
+    /// If set to true, the [`AgentLoopSummary`] returned by [`Self::execute`] will include the
+    /// final evaluated preamble and the tools that were active.  Default is false.
+    pub fn summarize_configuration(mut self, summarize_configuration: bool) -> Self {
+        self.summarize_configuration = summarize_configuration;
+        self
+    }
+
+    ///
+    /// Sets a directory that the complete message history will be written to after every prompt
+    /// iteration.  One JSON file is written per iteration, named `iteration-<n>.json`.  The
+    /// directory will be created if it does not exist.
+    ///
+    /// This is a local debugging/audit record and is separate from telemetry.  Failing to write a
+    /// file will generate a warning but will not stop the loop.
+    pub fn history_directory(mut self, history_directory: impl Into<PathBuf>) -> Self {
+        self.history_directory = Some(history_directory.into());
+        self
+    }
+
+    ///
+    /// A hard cap on the size of the message history, measured as the size of the serialized
+    /// messages in bytes.  Before every completion, the oldest messages are removed until the
+    /// history fits under the cap.  The preamble is not part of the message history and is never
+    /// removed.
+    ///
+    /// Messages are removed so that the history always starts with a prompt, never with an orphaned
+    /// tool result or assistant response.  The most recent message is never removed, so the history
+    /// can exceed the cap if that message alone is larger than it.
+    ///
+    /// This is a provider-agnostic memory guard and does not require a tokenizer.  Default is None
+    /// (no limit).
+    pub fn max_history_bytes(mut self, max_history_bytes: Option<usize>) -> Self {
+        self.max_history_bytes = max_history_bytes;
+        self
+    }
+
+    ///
+    /// Finishes the current prompt iteration as soon as a completion calls the named tool.  The
+    /// tool is still run and its result is recorded in the message history.  The loop continues
+    /// with the next prompt from the prompt stream.
+    ///
+    /// This can be called multiple times to register multiple tools.
+    pub fn stop_on_tool(mut self, name: impl Into<String>) -> Self {
+        self.stop_tools.insert(name.into());
+        self
+    }
+
+    ///
+    /// Ends the loop entirely as soon as a completion calls the named tool, as if the prompt stream
+    /// had ended.  The tool is still run and its result is recorded in the message history.  This is
+    /// useful for agents that signal that their task is complete by calling a `finish` tool.
+    ///
+    /// This can be called multiple times to register multiple tools.
+    pub fn end_on_tool(mut self, name: impl Into<String>) -> Self {
+        self.end_tools.insert(name.into());
+        self
+    }
+
+    ///
+    /// Sets a future that requests a graceful shutdown when it completes, for example, a future
+    /// that completes on SIGTERM.  If the loop is waiting for a prompt, it ends immediately.  If a
+    /// prompt iteration is running, the current tool iteration is finished first, and the loop ends
+    /// before the next completion.
+    ///
+    /// A completion that is in progress is never cancelled, so tool calls are never left
+    /// half-applied.  The message history up to the shutdown is returned in
+    /// [`AgentLoopSummary::messages`].
+    pub fn with_shutdown(mut self, shutdown: impl Future<Output = ()> + 'static) -> Self {
+        self.shutdown = Some(Box::pin(shutdown));
+        self
+    }
+
+    ///
+    /// Exports every prompt iteration as a fine-tuning example: the evaluated preamble, the loop
+    /// prompt and the text of the final completion.  See [`TrainingDataExporter`].
+    pub fn training_data_exporter(mut self, training_data_exporter: TrainingDataExporter) -> Self {
+        self.training_data_exporter = Some(training_data_exporter);
+        self
+    }
+
+    ///
+    /// Polls the Coral server at the given interval, and cleanly ends the loop once this agent's
+    /// session is no longer active.  This lets the orchestrator end an agent's participation in a
+    /// session without starving its budget.  The loop ends at the next prompt or tool iteration,
+    /// or immediately if the loop is waiting on the prompt stream.
+    ///
+    /// In a remote session (`CORAL_SEND_CLAIMS` is set to `1`), `CORAL_SESSION_ID` is the remote
+    /// session id, which the server does not list with its local sessions.  The session is
+    /// checked by claiming zero micro-coral instead, and has ended once the server no longer
+    /// recognises it.  Otherwise, the session has ended once it is no longer listed by the server.
+    ///
+    /// Errors while polling generate a warning and do not end the loop.  The following environment
+    /// variables are required, [`AgentLoop::execute`] returns [`Error::MissingEnv`] if they are
+    /// not provided:
+    /// - CORAL_API_URL
+    /// - CORAL_SESSION_ID
+    pub fn cancel_on_session_end(mut self, poll_interval: Duration) -> Self {
+        self.session_end_poll_interval = Some(poll_interval);
+        self
+    }
+
+    ///
+    /// Starts the session watch task if [`AgentLoop::cancel_on_session_end`] was set.  The returned
+    /// receiver becomes true when the session has ended.
+    fn watch_session_end(
+        &self,
+    ) -> Result<Option<(watch::Receiver<bool>, SessionWatchTask)>, Error> {
+        let Some(period) = self.session_end_poll_interval else {
+            return Ok(None);
+        };
+
+        let env = |name: &str| std::env::var(name).map_err(|_| Error::MissingEnv(name.to_string()));
+        let api_url = env("CORAL_API_URL")?;
+        let session_id = env("CORAL_SESSION_ID")?;
+        let remote = ClaimManager::send_claims();
+
+        let (sender, receiver) = watch::channel(false);
+        let task = tokio::spawn(async move {
+            let client = Client::new(api_url.as_str());
+
+            // The first tick of an interval completes immediately, but the session was active
+            // when the loop started
+            let mut interval = tokio::time::interval_at(Instant::now() + period, period);
+            loop {
+                interval.tick().await;
+                if !session_active(&client, &session_id, remote).await {
+                    info!("Coral session {session_id} has ended");
+                    let _ = sender.send(true);
+                    return;
+                }
+            }
+        });
+
+        Ok(Some((receiver, SessionWatchTask(task))))
+    }
+
+    ///
+    /// Waits for the next prompt from the prompt stream.  Returns None if the prompt stream ended, or
+    /// if the session ended or a shutdown was requested while waiting.
+    async fn next_prompt(
+        &mut self,
+        session_ended: &mut Option<watch::Receiver<bool>>,
+    ) -> Option<CompletionEvaluatedPrompt> {
+        let session_end = async {
+            match session_ended {
+                Some(session_ended) => {
+                    let _ = session_ended.wait_for(|ended| *ended).await;
+                }
+                None => future::pending().await,
+            }
+        };
+
+        let shutdown = async {
+            match &mut self.shutdown {
+                Some(shutdown) => shutdown.await,
+                None => future::pending().await,
+            }
+        };
+
+        let prompt = tokio::select! {
+            prompt = self.prompt_stream.next() => Ok(prompt),
+            _ = session_end => Ok(None),
+            _ = shutdown => Err(()),
+        };
+
+        prompt.unwrap_or_else(|_| {
+            info!("Shutdown requested while waiting for a prompt");
+            self.shutdown = None;
+            None
+        })
+    }
+
+    ///
+    /// Returns true if the shutdown future given to [`AgentLoop::with_shutdown`] has completed
+    fn poll_shutdown(&mut self) -> bool {
+        let requested = self
+            .shutdown
+            .as_mut()
+            .is_some_and(|shutdown| shutdown.now_or_never().is_some());
+
+        if requested {
+            self.shutdown = None;
+        }
+
+        requested
+    }
+
+    ///
+    /// Removes the oldest messages from the history until it fits within
+    /// [`AgentLoop::max_history_bytes`]
+    fn trim_history(&self, messages: &mut Vec<Message>) {
+        let Some(max_history_bytes) = self.max_history_bytes else {
+            return;
+        };
+
+        let sizes = messages
+            .iter()
+            .map(|message| serde_json::to_vec(message).map_or(0, |json| json.len()))
+            .collect::<Vec<_>>();
+
+        let mut total = sizes.iter().sum::<usize>();
+        let mut remove = 0;
+        while remove + 1 < messages.len()
+            && (total > max_history_bytes || !Self::starts_history(&messages[remove]))
+        {
+            total -= sizes[remove];
+            remove += 1;
+        }
+
+        if remove > 0 {
+            messages.drain(..remove);
+            warn!("Removed {remove} messages from history to fit within {max_history_bytes} bytes");
+        }
+
+        if total > max_history_bytes {
+            warn!(
+                "Message history is {total} bytes, which exceeds the limit of {max_history_bytes}"
+            );
+        }
+    }
+
+    ///
+    /// Returns true if the message can be the first message in a history (i.e. it is a prompt)
+    fn starts_history(message: &Message) -> bool {
+        match message {
+            Message::User { content } => !content
+                .iter()
+                .any(|content| matches!(content, UserContent::ToolResult(_))),
+            Message::Assistant { .. } => false,
+        }
+    }
+
+    ///
+    /// Writes the message history for one prompt iteration to the history directory, if one is set
+    fn write_history(&self, iteration: usize, messages: &[Message]) {
+        let Some(directory) = &self.history_directory else {
+            return;
+        };
+
+        let path = directory.join(format!("iteration-{iteration}.json"));
+        let res = std::fs::create_dir_all(directory)
+            .and_then(|_| Ok(serde_json::to_vec_pretty(messages)?))
+            .and_then(|json| std::fs::write(&path, json));
+
+        if let Err(e) = res {
+            warn!("Failed to write message history to {}: {e}", path.display());
+        }
+    }
+
+    ///
+    /// Returns the next interrupting prompt if one is immediately available, without waiting
+    fn poll_interrupt(&mut self) -> Option<CompletionEvaluatedPrompt> {
+        self.interrupt_stream
+            .as_mut()
+            .and_then(|stream| stream.next().now_or_never().flatten())
+    }
+
+    ///
+    /// Sets what happens when a prompt from the prompt stream evaluates to an empty or
+    /// whitespace-only string.  Sending an empty prompt wastes a completion and can confuse the
+    /// model.  Default is [`EmptyPromptPolicy::Skip`].
+    pub fn empty_prompt_policy(mut self, empty_prompt_policy: EmptyPromptPolicy) -> Self {
+        self.empty_prompt_policy = empty_prompt_policy;
+        self
+    }
+
+    ///
+    /// Seeds the loop with an existing message history, for example, one saved by
+    /// [`AgentLoop::on_iteration_end`] before the agent crashed.  The first prompt is appended to
+    /// these messages; nothing in the history is prompted again.
+    ///
+    /// If the history ends in the middle of a tool iteration, for example, one saved by
+    /// [`Agent::on_tool_checkpoint`], the interrupted prompt iteration is finished before the
+    /// first prompt is taken.  Only tool calls without a result in the history are run, see
+    /// [`Agent::resume_tool_calls`].
+    pub fn with_initial_messages(mut self, messages: Vec<Message>) -> Self {
+        self.initial_messages = messages;
+        self
+    }
+
+    ///
+    /// Sets a callback that is called with the message history at the end of every prompt
+    /// iteration.  This can be used to persist the conversation so that a restarted agent can
+    /// resume it with [`AgentLoop::with_initial_messages`].
+    pub fn on_iteration_end(mut self, on_iteration_end: impl Fn(&[Message]) + 'static) -> Self {
+        self.on_iteration_end = Some(Box::new(on_iteration_end));
+        self
+    }
+
+    ///
+    /// Reports the number of tools used by every prompt iteration to a prompt stream, so that it
+    /// can back off while the agent is idle.  See
+    /// [`crate::repeating_prompt_stream::backoff_prompt_stream`].
+    pub fn prompt_feedback(mut self, prompt_feedback: PromptFeedback) -> Self {
+        self.prompt_feedback = Some(prompt_feedback);
+        self
+    }
+
+    ///
+    /// Sets what happens when a completion fails, for example, because a tool call failed or the
+    /// model provider returned an error.  Budget errors ([`Error::BudgetExhausted`] and
+    /// [`Error::TokenLimitReached`]) always end the loop.  Errors from anything other than a
+    /// completion, such as evaluating a prompt, also always end the loop.  Default is
+    /// [`ErrorPolicy::Abort`].
+    pub fn on_error(mut self, error_policy: ErrorPolicy) -> Self {
+        self.error_policy = error_policy;
+        self
+    }
+
+    ///
+    /// Returns true if the [`AgentLoop::on_error`] policy may recover from the error.  Budget,
+    /// claim and configuration errors are never recoverable: repeating the completion would
+    /// either fail the same way or risk claiming the same work twice.
+    fn is_recoverable(error: &Error) -> bool {
+        match error {
+            Error::McpClientError(_)
+            | Error::McpSseError(_)
+            | Error::McpUnreachable(..)
+            | Error::McpStdioError(_)
+            | Error::McpServiceError(_)
+            | Error::McpCloseError(_)
+            | Error::McpToolError(_)
+            | Error::PromptError(_)
+            | Error::CompletionError(_)
+            | Error::ExtractError(_)
+            | Error::ToolsetError(_)
+            | Error::ToolTimeout { .. }
+            | Error::CoralToolError(_)
+            | Error::CoralToolResultError(_) => true,
+            Error::BudgetExhausted
+            | Error::TokenLimitReached
+            | Error::ClaimsNotSent
+            | Error::BudgetUnavailable
+            | Error::InvalidConversionRate(_)
+            | Error::ClaimDivergence(..)
+            | Error::ToolQuotaReached(_)
+            | Error::InvalidMcpHeader(_)
+            | Error::InvalidOption(_)
+            | Error::AgentDefinitionError(_)
+            | Error::MissingEnv(_)
+            | Error::InvalidSession(_)
+            | Error::EmptyPrompt
+            | Error::RecordingIoError(_)
+            | Error::RecordingFormatError(_)
+            | Error::ReplayMismatch(_)
+            | Error::TrainingDataIoError(_)
+            | Error::TemplateIoError(_)
+            | Error::ApiError(_)
+            | Error::RegistryError(_) => false,
+        }
+    }
+
+    ///
+    /// Runs one completion, retrying it if the [`AgentLoop::on_error`] policy says to
+    async fn run_completion(&mut self, messages: Vec<Message>) -> Result<CompletionResult, Error> {
+        let ErrorPolicy::Retry { max } = self.error_policy else {
+            return self.agent.run_completion(messages).await;
+        };
+
+        let mut retries = 0;
+        let mut res = self.agent.run_completion(messages.clone()).await;
+        loop {
+            match res {
+                Err(e) if retries < max && Self::is_recoverable(&e) => {
+                    retries += 1;
+                    warn!("Completion failed ({e}), retrying [retry {retries}/{max}]");
+
+                    // Tool calls that already ran are not run or claimed again
+                    res = match self.agent.take_interrupted_messages() {
+                        Some(interrupted) => {
+                            match self.agent.resume_tool_calls(interrupted).await {
+                                Ok(Some(res)) => Ok(res),
+                                Ok(None) => Err(e),
+                                Err(e) => Err(e),
+                            }
+                        }
+                        None => self.agent.run_completion(messages.clone()).await,
+                    };
+                }
+                res => return res,
+            }
+        }
+    }
+
+    ///
+    /// If set to true, an error that terminates [`AgentLoop::execute`] is reported to the Coral
+    /// server as an [`AgentError`] before it is returned, so that the server can tell why the agent
+    /// stopped rather than only that its process exited.  Default is true when the agent is
+    /// orchestrated (`CORAL_ORCHESTRATION_RUNTIME` is set) and false otherwise.
+    ///
+    /// The Coral server API has no endpoint for agent errors, so the error is sent as telemetry
+    /// with the error under [`crate::telemetry::AGENT_ERROR_PARAM`] in its additional parameters,
+    /// attached to the last Coral messages this agent sent telemetry for.  This requires
+    /// telemetry to be enabled (see [`Agent::telemetry`]).  The error is also logged at the error
+    /// level as [`FATAL_ERROR_PREFIX`] followed by the error as JSON, which covers agents that have
+    /// not sent any telemetry.
+    pub fn report_fatal_error(mut self, report_fatal_error: bool) -> Self {
+        self.report_fatal_error = report_fatal_error;
+        self
+    }
+
+    ///
+    /// Reports an error that terminated the loop, see [`AgentLoop::report_fatal_error`]
+    async fn report_error(&self, error: &Error) {
+        if !self.report_fatal_error {
+            return;
+        }
+
+        let agent_error = AgentError::from(error);
+        match serde_json::to_string(&agent_error) {
+            Ok(json) => error!("{FATAL_ERROR_PREFIX}{json}"),
+            Err(e) => warn!("Failed to log fatal error: {e}"),
+        }
+
+        if self.agent.report_error(agent_error).await {
+            info!("Fatal error reported to the Coral server");
+        }
+    }
+
+    ///
+    /// Executes the loop, consuming self.  A summary of the run is returned when the prompt stream
+    /// ends.
+    pub async fn execute(mut self) -> Result<AgentLoopSummary, Error> {
+        let res = self.run().await;
+        self.agent.drain_telemetry().await;
+        if let Err(e) = self.agent.flush_claims().await {
+            warn!("Failed to send accumulated claims: {e}");
+        }
+        if let Err(e) = &res {
+            self.report_error(e).await;
+        }
+
+        res
+    }
+
+    ///
+    /// Runs the loop, see [`AgentLoop::execute`]
+    async fn run(&mut self) -> Result<AgentLoopSummary, Error> {
         info!("Starting Coral agent loop");
+        self.agent.ensure_heartbeat();
 
-        let mut messages = Vec::new();
+        let mut messages = std::mem::take(&mut self.initial_messages);
         let mut iterations = 0;
-        while let Some(prompt) = self.prompt_stream.next().await {
-            iterations += 1;
+        let mut tool_iterations = 0;
+        let mut tools_used = 0;
+        let (mut session_ended, _session_watch) = self.watch_session_end()?.unzip();
 
-            // An iteration should always start with the loop prompt
-            messages.push(prompt.evaluate().await?.into());
+        let mut ended = false;
+        let mut resuming = false;
+        if let Some(res) = self.agent.resume_tool_calls(messages.clone()).await? {
+            messages = res.messages;
+            if let Some(name) = res
+                .tools_called
+                .iter()
+                .find(|name| self.end_tools.contains(*name))
+            {
+                info!("Resumed tool iteration finished - \"{name}\" ended the loop");
+                ended = true;
+            } else {
+                resuming = !res
+                    .tools_called
+                    .iter()
+                    .any(|name| self.stop_tools.contains(name));
+            }
+        }
 
+        while !ended {
+            if let Some(max_iterations) = self.max_iterations
+                && iterations >= max_iterations
+            {
+                info!("Ending agent loop - {iterations}/{max_iterations} prompt iterations done");
+                break;
+            }
+
+            // A resumed iteration continues with the history it was interrupted with
+            let prompt = if std::mem::take(&mut resuming) {
+                None
+            } else {
+                let Some(prompt) = self.next_prompt(&mut session_ended).await else {
+                    break;
+                };
+
+                let mut prompt = prompt.evaluate().await?;
+                if prompt.trim().is_empty() {
+                    match &self.empty_prompt_policy {
+                        EmptyPromptPolicy::Skip => {
+                            warn!(
+                                "Skipping prompt iteration - prompt evaluated to an empty string"
+                            );
+                            continue;
+                        }
+                        EmptyPromptPolicy::Substitute(substitute) => prompt = substitute.clone(),
+                        EmptyPromptPolicy::Fail => return Err(Error::EmptyPrompt),
+                    }
+                }
+
+                Some(prompt)
+            };
+
+            // The history is restored to this if the iteration is skipped, see ErrorPolicy
+            let mut iteration_start =
+                (self.error_policy == ErrorPolicy::SkipIteration).then(|| messages.clone());
+
+            // A new iteration should always start with the loop prompt
+            iterations += 1;
+            if let Some(prompt) = &prompt {
+                messages.push(prompt.clone().into());
+            }
+
+            let iteration_tool_iterations = tool_iterations;
+            let iteration_tools_used = tools_used;
+            let mut response = String::new();
             let mut depth = 0;
+            let mut skipped = false;
             loop {
-                depth = depth + 1;
+                depth += 1;
                 info!(
                     "Tool iteration {}/{} [prompt iteration {iterations}]",
                     depth + 1,
@@ -65,24 +762,336 @@ impl<M: CompletionModel> AgentLoop<M> {
                         .map_or("unlimited".to_string(), |x| x.to_string()),
                 );
 
-                let res = self.agent.run_completion(messages).await?;
+                self.trim_history(&mut messages);
+                let res = match self.run_completion(messages).await {
+                    Ok(res) => res,
+                    Err(e) => match iteration_start.take() {
+                        Some(start) if Self::is_recoverable(&e) => {
+                            warn!("Prompt iteration [{iterations}] skipped - {e}");
+                            messages = start;
+                            skipped = true;
+                            break;
+                        }
+                        _ => return Err(e),
+                    },
+                };
+
+                tool_iterations += 1;
+                tools_used += res.tools_used;
                 if !res.texts.is_empty() {
-                    info!("\"{}\"", res.texts.join(""));
+                    response = res.texts.join("");
+                    info!("\"{response}\"");
                 }
 
                 messages = res.messages;
-                if res.tools_used == 0 {
-                    info!("Prompt iteration [{iterations}] finished - no tools used");
+                if session_ended.as_ref().is_some_and(|ended| *ended.borrow()) {
+                    info!("Prompt iteration [{iterations}] finished - session ended");
+                    ended = true;
                     break;
                 }
 
-                if Some(depth) == self.iteration_tool_quota {
-                    warn!("Prompt iteration [{iterations}] finished - tool quota reached");
+                if self.poll_shutdown() {
+                    info!("Prompt iteration [{iterations}] finished - shutdown requested");
+                    ended = true;
                     break;
                 }
+
+                if let Some(name) = res
+                    .tools_called
+                    .iter()
+                    .find(|name| self.end_tools.contains(*name))
+                {
+                    info!("Prompt iteration [{iterations}] finished - \"{name}\" ended the loop");
+                    ended = true;
+                    break;
+                }
+
+                if let Some(name) = res
+                    .tools_called
+                    .iter()
+                    .find(|name| self.stop_tools.contains(*name))
+                {
+                    info!("Prompt iteration [{iterations}] finished - \"{name}\" was called");
+                    break;
+                }
+
+                if let Some(interrupt) = self.poll_interrupt() {
+                    info!(
+                        "Prompt iteration [{iterations}] interrupted - continuing with new prompt"
+                    );
+                    messages.push(interrupt.evaluate().await?.into());
+                } else if res.tools_used == 0 {
+                    info!("Prompt iteration [{iterations}] finished - no tools used");
+                    break;
+                }
+
+                match self.quota_scope {
+                    QuotaScope::PerPrompt if Some(depth) == self.iteration_tool_quota => {
+                        warn!("Prompt iteration [{iterations}] finished - tool quota reached");
+                        break;
+                    }
+                    QuotaScope::PerRun
+                        if self
+                            .iteration_tool_quota
+                            .is_some_and(|quota| tool_iterations >= quota as usize) =>
+                    {
+                        warn!("Prompt iteration [{iterations}] finished - run tool quota reached");
+                        ended = true;
+                        break;
+                    }
+                    _ => {}
+                }
+            }
+
+            self.agent.flush_telemetry().await;
+            if skipped {
+                continue;
+            }
+
+            self.write_history(iterations, &messages);
+            if let Some(on_iteration_end) = &self.on_iteration_end {
+                on_iteration_end(&messages);
+            }
+
+            if let Some(prompt_feedback) = &self.prompt_feedback {
+                prompt_feedback.record_iteration(tools_used - iteration_tools_used);
+            }
+
+            if let Some(exporter) = &mut self.training_data_exporter
+                && let Some(prompt) = prompt
+            {
+                exporter.export(&TrainingExample {
+                    system: self.agent.current_preamble().to_string(),
+                    prompt,
+                    response,
+                    tool_iterations: tool_iterations - iteration_tool_iterations,
+                    tools_used: tools_used - iteration_tools_used,
+                });
             }
         }
 
-        Ok(())
+        Ok(AgentLoopSummary {
+            prompt_iterations: iterations,
+            tool_iterations,
+            tools_used,
+            messages,
+            configuration: self
+                .summarize_configuration
+                .then(|| AgentConfigurationSummary {
+                    preamble: self.agent.current_preamble().to_string(),
+                    tools: self.agent.tool_names(),
+                }),
+        })
+    }
+}
+
+///
+/// Checks whether a Coral session is still active, see [`AgentLoop::cancel_on_session_end`].
+/// Errors are logged and treated as the session still being active.
+async fn session_active(client: &Client, session_id: &str, remote: bool) -> bool {
+    if remote {
+        let claim = AgentPaymentClaimRequest {
+            amount: AgentClaimAmount::MicroCoral(0),
+        };
+
+        match client.claim_payment(session_id, &claim).await {
+            Ok(_) => true,
+            Err(e) if e.status() == Some(StatusCode::NOT_FOUND) => false,
+            Err(e) => {
+                warn!("Failed to check whether the Coral session has ended: {e}");
+                true
+            }
+        }
+    } else {
+        match client.get_sessions().await {
+            Ok(sessions) => sessions.contains(&session_id.to_string()),
+            Err(e) => {
+                warn!("Failed to check whether the Coral session has ended: {e}");
+                true
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::generated::types::TelemetryTarget;
+    use crate::mock_completion_model::MockCompletionModel;
+    use crate::telemetry::{AGENT_ERROR_PARAM, TelemetryMode};
+    use crate::test_util::{TestTool, set_env, test_agent, tool_calls};
+    use futures::stream;
+    use rig::completion::{CompletionError, Usage};
+    use wiremock::matchers::method;
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    fn prompts(prompts: &[&str]) -> impl Stream<Item = CompletionEvaluatedPrompt> + 'static {
+        stream::iter(
+            prompts
+                .iter()
+                .map(|prompt| CompletionEvaluatedPrompt::new().string(*prompt))
+                .collect::<Vec<_>>(),
+        )
+    }
+
+    fn provider_error() -> CompletionError {
+        CompletionError::ProviderError("overloaded".into())
+    }
+
+    #[tokio::test]
+    async fn abort_ends_the_loop() {
+        let model = MockCompletionModel::default();
+        model.push_error(provider_error());
+        model.push_text("unused");
+
+        let res = AgentLoop::new(test_agent(model.clone(), []), prompts(&["a", "b"]))
+            .execute()
+            .await;
+
+        assert!(matches!(res, Err(Error::CompletionError(_))));
+        assert_eq!(model.remaining(), 1);
+    }
+
+    #[tokio::test]
+    async fn skip_iteration_restores_history() {
+        let model = MockCompletionModel::default();
+        model.push_error(provider_error());
+        model.push_text("ok");
+
+        let summary = AgentLoop::new(test_agent(model.clone(), []), prompts(&["a", "b"]))
+            .on_error(ErrorPolicy::SkipIteration)
+            .execute()
+            .await
+            .unwrap();
+
+        assert_eq!(summary.prompt_iterations, 2);
+        assert_eq!(summary.tool_iterations, 1);
+        assert_eq!(summary.messages.len(), 2);
+        assert_eq!(model.remaining(), 0);
+    }
+
+    #[tokio::test]
+    async fn retry_resends_failed_model_request() {
+        let model = MockCompletionModel::default();
+        model.push_error(provider_error());
+        model.push_error(provider_error());
+        model.push_text("ok");
+
+        let summary = AgentLoop::new(test_agent(model.clone(), []), prompts(&["a"]))
+            .on_error(ErrorPolicy::Retry { max: 2 })
+            .execute()
+            .await
+            .unwrap();
+
+        assert_eq!(summary.tool_iterations, 1);
+        assert_eq!(summary.messages.len(), 2);
+        assert_eq!(model.remaining(), 0);
+    }
+
+    #[tokio::test]
+    async fn retry_gives_up_after_max() {
+        let model = MockCompletionModel::default();
+        model.push_error(provider_error());
+        model.push_error(provider_error());
+        model.push_text("ok");
+
+        let res = AgentLoop::new(test_agent(model.clone(), []), prompts(&["a"]))
+            .on_error(ErrorPolicy::Retry { max: 1 })
+            .execute()
+            .await;
+
+        assert!(matches!(res, Err(Error::CompletionError(_))));
+        assert_eq!(model.remaining(), 1);
+    }
+
+    #[tokio::test]
+    async fn retry_does_not_rerun_successful_tools() {
+        let count = TestTool::new("count");
+        let flaky = TestTool::new("flaky").failing(1);
+
+        let model = MockCompletionModel::default();
+        model.push_response(tool_calls(&["count", "flaky"]), Usage::new());
+        model.push_text("done");
+
+        let summary = AgentLoop::new(
+            test_agent(model.clone(), [count.clone(), flaky.clone()]),
+            prompts(&["a"]),
+        )
+        .on_error(ErrorPolicy::Retry { max: 1 })
+        .execute()
+        .await
+        .unwrap();
+
+        assert_eq!(count.calls(), 1);
+        assert_eq!(flaky.calls(), 2);
+        assert_eq!(summary.tools_used, 2);
+        assert_eq!(model.remaining(), 0);
+    }
+
+    #[test]
+    fn claim_and_configuration_errors_are_fatal() {
+        let fatal = [
+            Error::BudgetExhausted,
+            Error::TokenLimitReached,
+            Error::ClaimsNotSent,
+            Error::BudgetUnavailable,
+            Error::InvalidConversionRate(0.0),
+            Error::ClaimDivergence(1, 2),
+            Error::MissingEnv("CORAL_API_URL".to_string()),
+            Error::InvalidOption("x".to_string()),
+        ];
+
+        for error in &fatal {
+            assert!(
+                !AgentLoop::<MockCompletionModel>::is_recoverable(error),
+                "{error} should be fatal"
+            );
+        }
+
+        assert!(AgentLoop::<MockCompletionModel>::is_recoverable(
+            &Error::CompletionError(Box::new(provider_error()))
+        ));
+    }
+
+    #[tokio::test]
+    async fn fatal_error_is_reported_as_telemetry() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .respond_with(ResponseTemplate::new(200))
+            .mount(&server)
+            .await;
+
+        let _env = set_env(&[("CORAL_SESSION_ID", Some("session"))]).await;
+        let model = MockCompletionModel::default();
+        model.push_response(tool_calls(&["send"]), Usage::new());
+        model.push_error(provider_error());
+
+        let agent = test_agent(model, [TestTool::new("send")])
+            .telemetry_url(server.uri())
+            .telemetry(TelemetryMode::Generic, "test")
+            .telemetry_extractor("send", |_| {
+                vec![TelemetryTarget {
+                    message_id: "message".to_string(),
+                    thread_id: "thread".to_string(),
+                }]
+            });
+
+        let res = AgentLoop::new(agent, prompts(&["a"]))
+            .report_fatal_error(true)
+            .execute()
+            .await;
+        assert!(matches!(res, Err(Error::CompletionError(_))));
+
+        let requests = server.received_requests().await.unwrap();
+        assert_eq!(requests.len(), 2);
+        let first: serde_json::Value = serde_json::from_slice(&requests[0].body).unwrap();
+        assert!(first["data"]["additionalParams"][AGENT_ERROR_PARAM].is_null());
+
+        let report: serde_json::Value = serde_json::from_slice(&requests[1].body).unwrap();
+        assert_eq!(report["targets"][0]["messageId"], "message");
+        assert_eq!(
+            report["data"]["additionalParams"][AGENT_ERROR_PARAM]["category"],
+            "completion"
+        );
     }
 }
@@ -0,0 +1,172 @@
+use crate::api::generated::types::{AgentOption, AgentOptionValue};
+use crate::error::Error;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::path::Path;
+
+///
+/// Builds the options map for a [`crate::api::generated::types::GraphAgentRequest`], validating
+/// every value against the agent's option definitions.
+///
+/// Building the options map by hand makes it easy to mismatch a value with the type of the option
+/// it is set for, which is only caught by the server when the session is created.  This builder
+/// catches unknown options, type mismatches and missing required options client-side.
+///
+/// ```ignore
+/// let options = AgentOptionsBuilder::from_agent_toml("coral-agent.toml")?
+///     .set("OPENAI_API_KEY", "sk-...")?
+///     .build()?;
+/// ```
+#[derive(Debug, Clone)]
+pub struct AgentOptionsBuilder {
+    definitions: HashMap<String, AgentOption>,
+    values: HashMap<String, AgentOptionValue>,
+}
+
+///
+/// The parts of a `coral-agent.toml` file that are relevant to options
+#[derive(Deserialize)]
+struct AgentDefinitionFile {
+    #[serde(default)]
+    options: HashMap<String, toml::Value>,
+}
+
+impl AgentOptionsBuilder {
+    ///
+    /// Creates a new options builder from a set of option definitions
+    pub fn from_definitions(definitions: HashMap<String, AgentOption>) -> Self {
+        Self {
+            definitions,
+            values: HashMap::new(),
+        }
+    }
+
+    ///
+    /// Creates a new options builder using the `[options]` table of an agent definition
+    /// (`coral-agent.toml`) file.  Options that do not specify `required` are optional.
+    pub fn from_agent_toml(path: impl AsRef<Path>) -> Result<Self, Error> {
+        let path = path.as_ref();
+        let contents = std::fs::read_to_string(path).map_err(|e| {
+            Error::AgentDefinitionError(format!("failed to read {}: {e}", path.display()))
+        })?;
+
+        Self::from_agent_toml_str(&contents)
+    }
+
+    ///
+    /// The same as [`AgentOptionsBuilder::from_agent_toml`], but using the contents of the agent
+    /// definition file instead of a path
+    pub fn from_agent_toml_str(contents: &str) -> Result<Self, Error> {
+        let file: AgentDefinitionFile =
+            toml::from_str(contents).map_err(|e| Error::AgentDefinitionError(e.to_string()))?;
+
+        let mut definitions = HashMap::new();
+        for (name, mut definition) in file.options {
+            if let toml::Value::Table(table) = &mut definition {
+                table
+                    .entry("required")
+                    .or_insert(toml::Value::Boolean(false));
+            }
+
+            let definition = AgentOption::deserialize(definition)
+                .map_err(|e| Error::AgentDefinitionError(format!("option \"{name}\": {e}")))?;
+
+            definitions.insert(name, definition);
+        }
+
+        Ok(Self::from_definitions(definitions))
+    }
+
+    ///
+    /// Sets the value of an option.  Returns an error if the option is not defined or if the value
+    /// does not match the option's type.
+    pub fn set(
+        mut self,
+        name: impl Into<String>,
+        value: impl Into<AgentOptionValue>,
+    ) -> Result<Self, Error> {
+        let name = name.into();
+        let value = value.into();
+
+        let Some(definition) = self.definitions.get(&name) else {
+            return Err(Error::InvalidOption(format!("unknown option \"{name}\"")));
+        };
+
+        match (definition, &value) {
+            (AgentOption::Number { .. }, AgentOptionValue::Number(_))
+            | (AgentOption::String { .. }, AgentOptionValue::String(_))
+            | (AgentOption::Secret { .. }, AgentOptionValue::String(_)) => {}
+            (definition, value) => {
+                return Err(Error::InvalidOption(format!(
+                    "option \"{name}\" is {}, but was given {value:?}",
+                    Self::type_name(definition)
+                )));
+            }
+        }
+
+        self.values.insert(name, value);
+        Ok(self)
+    }
+
+    ///
+    /// Builds the options map.  Returns an error if a required option without a default value was
+    /// not set.
+    pub fn build(self) -> Result<HashMap<String, AgentOptionValue>, Error> {
+        let mut missing = self
+            .definitions
+            .iter()
+            .filter(|(name, definition)| {
+                !self.values.contains_key(*name) && Self::is_required(definition)
+            })
+            .map(|(name, _)| name.as_str())
+            .collect::<Vec<_>>();
+
+        if !missing.is_empty() {
+            missing.sort();
+            return Err(Error::InvalidOption(format!(
+                "missing required options: {}",
+                missing.join(", ")
+            )));
+        }
+
+        Ok(self.values)
+    }
+
+    ///
+    /// The names of every option declared as a secret, sorted
+    pub fn secret_names(&self) -> Vec<&str> {
+        let mut names = self
+            .definitions
+            .iter()
+            .filter(|(_, definition)| matches!(definition, AgentOption::Secret { .. }))
+            .map(|(name, _)| name.as_str())
+            .collect::<Vec<_>>();
+
+        names.sort();
+        names
+    }
+
+    ///
+    /// Returns true if the option must be given a value
+    fn is_required(definition: &AgentOption) -> bool {
+        match definition {
+            AgentOption::Number {
+                required, default, ..
+            } => *required && default.is_none(),
+            AgentOption::String {
+                required, default, ..
+            } => *required && default.is_none(),
+            AgentOption::Secret { required, .. } => *required,
+        }
+    }
+
+    ///
+    /// A human-readable name for an option's type
+    fn type_name(definition: &AgentOption) -> &'static str {
+        match definition {
+            AgentOption::Number { .. } => "a number",
+            AgentOption::String { .. } => "a string",
+            AgentOption::Secret { .. } => "a secret (string)",
+        }
+    }
+}
@@ -3,7 +3,9 @@ pub mod generated {
     include!(concat!(env!("OUT_DIR"), "/api_v1.rs"));
 }
 
-use crate::api::generated::types::AgentClaimAmount;
+use crate::api::generated::types::{
+    AgentClaimAmount, AgentOptionValue, McpToolResult, ResolvedMessage, ResolvedThread,
+};
 use generated::types;
 use std::ops::{Div, Mul};
 
@@ -349,6 +351,83 @@ impl std::fmt::Display for AgentClaimAmount {
     }
 }
 
+impl From<String> for AgentOptionValue {
+    fn from(value: String) -> Self {
+        AgentOptionValue::String(value)
+    }
+}
+
+impl From<&str> for AgentOptionValue {
+    fn from(value: &str) -> Self {
+        AgentOptionValue::String(value.to_string())
+    }
+}
+
+impl McpToolResult {
+    ///
+    /// The sent message, if this is the result of a successful send message tool call
+    pub fn as_send_message_success(&self) -> Option<&ResolvedMessage> {
+        match self {
+            McpToolResult::SendMessageSuccess { message } => Some(message),
+            _ => None,
+        }
+    }
+
+    ///
+    /// The created thread, if this is the result of a successful create thread tool call
+    pub fn as_create_thread_success(&self) -> Option<&ResolvedThread> {
+        match self {
+            McpToolResult::CreateThreadSuccess { thread } => Some(thread),
+            _ => None,
+        }
+    }
+
+    ///
+    /// The received messages, if this is the result of a successful wait for mentions tool call
+    pub fn as_wait_for_mentions_success(&self) -> Option<&[ResolvedMessage]> {
+        match self {
+            McpToolResult::WaitForMentionsSuccess { messages } => Some(messages),
+            _ => None,
+        }
+    }
+
+    ///
+    /// The ID of the sent message, if this is the result of a successful send message tool call
+    pub fn message_id(&self) -> Option<&str> {
+        self.as_send_message_success()
+            .map(|message| message.id.as_str())
+    }
+
+    ///
+    /// The ID of the thread that this result refers to, if this is the result of a successful send
+    /// message or create thread tool call
+    pub fn thread_id(&self) -> Option<&str> {
+        match self {
+            McpToolResult::SendMessageSuccess { message } => Some(&message.thread_id),
+            McpToolResult::CreateThreadSuccess { thread } => Some(&thread.id),
+            _ => None,
+        }
+    }
+
+    ///
+    /// The error message, if this result is an error
+    pub fn error_message(&self) -> Option<&str> {
+        match self {
+            McpToolResult::Error { message } | McpToolResult::ToolInputError { message } => {
+                Some(message)
+            }
+            McpToolResult::ErrorTimeout => Some("timeout"),
+            _ => None,
+        }
+    }
+
+    ///
+    /// Returns true if this result is any kind of error
+    pub fn is_error(&self) -> bool {
+        self.error_message().is_some()
+    }
+}
+
 macro_rules! impl_claim_math {
     ($($t:ty),+) => {
         $(
@@ -0,0 +1,67 @@
+use crate::completion_evaluated_prompt::CompletionEvaluatedPrompt;
+use futures::{Stream, stream};
+use tokio::sync::mpsc;
+
+///
+/// Sends prompts to a stream created by [`channel_prompt_stream`].  Senders can be cloned, the
+/// stream ends once every sender has been dropped.
+#[derive(Clone)]
+pub struct PromptSender(mpsc::UnboundedSender<CompletionEvaluatedPrompt>);
+
+impl PromptSender {
+    ///
+    /// Queues a prompt.  If the stream has been dropped (for example, because the agent loop has
+    /// ended), the prompt is returned as an error.
+    pub fn send(
+        &self,
+        prompt: impl Into<CompletionEvaluatedPrompt>,
+    ) -> Result<(), CompletionEvaluatedPrompt> {
+        self.0.send(prompt.into()).map_err(|e| e.0)
+    }
+
+    ///
+    /// Returns true if the stream has been dropped, and prompts can no longer be sent
+    pub fn is_closed(&self) -> bool {
+        self.0.is_closed()
+    }
+}
+
+///
+/// Creates a prompt stream that yields prompts as they are sent with the returned
+/// [`PromptSender`], for example, to feed incoming user messages into an
+/// [`crate::agent_loop::AgentLoop`] at runtime.  Prompts are yielded in the order they were sent,
+/// and prompts sent while a prompt iteration is running wait for the next iteration.  The stream
+/// ends when every sender has been dropped.
+///
+/// ```no_run
+/// # use coral_rs::agent::Agent;
+/// # use coral_rs::agent_loop::AgentLoop;
+/// # use coral_rs::channel_prompt_stream::channel_prompt_stream;
+/// # use coral_rs::completion_evaluated_prompt::CompletionEvaluatedPrompt;
+/// # use coral_rs::mock_completion_model::MockCompletionModel;
+/// # async fn run(
+/// #     agent: Agent<MockCompletionModel>,
+/// #     mut incoming_messages: tokio::sync::mpsc::Receiver<String>,
+/// # ) -> Result<(), coral_rs::error::Error> {
+/// let (sender, prompts) = channel_prompt_stream();
+/// tokio::spawn(async move {
+///     while let Some(message) = incoming_messages.recv().await {
+///         let prompt = CompletionEvaluatedPrompt::new().string(format!("A user asked: {message}"));
+///         if sender.send(prompt).is_err() {
+///             break;
+///         }
+///     }
+/// });
+///
+/// AgentLoop::new(agent, prompts).execute().await?;
+/// # Ok(())
+/// # }
+/// ```
+pub fn channel_prompt_stream() -> (PromptSender, impl Stream<Item = CompletionEvaluatedPrompt>) {
+    let (sender, receiver) = mpsc::unbounded_channel();
+    let stream = stream::unfold(receiver, |mut receiver| async move {
+        receiver.recv().await.map(|prompt| (prompt, receiver))
+    });
+
+    (PromptSender(sender), stream)
+}
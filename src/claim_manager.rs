@@ -4,12 +4,24 @@ use crate::api::generated::types::{
 };
 use crate::error::Error;
 use rig::completion::Usage;
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
 use std::ops::{Div, Mul};
-use tracing::{info, warn};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use tracing::{error, info, warn};
 
 const MICRO_CORAL_TO_CORAL: f64 = 1_000_000.0;
 
+///
+/// The number of recent iterations averaged by [`ClaimManager::estimated_remaining_iterations`]
+pub const RECENT_ITERATION_WINDOW: usize = 10;
+
+tokio::task_local! {
+    ///
+    /// Set for the duration of [`ClaimManager::without_claims`]
+    static CLAIMS_SUPPRESSED: ();
+}
+
 ///
 /// When a Coral agent is run in remote mode, it must make "claims".  The agent claims to have
 /// performed a certain amount of work for a certain amount of currency.  Claiming is done through
@@ -28,18 +40,78 @@ const MICRO_CORAL_TO_CORAL: f64 = 1_000_000.0;
 ///
 /// Note that the environment variable `CORAL_SEND_CLAIMS` must be set to `1` for a claim manager to
 /// send claims.  The Coral server will set this variable during orchestration in remote sessions.
+#[derive(Clone)]
 pub struct ClaimManager {
+    ///
+    /// The costs used when claiming.  These are shared between clones of this claim manager and
+    /// can be changed at runtime with [`ClaimManager::update_costs`].
+    costs: Arc<RwLock<CostConfig>>,
+
+    ///
+    /// Whether the agent should exit when the budget has been exhausted.  This should almost always
+    /// be true, if this value is not true, the agent will perform work for free when the budget has
+    /// been exhausted.
+    ///
+    /// The budget will be evaluated after a claim is made.
+    exit_on_budget_exhausted: bool,
+
+    ///
+    /// If set, the remaining budget reported by the server is compared against the remaining
+    /// budget expected from the claims this manager has made.  A divergence larger than this
+    /// tolerance is logged (or returned as an error, see [`strict_reconciliation`]).
+    reconciliation_tolerance: Option<ClaimAmount>,
+
+    ///
+    /// Whether a reconciliation divergence should be returned as an error instead of logged
+    strict_reconciliation: bool,
+
+    ///
+    /// Whether [`ClaimManager::verify`] should return an error when costs are configured but
+    /// claims will not be sent
+    require_send_claims: bool,
+
+    ///
+    /// If set, [`Error::TokenLimitReached`] is returned once more than this many tokens have been
+    /// claimed for
+    max_session_tokens: Option<u64>,
+
+    ///
+    /// If set, claims smaller than this are accumulated until their total reaches it, see
+    /// [`ClaimManager::min_claim`]
+    min_claim: Option<ClaimAmount>,
+
+    ///
+    /// Set once the "claims will not be sent" warning has been generated
+    send_claims_warned: Arc<AtomicBool>,
+
+    ///
+    /// Running totals used for reconciliation and iteration estimates
+    ledger: Arc<Mutex<ClaimLedger>>,
+
+    ///
+    /// API url from CORAL_API_URL
+    api_url: String,
+
+    ///
+    /// Session ID for this agent that must be used in API claims
+    remote_session_id: String,
+}
+
+///
+/// The costs a [`ClaimManager`] claims with.  See [`ClaimManager::update_costs`].
+#[derive(Debug, Clone)]
+pub struct CostConfig {
     ///
     /// The base cost of an input token.  Note that token usage is reported OPTIONALLY by the AI the model
     /// provider.  Check that the model provider you are using provides this information if you
     /// intend to use this metric.
     ///
     /// Tokens will be claimed after every completion (tool or prompt iteration)
-    input_token_cost: ClaimAmount,
+    pub input_token_cost: ClaimAmount,
 
     ///
-    /// The base cost of an output token.  See [`input_token_cost`] for more information.
-    output_token_cost: ClaimAmount,
+    /// The base cost of an output token.  See [`Self::input_token_cost`] for more information.
+    pub output_token_cost: ClaimAmount,
 
     ///
     /// The minimum amount of budget required to continue doing completions.  This can be used to
@@ -48,128 +120,181 @@ pub struct ClaimManager {
     /// budget of 500
     ///
     /// The min budget will be evaluated before: tool executions, prompt iterations and tool iterations
-    min_budget: ClaimAmount,
+    pub min_budget: ClaimAmount,
 
     ///
     /// A base cost added for any invocation of a tool.  Note that this will include Coral tooling,
     /// which is required to be used in a Coral agent.
     ///
     /// Tool calls will be claimed after execution
-    base_tool_call_cost: ClaimAmount,
+    pub base_tool_call_cost: ClaimAmount,
 
     ///
     /// This map can be used to add a custom cost associated with a tool.  The key is the name of
     /// the tool and the value is the cost.  The cost will be added to the base_tool_call_cost
     ///
     /// Tool calls will be claimed after execution
-    custom_tool_cost: HashMap<String, ClaimAmount>,
+    pub custom_tool_cost: HashMap<String, ClaimAmount>,
 
     ///
     /// The cost to perform one iteration.  A single iteration may contain one or more tool
     /// iterations.
     ///
     /// An iteration cost will be claimed after a prompt iteration
-    base_iteration_cost: ClaimAmount,
+    pub base_iteration_cost: ClaimAmount,
 
     ///
     /// A tool iteration is an iteration that occurs because of one or more tool calls.  A tool
     /// iteration is always exactly one model completion request.
     ///
     /// A tool iteration cost will be claimed after tool iteration
-    base_tool_iteration_cost: ClaimAmount,
+    pub base_tool_iteration_cost: ClaimAmount,
+}
 
-    ///
-    /// Whether the agent should exit when the budget has been exhausted.  This should almost always
-    /// be true, if this value is not true, the agent will perform work for free when the budget has
-    /// been exhausted.
-    ///
-    /// The budget will be evaluated after a claim is made.
-    exit_on_budget_exhausted: bool,
+impl Default for CostConfig {
+    fn default() -> Self {
+        Self {
+            input_token_cost: ClaimAmount::MicroCoral(0),
+            output_token_cost: ClaimAmount::MicroCoral(0),
+            min_budget: ClaimAmount::MicroCoral(0),
+            base_tool_call_cost: ClaimAmount::MicroCoral(0),
+            custom_tool_cost: HashMap::new(),
+            base_iteration_cost: ClaimAmount::MicroCoral(0),
+            base_tool_iteration_cost: ClaimAmount::MicroCoral(0),
+        }
+    }
+}
 
-    ///
-    /// API url from CORAL_API_URL
-    api_url: String,
+///
+/// A local record of claims, used to reconcile against the server's reported remaining budget and
+/// to estimate the remaining iterations
+#[derive(Default)]
+struct ClaimLedger {
+    /// The remaining budget before the first claim, derived from the first server response
+    initial_budget: Option<i64>,
 
-    ///
-    /// Session ID for this agent that must be used in API claims
-    remote_session_id: String,
+    /// The total amount claimed, in micro-coral
+    claimed: i64,
+
+    /// The remaining budget and coral price reported by the most recent claim
+    last_budget: Option<(i64, f64)>,
+
+    /// The amount claimed since the last iteration finished, in micro-coral
+    iteration_claimed: i64,
+
+    /// The amounts claimed by the most recent iterations, in micro-coral
+    recent_iterations: VecDeque<i64>,
+
+    /// The total number of tokens claimed for
+    tokens: u64,
+
+    /// Coral and micro-coral amounts accumulated by [`ClaimManager::min_claim`], in coral
+    pending_coral: f64,
+
+    /// USD amounts accumulated by [`ClaimManager::min_claim`]
+    pending_usd: f64,
 }
 
 impl ClaimManager {
     ///
     /// Creates a new claim manager with every claim value set to zero.  This function will panic if
-    /// `CORAL_API_URL` or `CORAL_SESSION_ID` are not set environment variables.
+    /// `CORAL_API_URL` or `CORAL_SESSION_ID` are not set environment variables, see
+    /// [`ClaimManager::try_new`].
     ///
     /// Claims will not be sent if `CORAL_SEND_CLAIMS` is not equal to `1`
+    #[allow(clippy::new_without_default)]
     pub fn new() -> Self {
+        Self::try_new().unwrap_or_else(|e| panic!("{e}"))
+    }
+
+    ///
+    /// The same as [`ClaimManager::new`], but returns [`Error::MissingEnv`] instead of panicking
+    /// if `CORAL_API_URL` or `CORAL_SESSION_ID` is not set.
+    ///
+    /// In local mode (`CORAL_SEND_CLAIMS` is not `1`) no claims are sent, so neither variable is
+    /// required, and a claim manager that never sends claims is returned.  This lets the same
+    /// binary run locally and in remote sessions.
+    pub fn try_new() -> Result<Self, Error> {
+        let env = |name: &str| match std::env::var(name) {
+            Ok(value) => Ok(value),
+            Err(_) if !Self::send_claims() => Ok(String::new()),
+            Err(_) => Err(Error::MissingEnv(name.to_string())),
+        };
+
+        Ok(Self::with_api(
+            env("CORAL_API_URL")?,
+            env("CORAL_SESSION_ID")?,
+        ))
+    }
+
+    ///
+    /// Creates a new claim manager with every claim value set to zero, sending claims to the given
+    /// API url for the given remote session instead of reading them from the environment.  This can
+    /// be used to point a claim manager at a mock Coral server.
+    ///
+    /// Claims will not be sent if `CORAL_SEND_CLAIMS` is not equal to `1`
+    pub fn with_api(api_url: impl Into<String>, remote_session_id: impl Into<String>) -> Self {
         Self {
-            input_token_cost: ClaimAmount::MicroCoral(0),
-            output_token_cost: ClaimAmount::MicroCoral(0),
-            min_budget: ClaimAmount::MicroCoral(0),
-            base_tool_call_cost: ClaimAmount::MicroCoral(0),
-            custom_tool_cost: HashMap::new(),
-            base_iteration_cost: ClaimAmount::MicroCoral(0),
-            base_tool_iteration_cost: ClaimAmount::MicroCoral(0),
+            costs: Arc::new(RwLock::new(CostConfig::default())),
             exit_on_budget_exhausted: true,
-            api_url: std::env::var("CORAL_API_URL").expect("CORAL_API_URL not set"),
-            remote_session_id: std::env::var("CORAL_SESSION_ID").expect("CORAL_SESSION_ID not set"),
+            reconciliation_tolerance: None,
+            strict_reconciliation: false,
+            require_send_claims: false,
+            max_session_tokens: None,
+            min_claim: None,
+            send_claims_warned: Arc::new(AtomicBool::new(false)),
+            ledger: Arc::new(Mutex::new(ClaimLedger::default())),
+            api_url: api_url.into(),
+            remote_session_id: remote_session_id.into(),
         }
     }
 
     ///
     /// Sets the cost per singular input token
-    pub fn input_token_cost(mut self, input_token_cost: ClaimAmount) -> Self {
-        self.input_token_cost = input_token_cost;
-        self
+    pub fn input_token_cost(self, input_token_cost: ClaimAmount) -> Self {
+        self.with_costs(|costs| costs.input_token_cost = input_token_cost)
     }
 
     ///
     /// Sets the cost per million input tokens
-    pub fn mil_input_token_cost(mut self, input_token_cost: ClaimAmount) -> Self {
-        self.input_token_cost = input_token_cost.div(1_000_000);
-        self
+    pub fn mil_input_token_cost(self, input_token_cost: ClaimAmount) -> Self {
+        self.with_costs(|costs| costs.input_token_cost = input_token_cost.div(1_000_000))
     }
 
     ///
     /// Sets the cost per singular output token
-    pub fn output_token_cost(mut self, output_token_cost: ClaimAmount) -> Self {
-        self.output_token_cost = output_token_cost;
-        self
+    pub fn output_token_cost(self, output_token_cost: ClaimAmount) -> Self {
+        self.with_costs(|costs| costs.output_token_cost = output_token_cost)
     }
 
     ///
     /// Sets the cost per million output tokens
-    pub fn mil_output_token_cost(mut self, output_token_cost: ClaimAmount) -> Self {
-        self.output_token_cost = output_token_cost.div(1_000_000);
-        self
+    pub fn mil_output_token_cost(self, output_token_cost: ClaimAmount) -> Self {
+        self.with_costs(|costs| costs.output_token_cost = output_token_cost.div(1_000_000))
     }
 
     ///
     /// Sets the minimum budget
-    pub fn min_budget(mut self, min_budget: ClaimAmount) -> Self {
-        self.min_budget = min_budget;
-        self
+    pub fn min_budget(self, min_budget: ClaimAmount) -> Self {
+        self.with_costs(|costs| costs.min_budget = min_budget)
     }
 
     ///
     /// Sets the base tool call cost
-    pub fn base_tool_call_cost(mut self, base_tool_call_cost: ClaimAmount) -> Self {
-        self.base_tool_call_cost = base_tool_call_cost;
-        self
+    pub fn base_tool_call_cost(self, base_tool_call_cost: ClaimAmount) -> Self {
+        self.with_costs(|costs| costs.base_tool_call_cost = base_tool_call_cost)
     }
 
     ///
     /// Sets the base iteration cost
-    pub fn base_iteration_cost(mut self, base_iteration_cost: ClaimAmount) -> Self {
-        self.base_iteration_cost = base_iteration_cost;
-        self
+    pub fn base_iteration_cost(self, base_iteration_cost: ClaimAmount) -> Self {
+        self.with_costs(|costs| costs.base_iteration_cost = base_iteration_cost)
     }
 
     ///
     /// Sets the base tool iteration cost
-    pub fn base_tool_iteration_cost(mut self, base_tool_iteration_cost: ClaimAmount) -> Self {
-        self.base_tool_iteration_cost = base_tool_iteration_cost;
-        self
+    pub fn base_tool_iteration_cost(self, base_tool_iteration_cost: ClaimAmount) -> Self {
+        self.with_costs(|costs| costs.base_tool_iteration_cost = base_tool_iteration_cost)
     }
     ///
     /// Sets whether to exit when the budget has been exhausted
@@ -179,23 +304,287 @@ impl ClaimManager {
     }
 
     ///
-    /// Adds a new custom tool cost by name
-    pub fn custom_tool_cost(mut self, tool_name: impl Into<String>, cost: ClaimAmount) -> Self {
-        self.custom_tool_cost.insert(tool_name.into(), cost);
+    /// Enables claim reconciliation.  After every claim, the remaining budget reported by the
+    /// server is compared with the remaining budget expected from the claims this manager has
+    /// made.  If they differ by more than `tolerance`, the divergence is logged as an error.
+    ///
+    /// Small divergences are expected from rounding, especially when costs are expressed in USD.
+    /// Large divergences indicate accounting drift, for example, claims being sent twice.
+    pub fn reconciliation_tolerance(mut self, tolerance: Option<ClaimAmount>) -> Self {
+        self.reconciliation_tolerance = tolerance;
+        self
+    }
+
+    ///
+    /// If set to true, a reconciliation divergence will return [`Error::ClaimDivergence`] instead
+    /// of only being logged.  Has no effect unless [`ClaimManager::reconciliation_tolerance`] is
+    /// set.
+    pub fn strict_reconciliation(mut self, strict_reconciliation: bool) -> Self {
+        self.strict_reconciliation = strict_reconciliation;
         self
     }
 
+    ///
+    /// If set to true, [`ClaimManager::verify`] returns [`Error::ClaimsNotSent`] when costs are
+    /// configured but `CORAL_SEND_CLAIMS` is not set to `1`, instead of only generating a warning.
+    /// This turns an "agent worked for free" misconfiguration into a hard failure.
+    pub fn require_send_claims(mut self, require_send_claims: bool) -> Self {
+        self.require_send_claims = require_send_claims;
+        self
+    }
+
+    ///
+    /// Sets a local limit on the total number of tokens claimed for in this session.  Once more
+    /// than `max_session_tokens` tokens have been claimed for, the completion that crossed the
+    /// limit is still claimed, then [`Error::TokenLimitReached`] is returned.
+    ///
+    /// This is a safety net against a runaway agent that is independent of the budget reported by
+    /// the server.  Tokens used in [`ClaimManager::without_claims`] are not counted.
+    pub fn max_session_tokens(mut self, max_session_tokens: u64) -> Self {
+        self.max_session_tokens = Some(max_session_tokens);
+        self
+    }
+
+    ///
+    /// Sets a minimum claim amount.  Claims are accumulated locally and sent as one claim once
+    /// their total reaches `min_claim`, instead of each being sent to the server.  This avoids
+    /// sending many tiny claims (for example, token claims with small per-token costs), which can
+    /// round to zero micro-coral individually.
+    ///
+    /// Coral and micro-coral amounts are accumulated in coral and USD amounts in USD, so nothing
+    /// is lost to rounding.  Comparing USD with coral uses the conversion rate reported by the
+    /// most recent claim; until one has been sent, a claim that needs conversion is sent
+    /// immediately.  The accumulated remainder is sent by [`ClaimManager::flush_claims`], which
+    /// [`crate::agent_loop::AgentLoop`] calls when the loop ends.
+    ///
+    /// Accumulated claims are not known to the server, so the remaining budget it reports is
+    /// higher than the real remaining budget by up to `min_claim` for each of coral and USD.  The
+    /// budget exhaustion check and [`ClaimManager::estimated_remaining_iterations`] subtract the
+    /// accumulated amount from the last reported budget, and accumulated claims are sent as soon
+    /// as they would exhaust it, but work done between two claims can still overspend the budget
+    /// by up to `min_claim` per currency.  Claims are attributed to the iteration that made them,
+    /// not the iteration that sent them.
+    pub fn min_claim(mut self, min_claim: ClaimAmount) -> Self {
+        self.min_claim = Some(min_claim);
+        self
+    }
+
+    ///
+    /// Sends any claims accumulated because of [`ClaimManager::min_claim`], regardless of the
+    /// minimum
+    pub async fn flush_claims(&self) -> Result<(), Error> {
+        let pending = {
+            let mut ledger = self.ledger.lock().unwrap();
+            Self::take_pending(&mut ledger)
+        };
+
+        for amount in pending {
+            info!("claiming {amount} accumulated from small claims");
+            self.send_claim(amount, false).await?;
+        }
+
+        Ok(())
+    }
+
+    ///
+    /// Adds a claim of `micro` micro-coral to the accumulated claims and to the current iteration.
+    /// Returns the claims to send, which are either nothing, or every accumulated claim if their
+    /// total has reached [`ClaimManager::min_claim`] or would exhaust the budget.
+    fn accumulate(
+        &self,
+        min_claim: &ClaimAmount,
+        amount: ClaimAmount,
+        micro: i64,
+    ) -> Vec<ClaimAmount> {
+        let min_budget = self.costs.read().unwrap().min_budget.clone();
+        let mut ledger = self.ledger.lock().unwrap();
+        match amount {
+            AgentClaimAmount::Coral(coral) => ledger.pending_coral += coral,
+            AgentClaimAmount::MicroCoral(micro) => {
+                ledger.pending_coral += micro as f64 / MICRO_CORAL_TO_CORAL
+            }
+            AgentClaimAmount::Usd(usd) => ledger.pending_usd += usd,
+        }
+        ledger.iteration_claimed = ledger.iteration_claimed.saturating_add(micro);
+
+        let coral_usd_price = ledger.last_budget.map_or(0.0, |(_, price)| price);
+        let pending = Self::pending_micro(&ledger, coral_usd_price);
+        let exhausted = self.exit_on_budget_exhausted
+            && ledger.last_budget.is_some_and(|(remaining_budget, _)| {
+                match (&pending, Self::to_micro(&min_budget, coral_usd_price)) {
+                    (Ok(pending), Ok(min_budget)) => {
+                        remaining_budget.saturating_sub(*pending) <= min_budget
+                    }
+                    _ => true,
+                }
+            });
+
+        match (pending, Self::to_micro(min_claim, coral_usd_price)) {
+            (Ok(pending), Ok(min_claim)) if pending < min_claim && !exhausted => Vec::new(),
+            _ => Self::take_pending(&mut ledger),
+        }
+    }
+
+    ///
+    /// The total of the accumulated claims, in micro-coral
+    fn pending_micro(ledger: &ClaimLedger, coral_usd_price: f64) -> Result<i64, Error> {
+        let coral = Self::to_micro(&AgentClaimAmount::Coral(ledger.pending_coral), 0.0)?;
+        if ledger.pending_usd == 0.0 {
+            return Ok(coral);
+        }
+
+        let usd = AgentClaimAmount::Usd(ledger.pending_usd);
+        Ok(coral.saturating_add(Self::to_micro(&usd, coral_usd_price)?))
+    }
+
+    ///
+    /// Removes and returns the accumulated claims
+    fn take_pending(ledger: &mut ClaimLedger) -> Vec<ClaimAmount> {
+        let coral = std::mem::take(&mut ledger.pending_coral);
+        let usd = std::mem::take(&mut ledger.pending_usd);
+
+        [AgentClaimAmount::Coral(coral), AgentClaimAmount::Usd(usd)]
+            .into_iter()
+            .filter(|amount| !amount.is_zero())
+            .collect()
+    }
+
+    ///
+    /// The total number of tokens claimed for so far, whether or not they had a cost
+    pub fn session_tokens(&self) -> u64 {
+        self.ledger.lock().unwrap().tokens
+    }
+
+    ///
+    /// Checks that this claim manager will actually send its claims.  If any cost is configured
+    /// but `CORAL_SEND_CLAIMS` is not set to `1`, no claims will be sent, and the agent will work
+    /// for free.  In that case, a warning is generated (once), or [`Error::ClaimsNotSent`] is
+    /// returned if [`ClaimManager::require_send_claims`] is set.
+    ///
+    /// This is called automatically by [`crate::agent::Agent`] before every completion, but can be
+    /// called at startup to catch the misconfiguration as early as possible.
+    pub fn verify(&self) -> Result<(), Error> {
+        if Self::send_claims() || !self.has_costs() {
+            return Ok(());
+        }
+
+        if self.require_send_claims {
+            return Err(Error::ClaimsNotSent);
+        }
+
+        if !self.send_claims_warned.swap(true, Ordering::Relaxed) {
+            warn!(
+                "claim costs are configured but CORAL_SEND_CLAIMS is not set to 1, no claims will be sent!  this agent will work for free"
+            );
+        }
+
+        Ok(())
+    }
+
+    ///
+    /// Returns true if any cost is configured
+    fn has_costs(&self) -> bool {
+        let costs = self.costs.read().unwrap();
+        !costs.input_token_cost.is_zero()
+            || !costs.output_token_cost.is_zero()
+            || !costs.base_tool_call_cost.is_zero()
+            || !costs.base_iteration_cost.is_zero()
+            || !costs.base_tool_iteration_cost.is_zero()
+            || costs.custom_tool_cost.values().any(|cost| !cost.is_zero())
+    }
+
+    ///
+    /// CORAL_SEND_CLAIMS must be '1' to send claims to the server, if this is not set, it
+    /// indicates the agent is running in local mode
+    pub(crate) fn send_claims() -> bool {
+        std::env::var("CORAL_SEND_CLAIMS") == Ok("1".to_string())
+    }
+
+    ///
+    /// Adds a new custom tool cost by name
+    pub fn custom_tool_cost(self, tool_name: impl Into<String>, cost: ClaimAmount) -> Self {
+        self.with_costs(|costs| {
+            costs.custom_tool_cost.insert(tool_name.into(), cost);
+        })
+    }
+
     ///
     /// Adds a new tool cost for a Coral tool
-    pub fn coral_custom_tool_cost(mut self, tool_name: McpToolName, cost: ClaimAmount) -> Self {
-        self.custom_tool_cost.insert(tool_name.to_string(), cost);
+    pub fn coral_custom_tool_cost(self, tool_name: McpToolName, cost: ClaimAmount) -> Self {
+        self.with_costs(|costs| {
+            costs.custom_tool_cost.insert(tool_name.to_string(), cost);
+        })
+    }
+
+    ///
+    /// Changes the costs used by this claim manager at runtime, for example, to track a change in
+    /// the active model.  Claim managers are cheap to clone and clones share their costs, so a
+    /// clone can be kept to update the costs of a claim manager attached to an
+    /// [`crate::agent::Agent`].  The new costs apply to every claim made after this call.
+    pub fn update_costs(&self, update: impl FnOnce(&mut CostConfig)) {
+        update(&mut self.costs.write().unwrap());
+    }
+
+    ///
+    /// Runs an operation with claims suppressed.  Any claim made by any claim manager while the
+    /// operation is running, on the same task, is skipped.  This can be used to separate billable
+    /// work from internal work that should not be billed, such as health checks.
+    ///
+    /// Suppression only applies to the task running the operation; claims made from other tasks
+    /// (including tasks spawned by the operation) are not affected.
+    pub async fn without_claims<F: Future>(operation: F) -> F::Output {
+        CLAIMS_SUPPRESSED.scope((), operation).await
+    }
+
+    ///
+    /// Returns a copy of the costs currently used by this claim manager
+    pub fn costs(&self) -> CostConfig {
+        self.costs.read().unwrap().clone()
+    }
+
+    ///
+    /// Builder helper for [`ClaimManager::update_costs`]
+    fn with_costs(self, update: impl FnOnce(&mut CostConfig)) -> Self {
+        self.update_costs(update);
         self
     }
 
     ///
-    /// Claim for tokens used
+    /// Claim for tokens used, then check the session token limit
     pub(crate) async fn claim_tokens(&self, usage: &Usage) -> Result<(), Error> {
-        if self.input_token_cost.is_zero() && self.output_token_cost.is_zero() {
+        self.claim_token_costs(usage).await?;
+
+        if CLAIMS_SUPPRESSED.try_with(|_| ()).is_ok() {
+            return Ok(());
+        }
+
+        let tokens = {
+            let mut ledger = self.ledger.lock().unwrap();
+            ledger.tokens += usage.total_tokens;
+            ledger.tokens
+        };
+
+        match self.max_session_tokens {
+            Some(max_session_tokens) if tokens > max_session_tokens => {
+                warn!(
+                    "{tokens} tokens used, which is over the session limit of {max_session_tokens}"
+                );
+                Err(Error::TokenLimitReached)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    ///
+    /// Claim for the cost of tokens used
+    async fn claim_token_costs(&self, usage: &Usage) -> Result<(), Error> {
+        let CostConfig {
+            input_token_cost,
+            output_token_cost,
+            ..
+        } = self.costs();
+
+        if input_token_cost.is_zero() && output_token_cost.is_zero() {
             info!("not claiming tokens because input_token_cost and output_token_cost are zero");
             return Ok(());
         }
@@ -205,7 +594,7 @@ impl ClaimManager {
             // provider did provide token usage but only gave it to us as total tokens.  In this
             // case the output token price will be used.  If the claim manager has a cost specified
             // for input tokens, a warning should be generated
-            if !self.input_token_cost.is_zero() {
+            if !input_token_cost.is_zero() {
                 warn!(
                     "provider only reported total token usage, input_token_cost will be ignored!  token cost will be claimed used output_token_cost"
                 )
@@ -213,79 +602,201 @@ impl ClaimManager {
 
             info!(
                 "claiming {} for {} tokens",
-                self.output_token_cost, usage.total_tokens
+                output_token_cost, usage.total_tokens
             );
             return self
-                .claim(self.output_token_cost.clone().mul(usage.total_tokens))
+                .claim(output_token_cost.clone().mul(usage.total_tokens))
                 .await;
         } else if usage.total_tokens == 0 {
             warn!("provider reported zero tokens!");
         } else {
             info!(
                 "claiming {} for {} input tokens",
-                self.input_token_cost, usage.input_tokens
+                input_token_cost, usage.input_tokens
             );
-            self.claim(self.input_token_cost.clone().mul(usage.input_tokens))
+            self.claim(input_token_cost.clone().mul(usage.input_tokens))
                 .await?;
 
             info!(
                 "claiming {} for {} output tokens",
-                self.output_token_cost, usage.output_tokens
+                output_token_cost, usage.output_tokens
             );
-            self.claim(self.output_token_cost.clone().mul(usage.output_tokens))
+            self.claim(output_token_cost.clone().mul(usage.output_tokens))
                 .await?;
         }
 
         Ok(())
     }
 
+    ///
+    /// Estimates the cost of the tokens in `usage` using the same rules as token claims.  Returns
+    /// None if the input and output token costs use units that cannot be added without the current
+    /// coral price (USD and coral).
+    pub fn estimate_token_cost(&self, usage: &Usage) -> Option<ClaimAmount> {
+        let CostConfig {
+            input_token_cost,
+            output_token_cost,
+            ..
+        } = self.costs();
+
+        if usage.input_tokens + usage.output_tokens != usage.total_tokens {
+            return Some(output_token_cost.mul(usage.total_tokens));
+        }
+
+        let input = input_token_cost.mul(usage.input_tokens);
+        let output = output_token_cost.mul(usage.output_tokens);
+        match (input, output) {
+            (AgentClaimAmount::Coral(a), AgentClaimAmount::Coral(b)) => {
+                Some(AgentClaimAmount::Coral(a + b))
+            }
+            (AgentClaimAmount::Usd(a), AgentClaimAmount::Usd(b)) => {
+                Some(AgentClaimAmount::Usd(a + b))
+            }
+            (AgentClaimAmount::Usd(_), _) | (_, AgentClaimAmount::Usd(_)) => None,
+            (a, b) => Some(AgentClaimAmount::MicroCoral(
+                Self::to_micro(&a, 0.0)
+                    .ok()?
+                    .saturating_add(Self::to_micro(&b, 0.0).ok()?),
+            )),
+        }
+    }
+
     ///
     /// Claim for one prompt iteration
     pub(crate) async fn claim_iteration(&self) -> Result<(), Error> {
-        if !self.base_iteration_cost.is_zero() {
-            info!(
-                "claiming {} for one prompt iteration",
-                self.base_iteration_cost
-            );
-            self.claim(self.base_iteration_cost.clone()).await
+        let base_iteration_cost = self.costs.read().unwrap().base_iteration_cost.clone();
+        let res = if !base_iteration_cost.is_zero() {
+            info!("claiming {} for one prompt iteration", base_iteration_cost);
+            self.claim(base_iteration_cost).await
         } else {
             info!("not claiming prompt iteration because base_iteration_cost is zero");
             Ok(())
-        }
+        };
+
+        self.finish_iteration();
+        res
     }
 
     ///
     /// Claim for one tool iteration
     pub(crate) async fn claim_tool_iteration(&self) -> Result<(), Error> {
-        if !self.base_tool_iteration_cost.is_zero() {
+        let base_tool_iteration_cost = self.costs.read().unwrap().base_tool_iteration_cost.clone();
+        let res = if !base_tool_iteration_cost.is_zero() {
             info!(
                 "claiming {} for one tool iteration",
-                self.base_tool_iteration_cost
+                base_tool_iteration_cost
             );
-            self.claim(self.base_tool_iteration_cost.clone()).await
+            self.claim(base_tool_iteration_cost).await
         } else {
             info!("not claiming tool iteration because base_tool_iteration_cost is zero");
             Ok(())
+        };
+
+        self.finish_iteration();
+        res
+    }
+
+    ///
+    /// Records the amount claimed during the iteration that just finished
+    fn finish_iteration(&self) {
+        let mut ledger = self.ledger.lock().unwrap();
+        let claimed = std::mem::take(&mut ledger.iteration_claimed);
+        if claimed > 0 {
+            if ledger.recent_iterations.len() == RECENT_ITERATION_WINDOW {
+                ledger.recent_iterations.pop_front();
+            }
+
+            ledger.recent_iterations.push_back(claimed);
+        }
+    }
+
+    ///
+    /// The remaining budget reported by the Coral server after the most recent claim, in
+    /// micro-coral.  None if no claim has been sent yet.  Use [`ClaimManager::remaining_budget`]
+    /// to ask the Coral server for the current budget.
+    pub fn last_known_budget(&self) -> Option<i64> {
+        self.ledger
+            .lock()
+            .unwrap()
+            .last_budget
+            .map(|(remaining_budget, _)| remaining_budget)
+    }
+
+    ///
+    /// Asks the Coral server for the remaining budget, in micro-coral, without charging anything.
+    /// The server has no budget query endpoint, so this sends a claim for zero micro-coral.  Unlike
+    /// [`ClaimManager::last_known_budget`], this does not depend on a claim having been sent, which
+    /// makes it suitable for polling the budget from a dashboard.
+    ///
+    /// Returns [`Error::BudgetUnavailable`] if the agent is not running in a remote session
+    /// (`CORAL_SEND_CLAIMS` is not set to `1`).
+    pub async fn remaining_budget(&self) -> Result<i64, Error> {
+        if !Self::send_claims() {
+            return Err(Error::BudgetUnavailable);
+        }
+
+        let budget = Client::new(self.api_url.as_str())
+            .claim_payment(
+                self.remote_session_id.as_str(),
+                &AgentPaymentClaimRequest {
+                    amount: ClaimAmount::MicroCoral(0),
+                },
+            )
+            .await
+            .map_err(|e| Error::ApiError(Box::new(e)))?
+            .into_inner();
+
+        self.ledger.lock().unwrap().last_budget =
+            Some((budget.remaining_budget, budget.coral_usd_price));
+
+        Ok(budget.remaining_budget)
+    }
+
+    ///
+    /// Estimates how many more iterations (completions) can be paid for, by dividing the
+    /// remaining budget above [`CostConfig::min_budget`] by the average amount claimed in the last
+    /// [`RECENT_ITERATION_WINDOW`] iterations.  This can be used to pace an agent rather than
+    /// running until the budget is abruptly exhausted.
+    ///
+    /// Returns None if no claim has been sent yet, or if no iteration has claimed anything.
+    pub fn estimated_remaining_iterations(&self) -> Option<u64> {
+        let min_budget = self.costs.read().unwrap().min_budget.clone();
+        let ledger = self.ledger.lock().unwrap();
+        let (remaining_budget, coral_usd_price) = ledger.last_budget?;
+        if ledger.recent_iterations.is_empty() {
+            return None;
         }
+
+        let average =
+            ledger.recent_iterations.iter().sum::<i64>() / ledger.recent_iterations.len() as i64;
+        let usable = remaining_budget
+            .saturating_sub(Self::to_micro(&min_budget, coral_usd_price).ok()?)
+            .saturating_sub(Self::pending_micro(&ledger, coral_usd_price).ok()?);
+
+        Some((usable.max(0) / average.max(1)) as u64)
     }
 
     ///
     /// Claim for one tool call
     pub(crate) async fn claim_tool_call(&self, tool_name: impl Into<String>) -> Result<(), Error> {
         let name = tool_name.into();
-        if !self.base_tool_call_cost.is_zero() {
-            self.claim(self.base_tool_call_cost.clone()).await?;
-            info!(
-                "claiming {} as a base cost for tool '{name}'",
-                self.base_tool_call_cost
-            );
+        let (base_tool_call_cost, custom_tool_cost) = {
+            let costs = self.costs.read().unwrap();
+            (
+                costs.base_tool_call_cost.clone(),
+                costs.custom_tool_cost.get(name.as_str()).cloned(),
+            )
+        };
+
+        if !base_tool_call_cost.is_zero() {
+            self.claim(base_tool_call_cost.clone()).await?;
+            info!("claiming {base_tool_call_cost} as a base cost for tool '{name}'");
         }
 
-        if let Some(cost) = self.custom_tool_cost.get(name.as_str()) {
+        if let Some(cost) = custom_tool_cost {
             info!("claiming {cost} as an additional cost for tool '{name}'");
 
-            self.claim(cost.clone()).await?;
-            self.claim(cost.clone()).await?;
+            self.claim(cost).await?;
         }
 
         Ok(())
@@ -294,9 +805,12 @@ impl ClaimManager {
     ///
     /// Send a claim to the Coral server
     async fn claim(&self, amount: ClaimAmount) -> Result<(), Error> {
-        // CORAL_SEND_CLAIMS must be '1' to send claims to the server, if this is not set, it
-        // indicates the agent is running in local mode
-        if std::env::var("CORAL_SEND_CLAIMS") != Ok("1".to_string()) {
+        if !Self::send_claims() {
+            return Ok(());
+        }
+
+        if CLAIMS_SUPPRESSED.try_with(|_| ()).is_ok() {
+            info!("not claiming {amount} because claims are suppressed");
             return Ok(());
         }
 
@@ -305,28 +819,63 @@ impl ClaimManager {
             return Ok(());
         }
 
+        let Some(min_claim) = &self.min_claim else {
+            return self.send_claim(amount, true).await;
+        };
+
+        // A USD claim cannot be accumulated until the conversion rate is known
+        let coral_usd_price = self.last_coral_usd_price();
+        let Ok(micro) = Self::to_micro(&amount, coral_usd_price) else {
+            return self.send_claim(amount, true).await;
+        };
+
+        for amount in self.accumulate(min_claim, amount, micro) {
+            self.send_claim(amount, false).await?;
+        }
+
+        Ok(())
+    }
+
+    ///
+    /// The coral price reported by the most recent claim, or zero if no claim has been sent
+    fn last_coral_usd_price(&self) -> f64 {
+        self.ledger
+            .lock()
+            .unwrap()
+            .last_budget
+            .map_or(0.0, |(_, price)| price)
+    }
+
+    ///
+    /// Sends a claim to the Coral server, then checks the remaining budget.  If
+    /// `count_iteration` is false, the claim has already been attributed to an iteration by
+    /// [`ClaimManager::accumulate`].
+    async fn send_claim(&self, amount: ClaimAmount, count_iteration: bool) -> Result<(), Error> {
         let budget = Client::new(self.api_url.as_str())
             .claim_payment(
                 self.remote_session_id.as_str(),
-                &AgentPaymentClaimRequest { amount },
+                &AgentPaymentClaimRequest {
+                    amount: amount.clone(),
+                },
             )
             .await
-            .map_err(Error::ApiError)?
+            .map_err(|e| Error::ApiError(Box::new(e)))?
             .into_inner();
 
-        if self.exit_on_budget_exhausted {
-            // If the ClaimManager's budget was expressed in USD, we need to use the server-provided
-            // conversion rate... Coral server has some warnings about the accuracy of this field,
-            // which shouldn't be ignored.  At this point, we have nothing better to use, and it is
-            // the only way to provide a reasonable result when USD is given.
-            let min_micro = match self.min_budget {
-                AgentClaimAmount::Coral(coral) => (coral * MICRO_CORAL_TO_CORAL) as i64,
-                AgentClaimAmount::MicroCoral(micro) => micro,
-                AgentClaimAmount::Usd(usd) => {
-                    ((usd / budget.coral_usd_price) * MICRO_CORAL_TO_CORAL) as i64
-                }
-            };
+        let claimed = Self::to_micro(&amount, budget.coral_usd_price)?;
+        {
+            let mut ledger = self.ledger.lock().unwrap();
+            if count_iteration {
+                ledger.iteration_claimed = ledger.iteration_claimed.saturating_add(claimed);
+            }
+            ledger.last_budget = Some((budget.remaining_budget, budget.coral_usd_price));
+        }
+
+        self.reconcile(claimed, budget.remaining_budget, budget.coral_usd_price)?;
 
+        if self.exit_on_budget_exhausted {
+            let min_budget = self.costs.read().unwrap().min_budget.clone();
+            let min_micro = Self::to_micro(&min_budget, budget.coral_usd_price)?;
             if budget.remaining_budget <= min_micro {
                 return Err(Error::BudgetExhausted);
             }
@@ -334,4 +883,411 @@ impl ClaimManager {
 
         Ok(())
     }
+
+    ///
+    /// Records a claim in the ledger and compares the expected remaining budget against the
+    /// remaining budget reported by the server
+    fn reconcile(
+        &self,
+        claimed: i64,
+        remaining_budget: i64,
+        coral_usd_price: f64,
+    ) -> Result<(), Error> {
+        let Some(tolerance) = &self.reconciliation_tolerance else {
+            return Ok(());
+        };
+
+        let mut ledger = self.ledger.lock().unwrap();
+        let initial_budget = *ledger
+            .initial_budget
+            .get_or_insert(remaining_budget.saturating_add(claimed));
+        ledger.claimed = ledger.claimed.saturating_add(claimed);
+
+        let expected = initial_budget.saturating_sub(ledger.claimed);
+        let divergence = expected.saturating_sub(remaining_budget).saturating_abs();
+        if divergence > Self::to_micro(tolerance, coral_usd_price)? {
+            if self.strict_reconciliation {
+                return Err(Error::ClaimDivergence(expected, remaining_budget));
+            }
+
+            error!(
+                "claim divergence: expected {expected} micro-coral remaining, server reported {remaining_budget}"
+            );
+        }
+
+        Ok(())
+    }
+
+    ///
+    /// Converts a claim amount to micro-coral.  If the amount was expressed in USD, we need to use
+    /// the server-provided conversion rate... Coral server has some warnings about the accuracy of
+    /// this field, which shouldn't be ignored.  At this point, we have nothing better to use, and it
+    /// is the only way to provide a reasonable result when USD is given.
+    ///
+    /// A USD amount with a conversion rate that is not positive returns
+    /// [`Error::InvalidConversionRate`].  Amounts too large for an i64 saturate at the bounds of
+    /// i64 (float to integer `as` casts saturate) rather than wrapping.
+    fn to_micro(amount: &ClaimAmount, coral_usd_price: f64) -> Result<i64, Error> {
+        Ok(match amount {
+            AgentClaimAmount::Coral(coral) => (coral * MICRO_CORAL_TO_CORAL) as i64,
+            AgentClaimAmount::MicroCoral(micro) => *micro,
+            AgentClaimAmount::Usd(_) if coral_usd_price.is_nan() || coral_usd_price <= 0.0 => {
+                return Err(Error::InvalidConversionRate(coral_usd_price));
+            }
+            AgentClaimAmount::Usd(usd) => ((usd / coral_usd_price) * MICRO_CORAL_TO_CORAL) as i64,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock_completion_model::MockCompletionModel;
+    use crate::test_util::{TestTool, set_env, test_agent, tool_calls};
+    use rig::completion::Message;
+    use tokio::sync::MutexGuard;
+    use wiremock::matchers::{method, path};
+    use wiremock::{Mock, MockServer, Request, Respond, ResponseTemplate};
+
+    const SESSION: &str = "remote-session";
+
+    ///
+    /// Responds to claims like the Coral server: each claim is subtracted from a budget, and the
+    /// remaining budget is returned
+    struct MockBudget {
+        remaining: Mutex<i64>,
+        coral_usd_price: f64,
+    }
+
+    impl Respond for MockBudget {
+        fn respond(&self, request: &Request) -> ResponseTemplate {
+            let claim: AgentPaymentClaimRequest = serde_json::from_slice(&request.body).unwrap();
+            let claimed = ClaimManager::to_micro(&claim.amount, self.coral_usd_price).unwrap();
+
+            let mut remaining = self.remaining.lock().unwrap();
+            *remaining -= claimed;
+            ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "remainingBudget": *remaining,
+                "coralUsdPrice": self.coral_usd_price,
+            }))
+        }
+    }
+
+    ///
+    /// A mock Coral server with the given budget in micro-coral, and a claim manager that sends
+    /// claims to it.  The returned guard keeps `CORAL_SEND_CLAIMS` set.
+    async fn mock_server(
+        budget: i64,
+        coral_usd_price: f64,
+    ) -> (MockServer, ClaimManager, MutexGuard<'static, ()>) {
+        let env = set_env(&[("CORAL_SEND_CLAIMS", Some("1"))]).await;
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path(format!("/api/v1/internal/claim/{SESSION}")))
+            .respond_with(MockBudget {
+                remaining: Mutex::new(budget),
+                coral_usd_price,
+            })
+            .mount(&server)
+            .await;
+
+        let claim_manager = ClaimManager::with_api(server.uri(), SESSION);
+        (server, claim_manager, env)
+    }
+
+    ///
+    /// Every claim received by the mock server, in micro-coral
+    async fn claims(server: &MockServer, coral_usd_price: f64) -> Vec<i64> {
+        server
+            .received_requests()
+            .await
+            .unwrap()
+            .iter()
+            .map(|request| {
+                let claim: AgentPaymentClaimRequest =
+                    serde_json::from_slice(&request.body).unwrap();
+                ClaimManager::to_micro(&claim.amount, coral_usd_price).unwrap()
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn claims_token_costs() {
+        let (server, claim_manager, _env) = mock_server(1_000_000, 1.0).await;
+        let claim_manager = claim_manager
+            .input_token_cost(ClaimAmount::MicroCoral(2))
+            .output_token_cost(ClaimAmount::MicroCoral(3));
+
+        let usage = Usage {
+            input_tokens: 10,
+            output_tokens: 5,
+            total_tokens: 15,
+        };
+        claim_manager.claim_tokens(&usage).await.unwrap();
+
+        assert_eq!(claims(&server, 1.0).await.iter().sum::<i64>(), 35);
+        assert_eq!(claim_manager.session_tokens(), 15);
+        assert_eq!(claim_manager.last_known_budget(), Some(1_000_000 - 35));
+    }
+
+    #[tokio::test]
+    async fn claims_tool_costs() {
+        let (server, claim_manager, _env) = mock_server(1_000_000, 1.0).await;
+        let claim_manager = claim_manager
+            .base_tool_call_cost(ClaimAmount::MicroCoral(100))
+            .custom_tool_cost("search", ClaimAmount::MicroCoral(50));
+
+        claim_manager.claim_tool_call("search").await.unwrap();
+        claim_manager.claim_tool_call("other").await.unwrap();
+
+        assert_eq!(claims(&server, 1.0).await, vec![100, 50, 100]);
+    }
+
+    #[tokio::test]
+    async fn custom_tool_cost_is_claimed_once_per_call() {
+        let (server, claim_manager, _env) = mock_server(1_000_000, 1.0).await;
+        let claim_manager = claim_manager.custom_tool_cost("search", ClaimAmount::MicroCoral(50));
+
+        let model = MockCompletionModel::default();
+        model.push_response(tool_calls(&["search", "other"]), Usage::new());
+
+        let search = TestTool::new("search");
+        let mut agent = test_agent(model, [search.clone(), TestTool::new("other")])
+            .claim_manager(claim_manager);
+        agent
+            .run_completion(vec![Message::user("go")])
+            .await
+            .unwrap();
+
+        assert_eq!(search.calls(), 1);
+        assert_eq!(claims(&server, 1.0).await, vec![50]);
+    }
+
+    #[tokio::test]
+    async fn budget_exhaustion_stops_remaining_tool_calls() {
+        let (server, claim_manager, _env) = mock_server(150, 1.0).await;
+        let claim_manager = claim_manager.base_tool_call_cost(ClaimAmount::MicroCoral(100));
+
+        let model = MockCompletionModel::default();
+        model.push_response(tool_calls(&["a", "b", "c"]), Usage::new());
+
+        let tools = [TestTool::new("a"), TestTool::new("b"), TestTool::new("c")];
+        let mut agent = test_agent(model, tools.clone()).claim_manager(claim_manager);
+        let res = agent.run_completion(vec![Message::user("go")]).await;
+
+        assert!(matches!(res, Err(Error::BudgetExhausted)));
+        assert_eq!(tools.map(|tool| tool.calls()), [1, 1, 0]);
+        assert_eq!(claims(&server, 1.0).await, vec![100, 100]);
+    }
+
+    #[tokio::test]
+    async fn claims_iteration_costs() {
+        let (server, claim_manager, _env) = mock_server(1_000_000, 1.0).await;
+        let claim_manager = claim_manager
+            .base_iteration_cost(ClaimAmount::MicroCoral(7))
+            .base_tool_iteration_cost(ClaimAmount::MicroCoral(11));
+
+        claim_manager.claim_tool_iteration().await.unwrap();
+        claim_manager.claim_iteration().await.unwrap();
+
+        assert_eq!(claims(&server, 1.0).await, vec![11, 7]);
+        assert_eq!(
+            claim_manager.estimated_remaining_iterations(),
+            Some((1_000_000 - 18) / 9)
+        );
+    }
+
+    #[tokio::test]
+    async fn budget_exhaustion() {
+        let (server, claim_manager, _env) = mock_server(250, 1.0).await;
+        let claim_manager = claim_manager
+            .base_iteration_cost(ClaimAmount::MicroCoral(100))
+            .min_budget(ClaimAmount::MicroCoral(50));
+
+        claim_manager.claim_iteration().await.unwrap();
+        assert!(matches!(
+            claim_manager.claim_iteration().await,
+            Err(Error::BudgetExhausted)
+        ));
+        assert_eq!(claims(&server, 1.0).await.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn converts_usd_claims() {
+        let (server, claim_manager, _env) = mock_server(10_000_000, 2.0).await;
+        let claim_manager = claim_manager.base_iteration_cost(ClaimAmount::Usd(1.0));
+
+        claim_manager.claim_iteration().await.unwrap();
+
+        // 1 USD at 2 USD per coral is half a coral
+        assert_eq!(claims(&server, 2.0).await, vec![500_000]);
+        assert_eq!(claim_manager.last_known_budget(), Some(9_500_000));
+    }
+
+    #[tokio::test]
+    async fn min_claim_accumulates_small_claims() {
+        let (server, claim_manager, _env) = mock_server(1_000_000, 1.0).await;
+        let claim_manager = claim_manager
+            .base_iteration_cost(ClaimAmount::MicroCoral(30))
+            .min_claim(ClaimAmount::MicroCoral(100));
+
+        for _ in 0..3 {
+            claim_manager.claim_iteration().await.unwrap();
+        }
+        assert!(claims(&server, 1.0).await.is_empty());
+
+        claim_manager.claim_iteration().await.unwrap();
+        claim_manager.claim_iteration().await.unwrap();
+        assert_eq!(claims(&server, 1.0).await, vec![120]);
+
+        claim_manager.flush_claims().await.unwrap();
+        assert_eq!(claims(&server, 1.0).await, vec![120, 30]);
+    }
+
+    #[tokio::test]
+    async fn min_claim_sends_claims_that_would_exhaust_the_budget() {
+        let (server, claim_manager, _env) = mock_server(250, 1.0).await;
+        let claim_manager = claim_manager
+            .base_iteration_cost(ClaimAmount::MicroCoral(100))
+            .min_claim(ClaimAmount::MicroCoral(500));
+
+        claim_manager.remaining_budget().await.unwrap();
+        claim_manager.claim_iteration().await.unwrap();
+        claim_manager.claim_iteration().await.unwrap();
+        assert!(matches!(
+            claim_manager.claim_iteration().await,
+            Err(Error::BudgetExhausted)
+        ));
+        assert_eq!(claims(&server, 1.0).await, vec![0, 300]);
+    }
+
+    #[tokio::test]
+    async fn min_claim_pending_amounts_reduce_remaining_iterations() {
+        let (_server, claim_manager, _env) = mock_server(10_000, 1.0).await;
+        let claim_manager = claim_manager
+            .base_iteration_cost(ClaimAmount::MicroCoral(100))
+            .min_claim(ClaimAmount::MicroCoral(1_000));
+
+        claim_manager.remaining_budget().await.unwrap();
+        claim_manager.claim_iteration().await.unwrap();
+        claim_manager.claim_iteration().await.unwrap();
+
+        assert_eq!(claim_manager.estimated_remaining_iterations(), Some(98));
+    }
+
+    #[tokio::test]
+    async fn nothing_is_sent_in_local_mode() {
+        let _env = set_env(&[("CORAL_SEND_CLAIMS", None)]).await;
+        let server = MockServer::start().await;
+        let claim_manager = ClaimManager::with_api(server.uri(), SESSION)
+            .base_iteration_cost(ClaimAmount::MicroCoral(100));
+
+        claim_manager.claim_iteration().await.unwrap();
+        assert!(server.received_requests().await.unwrap().is_empty());
+    }
+
+    #[test]
+    fn to_micro_rejects_prices_that_are_not_positive() {
+        for price in [0.0, -1.0, f64::NAN] {
+            assert!(matches!(
+                ClaimManager::to_micro(&ClaimAmount::Usd(1.0), price),
+                Err(Error::InvalidConversionRate(_))
+            ));
+        }
+
+        assert_eq!(
+            ClaimManager::to_micro(&ClaimAmount::Coral(1.0), 0.0).unwrap(),
+            1_000_000
+        );
+        assert_eq!(
+            ClaimManager::to_micro(&ClaimAmount::MicroCoral(5), 0.0).unwrap(),
+            5
+        );
+    }
+
+    #[test]
+    fn to_micro_saturates_huge_amounts() {
+        assert_eq!(
+            ClaimManager::to_micro(&ClaimAmount::Usd(1e300), 1e-9).unwrap(),
+            i64::MAX
+        );
+        assert_eq!(
+            ClaimManager::to_micro(&ClaimAmount::Coral(1e300), 1.0).unwrap(),
+            i64::MAX
+        );
+        assert_eq!(
+            ClaimManager::to_micro(&ClaimAmount::Coral(-1e300), 1.0).unwrap(),
+            i64::MIN
+        );
+    }
+
+    #[tokio::test]
+    async fn zero_price_with_usd_min_budget_is_an_error() {
+        let (_server, claim_manager, _env) = mock_server(1_000_000, 0.0).await;
+        let claim_manager = claim_manager
+            .base_iteration_cost(ClaimAmount::MicroCoral(100))
+            .min_budget(ClaimAmount::Usd(1.0));
+
+        assert!(matches!(
+            claim_manager.claim_iteration().await,
+            Err(Error::InvalidConversionRate(price)) if price == 0.0
+        ));
+    }
+
+    #[tokio::test]
+    async fn huge_usd_min_budget_exhausts_the_budget() {
+        let (server, claim_manager, _env) = mock_server(1_000_000, 1.0).await;
+        let claim_manager = claim_manager
+            .base_iteration_cost(ClaimAmount::MicroCoral(100))
+            .min_budget(ClaimAmount::Usd(1e300));
+
+        assert!(matches!(
+            claim_manager.claim_iteration().await,
+            Err(Error::BudgetExhausted)
+        ));
+        assert_eq!(claims(&server, 1.0).await, vec![100]);
+    }
+
+    #[tokio::test]
+    async fn try_new_without_env_in_local_mode() {
+        let _env = set_env(&[
+            ("CORAL_SEND_CLAIMS", None),
+            ("CORAL_API_URL", None),
+            ("CORAL_SESSION_ID", None),
+        ])
+        .await;
+
+        let claim_manager = ClaimManager::try_new().unwrap();
+        assert!(!ClaimManager::send_claims());
+        claim_manager.claim_iteration().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn try_new_requires_env_when_sending_claims() {
+        let env = set_env(&[
+            ("CORAL_SEND_CLAIMS", Some("1")),
+            ("CORAL_API_URL", None),
+            ("CORAL_SESSION_ID", Some(SESSION)),
+        ])
+        .await;
+        assert!(
+            matches!(ClaimManager::try_new(), Err(Error::MissingEnv(name)) if name == "CORAL_API_URL")
+        );
+        drop(env);
+
+        let env = set_env(&[
+            ("CORAL_API_URL", Some("http://localhost:5555")),
+            ("CORAL_SESSION_ID", None),
+        ])
+        .await;
+        assert!(
+            matches!(ClaimManager::try_new(), Err(Error::MissingEnv(name)) if name == "CORAL_SESSION_ID")
+        );
+        drop(env);
+
+        let _env = set_env(&[("CORAL_SESSION_ID", Some(SESSION))]).await;
+        let claim_manager = ClaimManager::try_new().unwrap();
+        assert_eq!(claim_manager.api_url, "http://localhost:5555");
+        assert_eq!(claim_manager.remote_session_id, SESSION);
+    }
 }
@@ -1,7 +1,27 @@
 use crate::api::generated::types::McpResources;
 use crate::error::Error;
 use crate::mcp_server::McpServerConnection;
-use rmcp::model::ResourceContents;
+use crate::resource_tool::ReadResourceTool;
+use futures::future::try_join_all;
+use rig::tool::Tool;
+use rmcp::model::{Resource, ResourceContents};
+use std::collections::HashMap;
+use std::path::Path;
+use tracing::warn;
+
+///
+/// Appended to the end of any resource part that was truncated by
+/// [`CompletionEvaluatedPrompt::evaluate_with_budget`]
+pub const TRUNCATION_MARKER: &str = "\n[truncated]";
+
+///
+/// The default separator between resources in a single resource part.  See
+/// [`CompletionEvaluatedPrompt::resource_separator`].
+pub const DEFAULT_RESOURCE_SEPARATOR: &str = "\n";
+
+///
+/// The default separator between parts.  See [`CompletionEvaluatedPrompt::separator`].
+pub const DEFAULT_SEPARATOR: &str = "\n";
 
 ///
 /// A CompletionEvaluatedPrompt is made up of many [`PromptPart`] parts that will be evaluated by
@@ -11,13 +31,33 @@ use rmcp::model::ResourceContents;
 /// in Coral specifically this is useful because Coral offers resources that are "live" and can
 /// impact the completion greatly.
 ///
-/// A newline will separate all parts in a CompletionEvaluatedPrompt when evaluated.
+/// A separator (a newline by default, see [`CompletionEvaluatedPrompt::separator`]) will separate
+/// all parts in a CompletionEvaluatedPrompt when evaluated.
 ///
 /// A CompletionEvaluatedPrompt can be evaluated many times, each time creating a new string, using
 /// the [`CompletionEvaluatedPrompt::evaluate`] function.
 #[derive(Clone)]
 pub struct CompletionEvaluatedPrompt {
     pub parts: Vec<PromptPart>,
+    separator: String,
+    resource_separator: String,
+    resource_headers: bool,
+}
+
+///
+/// Where resources are placed relative to the instruction in prompts built by the resource-prompt
+/// helpers on [`McpServerConnection`].  Some models follow instructions better when large context
+/// comes first and the instruction last, others the reverse.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ResourceOrder {
+    ///
+    /// The instruction comes first, followed by the resources
+    #[default]
+    InstructionFirst,
+
+    ///
+    /// The resources come first, followed by the instruction
+    ResourcesFirst,
 }
 
 #[derive(Clone)]
@@ -39,19 +79,127 @@ pub enum PromptPart {
     ///
     /// All resources on a specific MCP server
     AllResources(McpServerConnection),
+
+    ///
+    /// A list of the resources on a specific MCP server, without their contents
+    ResourceIndex(McpServerConnection),
+}
+
+impl Default for CompletionEvaluatedPrompt {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl CompletionEvaluatedPrompt {
     pub fn new() -> Self {
-        Self { parts: Vec::new() }
+        Self {
+            parts: Vec::new(),
+            separator: DEFAULT_SEPARATOR.to_string(),
+            resource_separator: DEFAULT_RESOURCE_SEPARATOR.to_string(),
+            resource_headers: false,
+        }
     }
 
     ///
     /// Creates a new prompt starting with a single [`PromptPart::String`] part.
     pub fn from_string(string: impl Into<String>) -> Self {
-        Self {
-            parts: vec![PromptPart::String(string.into())],
+        Self::new().string(string)
+    }
+
+    ///
+    /// Creates a new prompt from a template, resolving resources against an MCP connection.  The
+    /// template is plain text that can contain the following placeholders:
+    /// - `{{resource:URI}}`, replaced with the resource with the given URI (see [`Self::resource`])
+    /// - `{{all_resources}}`, replaced with every resource (see [`Self::all_resources`])
+    /// - `{{resource_index}}`, replaced with a list of every resource (see
+    ///   [`Self::resource_index`])
+    ///
+    /// Any other text, including unrecognised placeholders, is kept as is.  Resources are read when
+    /// the prompt is evaluated, not when this function is called.  The template controls all
+    /// whitespace, so the part separator is set to an empty string.
+    pub fn from_template(template: &str, mcp_server_connection: McpServerConnection) -> Self {
+        let mut prompt = Self::new().separator("");
+        let mut rest = template;
+        let mut text = String::new();
+        while let Some(start) = rest.find("{{") {
+            let Some(end) = rest[start..].find("}}").map(|end| start + end) else {
+                break;
+            };
+
+            text.push_str(&rest[..start]);
+            let placeholder = rest[start + 2..end].trim();
+            let part = match placeholder.split_once(':') {
+                Some(("resource", uri)) if !uri.trim().is_empty() => {
+                    Some(PromptPart::Resource(ResourceData {
+                        mcp_server_connection: mcp_server_connection.clone(),
+                        resource_uri: uri.trim().to_string(),
+                    }))
+                }
+                _ if placeholder == "all_resources" => {
+                    Some(PromptPart::AllResources(mcp_server_connection.clone()))
+                }
+                _ if placeholder == "resource_index" => {
+                    Some(PromptPart::ResourceIndex(mcp_server_connection.clone()))
+                }
+                _ => None,
+            };
+
+            match part {
+                Some(part) => {
+                    if !text.is_empty() {
+                        prompt
+                            .parts
+                            .push(PromptPart::String(std::mem::take(&mut text)));
+                    }
+
+                    prompt.parts.push(part);
+                }
+                None => text.push_str(&rest[start..end + 2]),
+            }
+
+            rest = &rest[end + 2..];
         }
+
+        text.push_str(rest);
+        if !text.is_empty() {
+            prompt.parts.push(PromptPart::String(text));
+        }
+
+        prompt
+    }
+
+    ///
+    /// Creates a new prompt from a template file.  See [`Self::from_template`] for the template
+    /// syntax.
+    pub fn from_template_file(
+        path: impl AsRef<Path>,
+        mcp_server_connection: McpServerConnection,
+    ) -> Result<Self, Error> {
+        let template = std::fs::read_to_string(path).map_err(Error::TemplateIoError)?;
+        Ok(Self::from_template(&template, mcp_server_connection))
+    }
+
+    ///
+    /// Sets the separator placed between parts when this prompt is evaluated, e.g. `"\n\n"` to
+    /// leave a blank line between sections.  No separator is placed after the final part.
+    ///
+    /// Default is [`DEFAULT_SEPARATOR`].
+    pub fn separator(mut self, separator: impl Into<String>) -> Self {
+        self.separator = separator.into();
+        self
+    }
+
+    ///
+    /// Sets the separator placed between resources when a single part evaluates to more than one
+    /// resource (e.g. [`PromptPart::AllResources`]).  A distinct separator, such as a heading or a
+    /// horizontal rule, makes the boundaries between documents clear to the model.  This does not
+    /// change the separator placed between parts, see [`CompletionEvaluatedPrompt::separator`].
+    ///
+    /// Default is [`DEFAULT_RESOURCE_SEPARATOR`].
+    pub fn resource_separator(mut self, resource_separator: impl Into<String>) -> Self {
+        self.resource_separator = resource_separator.into();
+        self
     }
 
     ///
@@ -61,6 +209,16 @@ impl CompletionEvaluatedPrompt {
         self
     }
 
+    ///
+    /// If set to true, the content of every resource is prefixed with a heading containing its
+    /// URI, e.g. `## resource://coral/instructions`.  This gives the model provenance for each
+    /// chunk of the prompt, which helps it cite and reason about specific resources.  Default is
+    /// false.
+    pub fn resource_headers(mut self, resource_headers: bool) -> Self {
+        self.resource_headers = resource_headers;
+        self
+    }
+
     ///
     /// Adds a single URI-referenced resource from an MCP server as a part of this dynamic prompt.
     pub fn resource(
@@ -96,19 +254,58 @@ impl CompletionEvaluatedPrompt {
     }
 
     ///
-    /// Helper function to convert a list of resource contents into a newline-separated string
-    fn resource_contents_to_string(resource_contents: Vec<ResourceContents>) -> String {
+    /// Adds a part listing the URI, name and description of every resource on an MCP connection,
+    /// without their contents.  This is an alternative to [`Self::all_resources`] for servers with
+    /// many resources, where the model only needs a few: instead of every resource being included
+    /// in the prompt, the model reads the resources it needs with a [`ReadResourceTool`] (see
+    /// [`crate::agent::Agent::lazy_resources`]).
+    ///
+    /// Like [`Self::all_resources`], the list of resources is calculated when
+    /// [`CompletionEvaluatedPrompt::evaluate`] is called.
+    pub fn resource_index(mut self, mcp_server_connection: McpServerConnection) -> Self {
+        self.parts
+            .push(PromptPart::ResourceIndex(mcp_server_connection));
+        self
+    }
+
+    ///
+    /// Helper function to list resources, one per line, for a [`PromptPart::ResourceIndex`] part
+    fn resource_index_to_string(resources: &[Resource]) -> String {
+        let mut index = format!(
+            "The following resources can be read with the {} tool:",
+            ReadResourceTool::NAME
+        );
+
+        for resource in resources {
+            index.push_str(&format!("\n- {} ({})", resource.uri, resource.name));
+            if let Some(description) = &resource.description {
+                index.push_str(&format!(": {description}"));
+            }
+        }
+
+        index
+    }
+
+    ///
+    /// Helper function to convert a list of resource contents into a string, separated by the
+    /// resource separator
+    fn resource_contents_to_string(&self, resource_contents: Vec<ResourceContents>) -> String {
         resource_contents
             .iter()
             .map(|x| {
-                match x {
-                    ResourceContents::TextResourceContents { text, .. } => text,
-                    ResourceContents::BlobResourceContents { blob, .. } => blob,
+                let (uri, content) = match x {
+                    ResourceContents::TextResourceContents { uri, text, .. } => (uri, text),
+                    ResourceContents::BlobResourceContents { uri, blob, .. } => (uri, blob),
+                };
+
+                if self.resource_headers {
+                    format!("## {uri}\n{content}")
+                } else {
+                    content.clone()
                 }
-                .clone()
             })
             .collect::<Vec<_>>()
-            .join("\n")
+            .join(&self.resource_separator)
     }
 
     ///
@@ -118,30 +315,206 @@ impl CompletionEvaluatedPrompt {
     /// to get fetched here.  The potential for resources is also the reason that this function is
     /// async.
     ///
-    /// A newline character will separate all parts in this prompt when evaluated.
+    /// The separator (see [`Self::separator`]) will separate all parts in this prompt when
+    /// evaluated.  No separator follows the final part.
     pub async fn evaluate(&self) -> Result<String, Error> {
-        let mut buffer = String::new();
-        for part in &self.parts {
-            buffer.push_str(
-                match part {
-                    PromptPart::String(string) => string.clone(),
-                    PromptPart::Resource(resource_data) => Self::resource_contents_to_string(
-                        resource_data
-                            .mcp_server_connection
-                            .read_resource(&resource_data.resource_uri)
-                            .await?,
-                    ),
-                    PromptPart::AllResources(mcp_server_connection) => {
-                        Self::resource_contents_to_string(
-                            mcp_server_connection.get_resources().await?,
-                        )
-                    }
-                }
-                .as_str(),
+        Ok(self.join_parts(self.evaluate_parts().await?))
+    }
+
+    ///
+    /// Evaluates this prompt in the same way as [`Self::evaluate`], but if the result would be
+    /// larger than `max_bytes`, resource parts are truncated until it fits.  Resource parts are
+    /// considered less important than string parts, and later parts less important than earlier
+    /// ones, so resource parts are truncated starting from the last one.  Every truncated part ends
+    /// with [`TRUNCATION_MARKER`].
+    ///
+    /// String parts are never truncated, so the result can still be larger than `max_bytes` if the
+    /// string parts alone are.
+    pub async fn evaluate_with_budget(&self, max_bytes: usize) -> Result<String, Error> {
+        let mut evaluated_parts = self.evaluate_parts().await?;
+        let mut size: usize = evaluated_parts.iter().map(|x| x.len()).sum::<usize>()
+            + self.separator.len() * evaluated_parts.len().saturating_sub(1);
+
+        for (part, evaluated) in self.parts.iter().zip(evaluated_parts.iter_mut()).rev() {
+            if size <= max_bytes {
+                break;
+            }
+
+            if matches!(part, PromptPart::String(_)) {
+                continue;
+            }
+
+            let mut keep = evaluated
+                .len()
+                .saturating_sub(size - max_bytes + TRUNCATION_MARKER.len());
+            while !evaluated.is_char_boundary(keep) {
+                keep -= 1;
+            }
+
+            let mut truncated = evaluated[..keep].to_string();
+            truncated.push_str(TRUNCATION_MARKER);
+            if truncated.len() < evaluated.len() {
+                size = size - evaluated.len() + truncated.len();
+                *evaluated = truncated;
+            }
+        }
+
+        if size > max_bytes {
+            warn!(
+                "evaluated prompt is {size} bytes after truncating resources, which is over the budget of {max_bytes} bytes"
             );
-            buffer.push('\n');
         }
 
-        Ok(buffer)
+        Ok(self.join_parts(evaluated_parts))
+    }
+
+    ///
+    /// Evaluates every part into a string, in order
+    async fn evaluate_parts(&self) -> Result<Vec<String>, Error> {
+        let listed_resources = try_join_all(self.parts.iter().map(|part| async move {
+            match part {
+                PromptPart::AllResources(connection) | PromptPart::ResourceIndex(connection) => {
+                    connection.list_resources().await.map(Some)
+                }
+                _ => Ok(None),
+            }
+        }))
+        .await?;
+
+        // The resources read by each part, in order.  String and index parts read nothing
+        let part_reads = self
+            .parts
+            .iter()
+            .zip(&listed_resources)
+            .map(|(part, resources)| match part {
+                PromptPart::String(_) | PromptPart::ResourceIndex(_) => Vec::new(),
+                PromptPart::Resource(resource_data) => vec![(
+                    &resource_data.mcp_server_connection,
+                    resource_data.resource_uri.clone(),
+                )],
+                PromptPart::AllResources(connection) => resources
+                    .iter()
+                    .flatten()
+                    .map(|resource| (connection, resource.uri.clone()))
+                    .collect(),
+            })
+            .collect::<Vec<_>>();
+
+        // Coral resources are live and can be expensive to read, so every resource is read
+        // concurrently, and at most once per evaluation even if more than one part refers to it
+        let mut unique_reads = HashMap::new();
+        for (connection, uri) in part_reads.iter().flatten() {
+            unique_reads
+                .entry((connection.identifier.as_str(), uri.as_str()))
+                .or_insert(*connection);
+        }
+
+        let contents = try_join_all(
+            unique_reads
+                .iter()
+                .map(|((_, uri), connection)| connection.read_resource(*uri)),
+        )
+        .await?;
+
+        let contents = unique_reads
+            .into_keys()
+            .zip(contents)
+            .collect::<HashMap<_, _>>();
+
+        Ok(self
+            .parts
+            .iter()
+            .zip(part_reads.iter().zip(&listed_resources))
+            .map(|(part, (reads, resources))| match part {
+                PromptPart::String(string) => string.clone(),
+                PromptPart::ResourceIndex(_) => {
+                    Self::resource_index_to_string(resources.as_deref().unwrap_or_default())
+                }
+                _ => self.resource_contents_to_string(
+                    reads
+                        .iter()
+                        .flat_map(|(connection, uri)| {
+                            contents[&(connection.identifier.as_str(), uri.as_str())].clone()
+                        })
+                        .collect(),
+                ),
+            })
+            .collect())
+    }
+
+    ///
+    /// Joins evaluated parts with the separator
+    fn join_parts(&self, evaluated_parts: Vec<String>) -> String {
+        evaluated_parts.join(&self.separator)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::FakeMcpServer;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn default_separator_is_a_newline_between_parts() {
+        let prompt = CompletionEvaluatedPrompt::new()
+            .string("first")
+            .string("second")
+            .string("third");
+
+        assert_eq!(prompt.evaluate().await.unwrap(), "first\nsecond\nthird");
+    }
+
+    #[tokio::test]
+    async fn custom_separator_is_placed_between_parts() {
+        let prompt = CompletionEvaluatedPrompt::new()
+            .separator("\n\n---\n\n")
+            .string("first")
+            .string("second");
+
+        assert_eq!(prompt.evaluate().await.unwrap(), "first\n\n---\n\nsecond");
+        assert_eq!(
+            prompt.evaluate_with_budget(usize::MAX).await.unwrap(),
+            "first\n\n---\n\nsecond"
+        );
+    }
+
+    #[tokio::test]
+    async fn no_separator_follows_the_final_part() {
+        let prompt = CompletionEvaluatedPrompt::new().string("only");
+        assert_eq!(prompt.evaluate().await.unwrap(), "only");
+
+        let prompt = CompletionEvaluatedPrompt::new()
+            .separator("")
+            .string("a")
+            .string("b");
+        assert_eq!(prompt.evaluate().await.unwrap(), "ab");
+
+        assert_eq!(
+            CompletionEvaluatedPrompt::new().evaluate().await.unwrap(),
+            ""
+        );
+    }
+
+    #[tokio::test]
+    async fn resources_are_read_concurrently_and_once() {
+        let server = FakeMcpServer::new("server")
+            .resource("test://a", "a")
+            .resource("test://b", "b")
+            .resource("test://c", "c")
+            .read_delay(Duration::from_millis(50));
+        let connection = server.connect().await;
+
+        let prompt = CompletionEvaluatedPrompt::new()
+            .resource(connection.clone(), "test://a")
+            .string("all")
+            .all_resources(connection);
+
+        assert_eq!(prompt.evaluate().await.unwrap(), "a\nall\na\nb\nc");
+        assert_eq!(
+            server.max_concurrent_reads(),
+            3,
+            "every unique resource is read at the same time"
+        );
     }
 }
@@ -4,34 +4,173 @@ use rig::tool::ToolSetError;
 use rmcp::ServiceError;
 use rmcp::service::ClientInitializeError;
 use rmcp::transport::sse_client::SseTransportError;
+use serde::Serialize;
 use thiserror::Error;
 
 #[derive(Debug, Error)]
 pub enum Error {
     #[error("mcp error: {0}")]
-    McpClientError(ClientInitializeError),
+    McpClientError(Box<ClientInitializeError>),
 
     #[error("mcp error: {0}")]
     McpSseError(SseTransportError<reqwest::Error>),
 
+    #[error("invalid mcp header: {0}")]
+    InvalidMcpHeader(String),
+
+    #[error("mcp error: could not reach {0}: {1}")]
+    McpUnreachable(String, Box<ClientInitializeError>),
+
     #[error("mcp error: {0}")]
     McpStdioError(std::io::Error),
 
     #[error("mcp error: {0}")]
     McpServiceError(ServiceError),
 
+    #[error("mcp error: {0}")]
+    McpCloseError(tokio::task::JoinError),
+
+    #[error("mcp tool error: {0}")]
+    McpToolError(String),
+
     #[error("completion error: {0}")]
-    PromptError(rig::completion::PromptError),
+    PromptError(Box<rig::completion::PromptError>),
 
     #[error("completion error: {0}")]
-    CompletionError(rig::completion::CompletionError),
+    CompletionError(Box<rig::completion::CompletionError>),
 
     #[error("tool error: {0}")]
     ToolsetError(ToolSetError),
 
+    #[error("tool error: \"{tool}\" timed out")]
+    ToolTimeout { tool: String },
+
+    #[error("tool quota of {0} tool iterations reached")]
+    ToolQuotaReached(u32),
+
     #[error("budget exhausted")]
     BudgetExhausted,
 
+    #[error("prompt evaluated to an empty string")]
+    EmptyPrompt,
+
+    #[error("session token limit reached")]
+    TokenLimitReached,
+
+    #[error("claim costs are configured but CORAL_SEND_CLAIMS is not set to 1")]
+    ClaimsNotSent,
+
+    #[error("budget unavailable: CORAL_SEND_CLAIMS is not set to 1")]
+    BudgetUnavailable,
+
+    #[error("invalid coral usd price: {0}")]
+    InvalidConversionRate(f64),
+
+    #[error("claim divergence: expected {0} micro-coral remaining, server reported {1}")]
+    ClaimDivergence(i64, i64),
+
+    #[error("coral tool error: {0}")]
+    CoralToolError(String),
+
+    #[error("failed to parse coral tool result: {0}")]
+    CoralToolResultError(serde_json::Error),
+
+    #[error("invalid agent option: {0}")]
+    InvalidOption(String),
+
+    #[error("invalid agent definition: {0}")]
+    AgentDefinitionError(String),
+
+    #[error("environment variable {0} is not set")]
+    MissingEnv(String),
+
+    #[error("invalid session: {}", .0.join("; "))]
+    InvalidSession(Vec<String>),
+
+    #[error("session recording error: {0}")]
+    RecordingIoError(std::io::Error),
+
+    #[error("session recording error: {0}")]
+    RecordingFormatError(serde_json::Error),
+
+    #[error("session replay mismatch: {0}")]
+    ReplayMismatch(String),
+
+    #[error("training data error: {0}")]
+    TrainingDataIoError(std::io::Error),
+
+    #[error("failed to read prompt template: {0}")]
+    TemplateIoError(std::io::Error),
+
+    #[error("failed to parse structured output: {0}")]
+    ExtractError(serde_json::Error),
+
     #[error("api error {0}")]
-    ApiError(ProgenitorError<RouteException>),
+    ApiError(Box<ProgenitorError<RouteException>>),
+
+    #[error("registry error {0}")]
+    RegistryError(Box<ProgenitorError<()>>),
+}
+
+impl Error {
+    ///
+    /// A short, stable name for the kind of error, e.g. `"mcp"` or `"budget"`.  Used to report
+    /// why an agent stopped, see [`AgentError`].
+    pub fn category(&self) -> &'static str {
+        match self {
+            Error::McpClientError(_)
+            | Error::McpSseError(_)
+            | Error::InvalidMcpHeader(_)
+            | Error::McpUnreachable(..)
+            | Error::McpStdioError(_)
+            | Error::McpServiceError(_)
+            | Error::McpCloseError(_)
+            | Error::McpToolError(_) => "mcp",
+            Error::PromptError(_) | Error::CompletionError(_) | Error::ExtractError(_) => {
+                "completion"
+            }
+            Error::ToolsetError(_) | Error::ToolTimeout { .. } | Error::ToolQuotaReached(_) => {
+                "tool"
+            }
+            Error::BudgetExhausted | Error::TokenLimitReached => "budget",
+            Error::ClaimsNotSent
+            | Error::BudgetUnavailable
+            | Error::InvalidConversionRate(_)
+            | Error::ClaimDivergence(..) => "claim",
+            Error::CoralToolError(_) | Error::CoralToolResultError(_) => "coral",
+            Error::EmptyPrompt => "prompt",
+            Error::InvalidOption(_)
+            | Error::AgentDefinitionError(_)
+            | Error::InvalidSession(_)
+            | Error::MissingEnv(_) => "configuration",
+            Error::RecordingIoError(_)
+            | Error::RecordingFormatError(_)
+            | Error::TrainingDataIoError(_)
+            | Error::TemplateIoError(_) => "io",
+            Error::ReplayMismatch(_) => "replay",
+            Error::ApiError(_) | Error::RegistryError(_) => "api",
+        }
+    }
+}
+
+///
+/// A structured description of the error that terminated an agent, logged before the agent
+/// exits.  See [`crate::agent_loop::AgentLoop::report_fatal_error`].
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AgentError {
+    /// See [`Error::category`]
+    pub category: &'static str,
+
+    /// The error message
+    pub message: String,
+}
+
+impl From<&Error> for AgentError {
+    fn from(error: &Error) -> Self {
+        Self {
+            category: error.category(),
+            message: error.to_string(),
+        }
+    }
 }
@@ -1,27 +1,59 @@
 pub mod agent;
 pub mod agent_loop;
+pub mod agent_options;
 pub mod api;
+pub mod channel_prompt_stream;
 pub mod claim_manager;
 pub mod completion_evaluated_prompt;
 pub mod error;
 pub mod mcp_server;
+pub mod mention_prompt_stream;
+pub mod mock_completion_model;
 pub mod repeating_prompt_stream;
+pub mod resource_tool;
+pub mod retry;
+pub mod session;
+pub mod session_recorder;
 pub mod telemetry;
+#[cfg(test)]
+mod test_util;
+pub mod training_data;
 
 pub use rig;
 pub use rmcp;
 pub use serde;
 use std::io;
-use tracing::Level;
+use tracing::{Level, Subscriber};
+use tracing_subscriber::EnvFilter;
+use tracing_subscriber::fmt::MakeWriter;
 use tracing_subscriber::fmt::writer::MakeWriterExt;
 use tracing_subscriber::layer::SubscriberExt;
 use tracing_subscriber::util::{SubscriberInitExt, TryInitError};
 
+///
+/// Expands to an [`rmcp::model::Implementation`] containing the name and version of the crate this
+/// macro is used in, taken from its Cargo metadata.  This identifies an agent by its own name and
+/// version, see [`mcp_server::McpConnectionBuilder::identity`].
+#[macro_export]
+macro_rules! agent_identity {
+    () => {
+        $crate::rmcp::model::Implementation {
+            name: env!("CARGO_PKG_NAME").to_string(),
+            version: env!("CARGO_PKG_VERSION").to_string(),
+        }
+    };
+}
+
 ///
 /// Helper function that omits verbose logging information when CORAL_ORCHESTRATION_RUNTIME is set.
 /// This is useful for developing Coral agents because during dev-mode development, extra logging
 /// information is desired, but when the agents are being orchestrated (during application
 /// development), the extra information is duplicated with the server's logging information
+///
+/// Which events are logged is controlled by the `CORAL_LOG` environment variable, or `RUST_LOG` if
+/// it is not set, using [`EnvFilter`] directives (for example, `debug` or `coral_rs=trace`).  When
+/// neither is set, info and above is logged.  Warnings and errors are always written to stderr
+/// and everything else to stdout.
 pub fn init_tracing() -> Result<(), TryInitError> {
     if std::env::var("CORAL_ORCHESTRATION_RUNTIME").is_ok() {
         let stderr = tracing_subscriber::fmt::layer()
@@ -41,10 +73,11 @@ pub fn init_tracing() -> Result<(), TryInitError> {
             .with_writer(
                 io::stdout
                     .with_min_level(Level::INFO)
-                    .with_max_level(Level::INFO),
+                    .with_max_level(Level::TRACE),
             );
 
         tracing_subscriber::registry()
+            .with(env_filter())
             .with(stdout)
             .with(stderr)
             .try_init()
@@ -58,12 +91,141 @@ pub fn init_tracing() -> Result<(), TryInitError> {
         let stdout = tracing_subscriber::fmt::layer().with_writer(
             io::stdout
                 .with_min_level(Level::INFO)
-                .with_max_level(Level::INFO),
+                .with_max_level(Level::TRACE),
         );
 
         tracing_subscriber::registry()
+            .with(env_filter())
             .with(stdout)
             .with(stderr)
             .try_init()
     }
 }
+
+///
+/// The same as [`init_tracing`], but every line is written as a JSON object, for log aggregators
+/// that parse structured logs.  Warnings and errors are still written to stderr and info to
+/// stdout.
+///
+/// Unlike [`init_tracing`], targets and timestamps are kept when CORAL_ORCHESTRATION_RUNTIME is
+/// set, because these logs are read by machines rather than duplicating the server's logs for a
+/// human.  Span information is omitted when orchestrated.  Events are filtered in the same way as
+/// [`init_tracing`].
+pub fn init_tracing_json() -> Result<(), TryInitError> {
+    json_subscriber(io::stdout, io::stderr).try_init()
+}
+
+///
+/// The subscriber installed by [`init_tracing_json`], writing to the given stdout and stderr
+fn json_subscriber<O, E>(stdout: O, stderr: E) -> impl Subscriber + Send + Sync + 'static
+where
+    O: for<'w> MakeWriter<'w> + Send + Sync + 'static,
+    E: for<'w> MakeWriter<'w> + Send + Sync + 'static,
+{
+    let orchestrated = std::env::var("CORAL_ORCHESTRATION_RUNTIME").is_ok();
+
+    let stderr = tracing_subscriber::fmt::layer()
+        .json()
+        .with_current_span(!orchestrated)
+        .with_span_list(!orchestrated)
+        .with_writer(
+            stderr
+                .with_min_level(Level::ERROR)
+                .with_max_level(Level::WARN),
+        );
+
+    let stdout = tracing_subscriber::fmt::layer()
+        .json()
+        .with_current_span(!orchestrated)
+        .with_span_list(!orchestrated)
+        .with_writer(
+            stdout
+                .with_min_level(Level::INFO)
+                .with_max_level(Level::TRACE),
+        );
+
+    tracing_subscriber::registry()
+        .with(env_filter())
+        .with(stdout)
+        .with(stderr)
+}
+
+///
+/// The filter used by [`init_tracing`] and [`init_tracing_json`], taken from `CORAL_LOG` or
+/// `RUST_LOG`.  Defaults to info and above.
+fn env_filter() -> EnvFilter {
+    EnvFilter::try_from_env("CORAL_LOG")
+        .or_else(|_| EnvFilter::try_from_env("RUST_LOG"))
+        .unwrap_or_else(|_| EnvFilter::new("info"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::set_env;
+    use std::sync::{Arc, Mutex};
+    use tracing::{info, warn};
+
+    ///
+    /// A writer that keeps everything written to it
+    #[derive(Clone, Default)]
+    struct Captured(Arc<Mutex<Vec<u8>>>);
+
+    impl io::Write for Captured {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl MakeWriter<'_> for Captured {
+        type Writer = Captured;
+
+        fn make_writer(&self) -> Self::Writer {
+            self.clone()
+        }
+    }
+
+    impl Captured {
+        fn lines(&self) -> Vec<serde_json::Value> {
+            String::from_utf8(self.0.lock().unwrap().clone())
+                .unwrap()
+                .lines()
+                .map(|line| serde_json::from_str(line).unwrap())
+                .collect()
+        }
+    }
+
+    #[tokio::test]
+    async fn json_tracing_writes_one_json_object_per_line() {
+        let _env = set_env(&[
+            ("CORAL_LOG", None),
+            ("RUST_LOG", None),
+            ("CORAL_ORCHESTRATION_RUNTIME", None),
+        ])
+        .await;
+
+        let (stdout, stderr) = (Captured::default(), Captured::default());
+        tracing::subscriber::with_default(json_subscriber(stdout.clone(), stderr.clone()), || {
+            info!(answer = 42, "first");
+            info!("second");
+            warn!("careful");
+        });
+
+        let stdout = stdout.lines();
+        assert_eq!(stdout.len(), 2);
+        assert_eq!(stdout[0]["level"], "INFO");
+        assert_eq!(stdout[0]["fields"]["message"], "first");
+        assert_eq!(stdout[0]["fields"]["answer"], 42);
+        assert_eq!(stdout[1]["fields"]["message"], "second");
+
+        let stderr = stderr.lines();
+        assert_eq!(stderr.len(), 1);
+        assert_eq!(stderr[0]["level"], "WARN");
+        assert_eq!(stderr[0]["fields"]["message"], "careful");
+    }
+}
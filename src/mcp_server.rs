@@ -1,26 +1,57 @@
-use crate::completion_evaluated_prompt::CompletionEvaluatedPrompt;
+use crate::api::generated::types::{
+    CreateThreadInput, McpToolName, McpToolResult, ResolvedMessage, ResolvedThread,
+    SendMessageInput,
+};
+use crate::completion_evaluated_prompt::{CompletionEvaluatedPrompt, ResourceOrder};
 use crate::error::Error;
+use crate::resource_tool::ReadResourceTool;
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+use rig::completion::ToolDefinition;
 use rig::tool::rmcp::McpTool;
+use rig::tool::{ToolDyn, ToolError};
+use rmcp::model::ServerJsonRpcMessage;
 use rmcp::model::{
-    ClientInfo, Implementation, ProtocolVersion, ReadResourceRequestParam, ResourceContents,
+    CallToolRequestParam, ClientInfo, ClientRequest, ErrorCode, Implementation, PingRequest,
+    ProtocolVersion, ReadResourceRequestParam, Resource, ResourceContents,
+};
+use rmcp::service::{ClientInitializeError, RunningService};
+use rmcp::service::{RxJsonRpcMessage, TxJsonRpcMessage};
+use rmcp::transport::sse_client::SseClientConfig;
+use rmcp::transport::streamable_http_client::StreamableHttpClientTransportConfig;
+use rmcp::transport::{
+    ConfigureCommandExt, IntoTransport, SseClientTransport, StreamableHttpClientTransport,
+    TokioChildProcess, Transport,
 };
-use rmcp::service::RunningService;
-use rmcp::transport::{ConfigureCommandExt, SseClientTransport, TokioChildProcess};
 use rmcp::{RoleClient, ServiceExt};
+use serde::Serialize;
+use serde_json::json;
+use std::collections::HashMap;
+use std::pin::Pin;
 use std::sync::Arc;
 use tokio::process::Command;
+use tracing::{trace, warn};
 
 pub struct McpConnectionBuilder {
     client_info: ClientInfo,
+    protocol_version_fallbacks: Vec<ProtocolVersion>,
     transport: McpTransport,
     revalidate_tooling: bool,
     skip_tooling: bool,
+    allow_tools: Vec<String>,
+    deny_tools: Vec<String>,
+    tool_prefix: Option<String>,
+    trace_traffic: bool,
+    headers: Vec<(String, String)>,
 }
 
 struct SseTransport {
     url: String,
 }
 
+struct StreamableHttpTransport {
+    url: String,
+}
+
 struct StdioTransport {
     executable: String,
     arguments: Vec<String>,
@@ -29,6 +60,7 @@ struct StdioTransport {
 
 enum McpTransport {
     Sse(SseTransport),
+    StreamableHttp(StreamableHttpTransport),
     Stdio(StdioTransport),
 }
 
@@ -38,11 +70,40 @@ impl McpConnectionBuilder {
             client_info: ClientInfo {
                 protocol_version: Default::default(),
                 capabilities: Default::default(),
-                client_info: Implementation::from_build_env(),
+                client_info: Self::default_identity(),
             },
+            protocol_version_fallbacks: Vec::new(),
             transport,
             revalidate_tooling: false,
             skip_tooling: false,
+            allow_tools: Vec::new(),
+            deny_tools: Vec::new(),
+            tool_prefix: None,
+            trace_traffic: false,
+            headers: Vec::new(),
+        }
+    }
+
+    ///
+    /// The identity used when none is given with [`McpConnectionBuilder::identity`]: the name of
+    /// the running executable, which is the agent's binary rather than this library.  The version
+    /// of the executable is not known at runtime, so it is reported as "unknown".  Use
+    /// [`crate::agent_identity`] for an accurate version.
+    fn default_identity() -> Implementation {
+        let name = std::env::current_exe().ok().and_then(|exe| {
+            exe.file_stem()
+                .map(|stem| stem.to_string_lossy().into_owned())
+        });
+
+        match name {
+            Some(name) => Implementation {
+                name,
+                version: "unknown".to_string(),
+            },
+            None => Implementation {
+                name: env!("CARGO_PKG_NAME").to_string(),
+                version: env!("CARGO_PKG_VERSION").to_string(),
+            },
         }
     }
 
@@ -52,6 +113,14 @@ impl McpConnectionBuilder {
         Self::new(McpTransport::Sse(SseTransport { url: url.into() }))
     }
 
+    ///
+    /// Creates a new MCP connection builder using a streamable HTTP transport
+    pub fn streamable_http(url: impl Into<String>) -> Self {
+        Self::new(McpTransport::StreamableHttp(StreamableHttpTransport {
+            url: url.into(),
+        }))
+    }
+
     ///
     /// Creates a new MCP connection builder using a child process (stdio transport)
     pub fn stdio(
@@ -71,19 +140,46 @@ impl McpConnectionBuilder {
     /// used when the agent is orchestrated with Coral.  CORAL_CONNECTION_URL is set by the Coral
     /// server and is required for this function to work.  If CORAL_CONNECTION_URL is not set, this
     /// function will panic.
+    ///
+    /// The SSE transport is used unless CORAL_TRANSPORT is set to `http`, in which case the
+    /// streamable HTTP transport is used.  The protocol version matches the transport: SSE uses
+    /// [`ProtocolVersion::V_2024_11_05`], and streamable HTTP, which was introduced in the
+    /// 2025-03-26 revision of the protocol, uses [`ProtocolVersion::V_2025_03_26`].
     pub fn from_coral_env() -> Self {
-        Self::sse(std::env::var("CORAL_CONNECTION_URL").expect("CORAL_CONNECTION_URL not set"))
-            .protocol_version(ProtocolVersion::V_2024_11_05)
+        let url = std::env::var("CORAL_CONNECTION_URL").expect("CORAL_CONNECTION_URL not set");
+        match std::env::var("CORAL_TRANSPORT").as_deref() {
+            Ok("http") => {
+                Self::streamable_http(url).protocol_version(ProtocolVersion::V_2025_03_26)
+            }
+            _ => Self::sse(url).protocol_version(ProtocolVersion::V_2024_11_05),
+        }
     }
 
     ///
     /// MCP server Protocol.  The Coral MCP server currently requires that this is set to
-    /// [`ProtocolVersion::V_2024_11_05`]
+    /// [`ProtocolVersion::V_2024_11_05`] for the SSE transport, or
+    /// [`ProtocolVersion::V_2025_03_26`] for the streamable HTTP transport
     pub fn protocol_version(mut self, protocol_version: ProtocolVersion) -> Self {
         self.client_info.protocol_version = protocol_version;
         self
     }
 
+    ///
+    /// Protocol versions to fall back to, in order, if the server rejects the version set by
+    /// [`McpConnectionBuilder::protocol_version`] during initialization.  This is useful when
+    /// connecting to a mix of Coral and third-party MCP servers with different version
+    /// requirements.
+    ///
+    /// A fresh transport is created for every attempt.  Errors that are not caused by the server
+    /// rejecting initialization are returned immediately without trying any fallbacks.
+    pub fn protocol_version_fallbacks(
+        mut self,
+        protocol_version_fallbacks: impl IntoIterator<Item = ProtocolVersion>,
+    ) -> Self {
+        self.protocol_version_fallbacks = protocol_version_fallbacks.into_iter().collect();
+        self
+    }
+
     ///
     /// The name of the agent as exposed to other agents on the MCP server
     pub fn name(mut self, name: String) -> Self {
@@ -98,6 +194,23 @@ impl McpConnectionBuilder {
         self
     }
 
+    ///
+    /// The name and version of the agent as exposed to other agents on the MCP server.  Use
+    /// [`crate::agent_identity`] to use the name and version from the agent's Cargo metadata:
+    ///
+    /// ```ignore
+    /// let connection = McpConnectionBuilder::from_coral_env()
+    ///     .identity(coral_rs::agent_identity!())
+    ///     .connect()
+    ///     .await?;
+    /// ```
+    ///
+    /// By default, the name of the running executable is used, with an unknown version.
+    pub fn identity(mut self, identity: Implementation) -> Self {
+        self.client_info.client_info = identity;
+        self
+    }
+
     ///
     /// Full client info struct used internally by RMCP
     pub fn client_info(mut self, client_info: ClientInfo) -> Self {
@@ -122,53 +235,312 @@ impl McpConnectionBuilder {
     }
 
     ///
-    /// Builds the connection builder into a connection to an MCP server
+    /// Only tools with these names are given to agents using this connection.  An empty list (the
+    /// default) allows every tool.  If both an allowlist and a denylist are set, the allowlist takes
+    /// precedence and the denylist is ignored.
+    pub fn allow_tools(mut self, allow_tools: Vec<String>) -> Self {
+        self.allow_tools = allow_tools;
+        self
+    }
+
+    ///
+    /// Tools with these names are never given to agents using this connection.  Ignored if an
+    /// allowlist is set, see [`McpConnectionBuilder::allow_tools`].
+    pub fn deny_tools(mut self, deny_tools: Vec<String>) -> Self {
+        self.deny_tools = deny_tools;
+        self
+    }
+
+    ///
+    /// Prefixes the name of every tool from this connection with `prefix` and
+    /// [`TOOL_PREFIX_SEPARATOR`], e.g. `github__search` for the prefix `github`.  This prevents
+    /// tools with the same name on different MCP servers from replacing each other when they are
+    /// given to an agent.
+    ///
+    /// The model sees (and calls) the prefixed name.  Calls are sent to the MCP server with the
+    /// real name.  Note that the allow and deny lists use the real name, while
+    /// [`crate::agent::Agent::filter_tools`] and other tool names configured on the agent use the
+    /// prefixed name.  Most model providers only accept tool names made up of letters, digits,
+    /// underscores and dashes.
+    pub fn tool_prefix(mut self, prefix: impl Into<String>) -> Self {
+        self.tool_prefix = Some(prefix.into());
+        self
+    }
+
+    ///
+    /// If set to true, every raw JSON-RPC message sent to and received from the MCP server is
+    /// logged at trace level, with the target `coral_rs::mcp_traffic`.  This is useful for
+    /// diagnosing rejected requests, but is verbose and can include sensitive data, so it should
+    /// not be enabled in production.  Default is false.
+    pub fn trace_traffic(mut self, trace_traffic: bool) -> Self {
+        self.trace_traffic = trace_traffic;
+        self
+    }
+
+    ///
+    /// Adds an HTTP header that is sent with every request to the MCP server, for example, an
+    /// `Authorization` header required by an MCP gateway.  For the SSE transport, headers are sent
+    /// on the initial handshake, on reconnects and with every message.  Headers are ignored by the
+    /// stdio transport.
+    ///
+    /// This can be called multiple times to add multiple headers.  Invalid header names or values
+    /// are reported by [`McpConnectionBuilder::connect`].
+    pub fn header(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        self.headers.push((key.into(), value.into()));
+        self
+    }
+
+    ///
+    /// Builds the HTTP client used by the SSE and streamable HTTP transports, with every header
+    /// from [`McpConnectionBuilder::header`] set as a default header
+    fn http_client(&self) -> Result<reqwest::Client, Error> {
+        let mut headers = HeaderMap::new();
+        for (key, value) in &self.headers {
+            let name = HeaderName::try_from(key.as_str())
+                .map_err(|e| Error::InvalidMcpHeader(format!("\"{key}\": {e}")))?;
+            let value = HeaderValue::try_from(value.as_str())
+                .map_err(|e| Error::InvalidMcpHeader(format!("\"{key}\": {e}")))?;
+
+            headers.append(name, value);
+        }
+
+        reqwest::Client::builder()
+            .default_headers(headers)
+            .build()
+            .map_err(|e| Error::InvalidMcpHeader(e.to_string()))
+    }
+
+    ///
+    /// Builds the connection builder into a connection to an MCP server.  If the server rejects
+    /// the protocol version, each of the
+    /// [`McpConnectionBuilder::protocol_version_fallbacks`] is tried in turn.
     pub async fn connect(self) -> Result<McpServerConnection, Error> {
-        match self.transport {
+        let mut versions = std::iter::once(self.client_info.protocol_version.clone())
+            .chain(self.protocol_version_fallbacks.clone())
+            .peekable();
+
+        loop {
+            let version = versions
+                .next()
+                .expect("there is always at least one protocol version");
+
+            let mut client_info = self.client_info.clone();
+            client_info.protocol_version = version.clone();
+
+            match self.connect_with(client_info).await {
+                Err(Error::McpClientError(e)) if Self::is_version_rejection(&e) => {
+                    let Some(next) = versions.peek() else {
+                        return Err(Error::McpClientError(e));
+                    };
+
+                    warn!(
+                        "MCP server rejected protocol version {version} ({e}), retrying with {next}"
+                    );
+                }
+                res => {
+                    return res.map(|mut connection| {
+                        connection.allow_tools = self.allow_tools.clone();
+                        connection.deny_tools = self.deny_tools.clone();
+                        connection.tool_prefix = self.tool_prefix.clone();
+                        connection
+                    });
+                }
+            }
+        }
+    }
+
+    ///
+    /// Returns true if the initialization error is the server rejecting the requested protocol
+    /// version.  The MCP specification has servers reject an unsupported version with an "invalid
+    /// params" error; servers that use another error code are recognised by the error message.
+    /// Other failures, such as the connection closing, are not treated as a rejection.
+    fn is_version_rejection(error: &ClientInitializeError) -> bool {
+        match error {
+            ClientInitializeError::ExpectedInitResponse(Some(ServerJsonRpcMessage::Error(e))) => {
+                e.error.code == ErrorCode::INVALID_PARAMS
+                    || e.error.message.to_lowercase().contains("version")
+            }
+            _ => false,
+        }
+    }
+
+    ///
+    /// Wraps a transport so that its traffic is logged if [`McpConnectionBuilder::trace_traffic`]
+    /// is set
+    fn traced<T, E, A>(
+        &self,
+        transport: T,
+        identifier: &str,
+    ) -> TracedTransport<impl Transport<RoleClient, Error = E> + use<T, E, A>>
+    where
+        T: IntoTransport<RoleClient, E, A>,
+        E: std::error::Error + Send + Sync + 'static,
+    {
+        TracedTransport {
+            inner: transport.into_transport(),
+            identifier: identifier.to_string(),
+            enabled: self.trace_traffic,
+        }
+    }
+
+    ///
+    /// Makes a single connection attempt using the given client info
+    async fn connect_with(&self, client_info: ClientInfo) -> Result<McpServerConnection, Error> {
+        match &self.transport {
             McpTransport::Sse(sse) => {
-                let transport = SseClientTransport::start(sse.url.clone())
-                    .await
-                    .map_err(Error::McpSseError)?;
+                let transport = SseClientTransport::start_with_client(
+                    self.http_client()?,
+                    SseClientConfig {
+                        sse_endpoint: sse.url.as_str().into(),
+                        ..Default::default()
+                    },
+                )
+                .await
+                .map_err(Error::McpSseError)?;
 
-                let transport = self
-                    .client_info
-                    .serve(transport)
+                let transport = client_info
+                    .serve(self.traced(transport, &sse.url))
                     .await
-                    .map_err(Error::McpClientError)?;
+                    .map_err(|e| Error::McpClientError(Box::new(e)))?;
 
                 Ok(McpServerConnection::new(
                     transport,
                     self.revalidate_tooling,
                     self.skip_tooling,
                     sse.url.clone(),
-                )
-                .into())
+                ))
+            }
+            McpTransport::StreamableHttp(http) => {
+                let transport = StreamableHttpClientTransport::with_client(
+                    self.http_client()?,
+                    StreamableHttpClientTransportConfig::with_uri(http.url.as_str()),
+                );
+
+                let transport = client_info
+                    .serve(self.traced(transport, &http.url))
+                    .await
+                    .map_err(|e| match e {
+                        ClientInitializeError::TransportError { .. } => {
+                            Error::McpUnreachable(http.url.clone(), Box::new(e))
+                        }
+                        e => Error::McpClientError(Box::new(e)),
+                    })?;
+
+                Ok(McpServerConnection::new(
+                    transport,
+                    self.revalidate_tooling,
+                    self.skip_tooling,
+                    http.url.clone(),
+                ))
             }
             McpTransport::Stdio(stdio) => {
-                let cmd = Command::new(stdio.executable).configure(|c| {
+                let cmd = Command::new(&stdio.executable).configure(|c| {
                     c.args(&stdio.arguments);
                 });
 
                 let transport = TokioChildProcess::new(cmd).map_err(Error::McpStdioError)?;
 
-                let transport = self
-                    .client_info
-                    .serve(transport)
+                let transport = client_info
+                    .serve(self.traced(transport, &stdio.identifier))
                     .await
-                    .map_err(Error::McpClientError)?;
+                    .map_err(|e| Error::McpClientError(Box::new(e)))?;
 
                 Ok(McpServerConnection::new(
                     transport,
                     self.revalidate_tooling,
                     self.skip_tooling,
-                    stdio.identifier,
-                )
-                .into())
+                    stdio.identifier.clone(),
+                ))
             }
         }
     }
 }
 
+///
+/// A transport that logs every message passing through it at trace level.  See
+/// [`McpConnectionBuilder::trace_traffic`].
+struct TracedTransport<T> {
+    inner: T,
+    identifier: String,
+    enabled: bool,
+}
+
+impl<T> TracedTransport<T> {
+    ///
+    /// Logs a message, if tracing is enabled
+    fn trace(&self, direction: &str, message: &impl Serialize) {
+        if !self.enabled {
+            return;
+        }
+
+        let json = serde_json::to_string(message)
+            .unwrap_or_else(|e| format!("<unserializable message: {e}>"));
+        trace!(target: "coral_rs::mcp_traffic", "[{}] {direction} {json}", self.identifier);
+    }
+}
+
+impl<T: Transport<RoleClient>> Transport<RoleClient> for TracedTransport<T> {
+    type Error = T::Error;
+
+    fn send(
+        &mut self,
+        item: TxJsonRpcMessage<RoleClient>,
+    ) -> impl Future<Output = Result<(), Self::Error>> + Send + 'static {
+        self.trace("->", &item);
+        self.inner.send(item)
+    }
+
+    async fn receive(&mut self) -> Option<RxJsonRpcMessage<RoleClient>> {
+        let message = self.inner.receive().await;
+        if let Some(message) = &message {
+            self.trace("<-", message);
+        }
+
+        message
+    }
+
+    fn close(&mut self) -> impl Future<Output = Result<(), Self::Error>> + Send {
+        self.inner.close()
+    }
+}
+
+///
+/// Separates a tool prefix from the real tool name, see [`McpConnectionBuilder::tool_prefix`]
+pub const TOOL_PREFIX_SEPARATOR: &str = "__";
+
+///
+/// A tool from an [`McpServerConnection`], named as the model sees it.  Calls are dispatched to
+/// the MCP server using the tool's real name.
+pub(crate) struct McpConnectionTool {
+    name: String,
+    tool: McpTool,
+}
+
+impl ToolDyn for McpConnectionTool {
+    fn name(&self) -> String {
+        self.name.clone()
+    }
+
+    fn definition(
+        &self,
+        prompt: String,
+    ) -> Pin<Box<dyn Future<Output = ToolDefinition> + Send + Sync + '_>> {
+        Box::pin(async move {
+            ToolDefinition {
+                name: self.name.clone(),
+                ..self.tool.definition(prompt).await
+            }
+        })
+    }
+
+    fn call(
+        &self,
+        args: String,
+    ) -> Pin<Box<dyn Future<Output = Result<String, ToolError>> + Send + '_>> {
+        self.tool.call(args)
+    }
+}
+
 ///
 /// Represents a live connection to an MCP server.
 #[derive(Clone)]
@@ -177,10 +549,14 @@ pub struct McpServerConnection {
     pub(crate) revalidate_tooling: bool,
     pub(crate) skip_tooling: bool,
     pub(crate) identifier: String,
+    allow_tools: Vec<String>,
+    deny_tools: Vec<String>,
+    tool_prefix: Option<String>,
+    direct_threads: Arc<tokio::sync::Mutex<HashMap<String, String>>>,
 }
 
 impl McpServerConnection {
-    fn new(
+    pub(crate) fn new(
         running_service: RunningService<RoleClient, ClientInfo>,
         revalidate_tooling: bool,
         skip_tooling: bool,
@@ -191,6 +567,10 @@ impl McpServerConnection {
             revalidate_tooling,
             skip_tooling,
             identifier,
+            allow_tools: Vec::new(),
+            deny_tools: Vec::new(),
+            tool_prefix: None,
+            direct_threads: Arc::default(),
         }
     }
 
@@ -198,41 +578,53 @@ impl McpServerConnection {
     /// Returns a list of tooling that this MCP server provides.  Note that a tool must live as long
     /// as the connection does.  The MCP connection wrapped in this struct therefore remains alive
     /// for as long as tooling returned by this function does.
-    pub(crate) async fn get_tools(&self) -> Result<Vec<McpTool>, Error> {
+    ///
+    /// Tools excluded by [`McpConnectionBuilder::allow_tools`] or
+    /// [`McpConnectionBuilder::deny_tools`] are not returned.  Tools are named with the
+    /// [`McpConnectionBuilder::tool_prefix`], if there is one.
+    pub(crate) async fn get_tools(&self) -> Result<Vec<McpConnectionTool>, Error> {
         Ok(self
             .running_service
             .list_all_tools()
             .await
             .map_err(Error::McpServiceError)?
             .into_iter()
-            .map(|x| McpTool::from_mcp_server(x, self.running_service.peer().clone()))
+            .filter(|tool| self.is_tool_allowed(&tool.name))
+            .map(|tool| McpConnectionTool {
+                name: match &self.tool_prefix {
+                    Some(prefix) => format!("{prefix}{TOOL_PREFIX_SEPARATOR}{}", tool.name),
+                    None => tool.name.to_string(),
+                },
+                tool: McpTool::from_mcp_server(tool, self.running_service.peer().clone()),
+            })
             .collect())
     }
 
     ///
-    /// Returns a list of resolved resources from this MCP server
-    pub(crate) async fn get_resources(&self) -> Result<Vec<ResourceContents>, Error> {
-        let resource_list = self
-            .running_service
+    /// Returns true if the tool passes this connection's allowlist, or its denylist if there is no
+    /// allowlist
+    fn is_tool_allowed(&self, name: &str) -> bool {
+        if self.allow_tools.is_empty() {
+            !self.deny_tools.iter().any(|tool| tool == name)
+        } else {
+            self.allow_tools.iter().any(|tool| tool == name)
+        }
+    }
+
+    ///
+    /// Returns every resource on this MCP server
+    pub(crate) async fn list_resources(&self) -> Result<Vec<Resource>, Error> {
+        self.running_service
             .list_all_resources()
             .await
-            .map_err(Error::McpServiceError)?;
-
-        let mut resource_content_list = Vec::new();
-        for resource in resource_list {
-            let contents = self
-                .running_service
-                .read_resource(ReadResourceRequestParam {
-                    uri: resource.uri.clone(),
-                })
-                .await
-                .map_err(Error::McpServiceError)?
-                .contents;
-
-            resource_content_list.extend(contents);
-        }
+            .map_err(Error::McpServiceError)
+    }
 
-        Ok(resource_content_list)
+    ///
+    /// Returns a tool that lets a model read resources from this MCP server on demand.  See
+    /// [`ReadResourceTool`].
+    pub fn read_resource_tool(&self) -> ReadResourceTool {
+        ReadResourceTool::new(self.clone())
     }
 
     ///
@@ -249,6 +641,285 @@ impl McpServerConnection {
             .contents)
     }
 
+    ///
+    /// Sends a ping request to this MCP server
+    pub async fn ping(&self) -> Result<(), Error> {
+        self.running_service
+            .send_request(ClientRequest::PingRequest(PingRequest::default()))
+            .await
+            .map_err(Error::McpServiceError)?;
+
+        Ok(())
+    }
+
+    ///
+    /// Closes this connection by cancelling the running MCP service.  For stdio transports, this
+    /// terminates the child process.
+    ///
+    /// If this is the last handle to the connection, this function waits until the service has
+    /// fully shut down.  If the connection is still shared (e.g. a clone is held by an
+    /// [`crate::agent::Agent`] or a prompt), the service is cancelled for every holder and shuts
+    /// down in the background.  Either way, the connection can no longer be used afterward.
+    pub async fn close(self) -> Result<(), Error> {
+        match Arc::try_unwrap(self.running_service) {
+            Ok(running_service) => {
+                running_service
+                    .cancel()
+                    .await
+                    .map_err(Error::McpCloseError)?;
+            }
+            Err(running_service) => running_service.cancellation_token().cancel(),
+        }
+
+        Ok(())
+    }
+
+    ///
+    /// Calls a tool on this MCP server by name, without going through a model or an
+    /// [`crate::agent::Agent`], and returns the text content of the result.  `arguments` must be a
+    /// JSON object, or null for tools that take no arguments.
+    ///
+    /// Returns [`Error::McpToolError`] if the tool does not exist on the server or the server
+    /// reports that the call failed.
+    pub async fn call_tool(
+        &self,
+        name: &str,
+        arguments: serde_json::Value,
+    ) -> Result<String, Error> {
+        let arguments = match arguments {
+            serde_json::Value::Object(arguments) => Some(arguments),
+            serde_json::Value::Null => None,
+            other => {
+                return Err(Error::McpToolError(format!(
+                    "arguments for tool \"{name}\" must be a JSON object, got {other}"
+                )));
+            }
+        };
+
+        let result = match self
+            .running_service
+            .call_tool(CallToolRequestParam {
+                name: name.to_string().into(),
+                arguments,
+            })
+            .await
+        {
+            Ok(result) => result,
+            Err(e) => {
+                // Servers report unknown tools in different ways, so check the tool list to give
+                // a consistent error
+                let tools = self
+                    .running_service
+                    .list_all_tools()
+                    .await
+                    .map_err(Error::McpServiceError)?;
+
+                return Err(if tools.iter().any(|tool| tool.name == name) {
+                    Error::McpServiceError(e)
+                } else {
+                    Error::McpToolError(format!(
+                        "tool \"{name}\" does not exist on {}",
+                        self.identifier
+                    ))
+                });
+            }
+        };
+
+        let text = result
+            .content
+            .unwrap_or_default()
+            .iter()
+            .filter_map(|content| content.as_text().map(|text| text.text.clone()))
+            .collect::<String>();
+
+        if result.is_error.unwrap_or(false) {
+            Err(Error::McpToolError(format!(
+                "tool \"{name}\" failed: {text}"
+            )))
+        } else {
+            Ok(text)
+        }
+    }
+
+    ///
+    /// Calls a Coral tool directly, without going through a model, and parses the result.  Error
+    /// results from the Coral server are returned as [`Error::CoralToolError`].
+    async fn call_coral_tool(
+        &self,
+        name: McpToolName,
+        arguments: impl Serialize,
+    ) -> Result<McpToolResult, Error> {
+        let arguments = match serde_json::to_value(arguments) {
+            Ok(serde_json::Value::Object(arguments)) => Some(arguments),
+            Ok(_) => None,
+            Err(e) => return Err(Error::CoralToolResultError(e)),
+        };
+
+        let result = self
+            .running_service
+            .call_tool(CallToolRequestParam {
+                name: name.to_string().into(),
+                arguments,
+            })
+            .await
+            .map_err(Error::McpServiceError)?;
+
+        let text = result
+            .content
+            .unwrap_or_default()
+            .iter()
+            .filter_map(|content| content.as_text().map(|text| text.text.clone()))
+            .collect::<String>();
+
+        let result =
+            serde_json::from_str::<McpToolResult>(&text).map_err(Error::CoralToolResultError)?;
+
+        match result {
+            McpToolResult::Error { message } | McpToolResult::ToolInputError { message } => {
+                Err(Error::CoralToolError(message))
+            }
+            result => Ok(result),
+        }
+    }
+
+    ///
+    /// Lists the IDs of the other agents in this Coral session.  This requires that this
+    /// connection is to a Coral MCP server.
+    pub async fn list_agents(&self) -> Result<Vec<String>, Error> {
+        match self
+            .call_coral_tool(McpToolName::CoralListAgents, json!({}))
+            .await?
+        {
+            McpToolResult::AgentListSuccess { agents } => Ok(agents),
+            McpToolResult::AgentListSuccessWithDetails { agents } => {
+                Ok(agents.into_iter().map(|agent| agent.id).collect())
+            }
+            other => Err(Error::CoralToolError(format!(
+                "unexpected list agents result: {other:?}"
+            ))),
+        }
+    }
+
+    ///
+    /// Creates a new thread with the given participants.  This requires that this connection is to
+    /// a Coral MCP server.
+    pub async fn create_thread(
+        &self,
+        thread_name: impl Into<String>,
+        participant_ids: impl IntoIterator<Item = impl Into<String>>,
+    ) -> Result<ResolvedThread, Error> {
+        let input = CreateThreadInput {
+            thread_name: thread_name.into(),
+            participant_ids: participant_ids.into_iter().map(Into::into).collect(),
+        };
+
+        match self
+            .call_coral_tool(McpToolName::CoralCreateThread, input)
+            .await?
+        {
+            McpToolResult::CreateThreadSuccess { thread } => Ok(thread),
+            other => Err(Error::CoralToolError(format!(
+                "unexpected create thread result: {other:?}"
+            ))),
+        }
+    }
+
+    ///
+    /// Sends a message to a thread, mentioning the given agents.  This requires that this
+    /// connection is to a Coral MCP server.
+    pub async fn send_message(
+        &self,
+        thread_id: impl Into<String>,
+        content: impl Into<String>,
+        mentions: impl IntoIterator<Item = impl Into<String>>,
+    ) -> Result<ResolvedMessage, Error> {
+        let input = SendMessageInput {
+            thread_id: thread_id.into(),
+            content: content.into(),
+            mentions: mentions.into_iter().map(Into::into).collect(),
+        };
+
+        match self
+            .call_coral_tool(McpToolName::CoralSendMessage, input)
+            .await?
+        {
+            McpToolResult::SendMessageSuccess { message } => Ok(message),
+            other => Err(Error::CoralToolError(format!(
+                "unexpected send message result: {other:?}"
+            ))),
+        }
+    }
+
+    ///
+    /// Sends a direct message to another agent, mentioning the agent.  The first direct message to
+    /// an agent creates a thread between this agent and the named agent; later direct messages to
+    /// the same agent over this connection (or its clones) are sent to that thread.  Use
+    /// [`McpServerConnection::wait_for_reply`] with the returned message's thread ID and the
+    /// agent's ID to wait for a reply.
+    pub async fn send_direct_message(
+        &self,
+        agent_id: impl Into<String>,
+        content: impl Into<String>,
+    ) -> Result<ResolvedMessage, Error> {
+        let agent_id = agent_id.into();
+        let thread_id = {
+            let mut direct_threads = self.direct_threads.lock().await;
+            match direct_threads.get(&agent_id) {
+                Some(thread_id) => thread_id.clone(),
+                None => {
+                    let thread = self
+                        .create_thread(format!("direct-{agent_id}"), [agent_id.clone()])
+                        .await?;
+                    direct_threads.insert(agent_id.clone(), thread.id.clone());
+                    thread.id
+                }
+            }
+        };
+
+        self.send_message(thread_id, content, [agent_id]).await
+    }
+
+    ///
+    /// Waits for other agents to mention this agent.  Returns the messages that mentioned this
+    /// agent, which will be empty if the Coral server's timeout was reached.  This requires that
+    /// this connection is to a Coral MCP server.
+    pub async fn wait_for_mentions(&self) -> Result<Vec<ResolvedMessage>, Error> {
+        match self
+            .call_coral_tool(McpToolName::CoralWaitForMentions, json!({}))
+            .await?
+        {
+            McpToolResult::WaitForMentionsSuccess { messages } => Ok(messages),
+            McpToolResult::ErrorTimeout => Ok(Vec::new()),
+            other => Err(Error::CoralToolError(format!(
+                "unexpected wait for mentions result: {other:?}"
+            ))),
+        }
+    }
+
+    ///
+    /// Waits for the given agent to mention this agent in the given thread, for example, a reply
+    /// to [`McpServerConnection::send_direct_message`].  Unlike
+    /// [`McpServerConnection::wait_for_mentions`], this keeps waiting until such a message
+    /// arrives.  Mentions in other threads or from other agents are discarded, so use
+    /// [`McpServerConnection::wait_for_mentions`] and filter the messages yourself if this agent
+    /// may be mentioned elsewhere while it waits.
+    pub async fn wait_for_reply(
+        &self,
+        thread_id: &str,
+        sender_id: &str,
+    ) -> Result<ResolvedMessage, Error> {
+        loop {
+            let reply =
+                self.wait_for_mentions().await?.into_iter().find(|message| {
+                    message.thread_id == thread_id && message.sender_id == sender_id
+                });
+
+            if let Some(reply) = reply {
+                return Ok(reply);
+            }
+        }
+    }
+
     ///
     /// Quick helper function to create a [`CompletionEvaluatedPrompt`] from this MCP connection,
     /// this will include an [`CompletionEvaluatedPrompt::all_resources`] call from this MCP
@@ -259,7 +930,25 @@ impl McpServerConnection {
         &self,
         prompt: impl Into<String>,
     ) -> CompletionEvaluatedPrompt {
-        CompletionEvaluatedPrompt::from_string(prompt).all_resources(self.clone())
+        self.prompt_with_resources_ordered(prompt, ResourceOrder::InstructionFirst)
+    }
+
+    ///
+    /// The same as [`McpServerConnection::prompt_with_resources_str`], but the order of the passed
+    /// in string and the resources is given by `order`
+    pub fn prompt_with_resources_ordered(
+        &self,
+        prompt: impl Into<String>,
+        order: ResourceOrder,
+    ) -> CompletionEvaluatedPrompt {
+        match order {
+            ResourceOrder::InstructionFirst => {
+                CompletionEvaluatedPrompt::from_string(prompt).all_resources(self.clone())
+            }
+            ResourceOrder::ResourcesFirst => CompletionEvaluatedPrompt::new()
+                .all_resources(self.clone())
+                .string(prompt),
+        }
     }
 
     ///
@@ -270,3 +959,262 @@ impl McpServerConnection {
         CompletionEvaluatedPrompt::new().all_resources(self.clone())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock_completion_model::MockCompletionModel;
+    use crate::test_util::{FakeMcpServer, set_env, test_agent, tool_calls};
+    use rig::completion::Message;
+    use rmcp::model::{ErrorData, JsonRpcError, JsonRpcVersion2_0, NumberOrString};
+    use std::time::Duration;
+    use wiremock::matchers::method;
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    fn init_error(code: ErrorCode, message: &'static str) -> ClientInitializeError {
+        ClientInitializeError::ExpectedInitResponse(Some(ServerJsonRpcMessage::Error(
+            JsonRpcError {
+                jsonrpc: JsonRpcVersion2_0,
+                id: NumberOrString::Number(0),
+                error: ErrorData::new(code, message, None),
+            },
+        )))
+    }
+
+    #[test]
+    fn version_rejections() {
+        assert!(McpConnectionBuilder::is_version_rejection(&init_error(
+            ErrorCode::INVALID_PARAMS,
+            "Unsupported protocol version"
+        )));
+        assert!(McpConnectionBuilder::is_version_rejection(&init_error(
+            ErrorCode::INVALID_REQUEST,
+            "protocol version 2025-03-26 is not supported"
+        )));
+        assert!(!McpConnectionBuilder::is_version_rejection(&init_error(
+            ErrorCode::INTERNAL_ERROR,
+            "database unavailable"
+        )));
+        assert!(!McpConnectionBuilder::is_version_rejection(
+            &ClientInitializeError::ConnectionClosed("initialize response".to_string())
+        ));
+    }
+
+    #[tokio::test]
+    async fn coral_env_protocol_version_matches_transport() {
+        let env = set_env(&[
+            ("CORAL_CONNECTION_URL", Some("http://localhost:5555/mcp")),
+            ("CORAL_TRANSPORT", Some("http")),
+        ])
+        .await;
+        let builder = McpConnectionBuilder::from_coral_env();
+        assert!(matches!(builder.transport, McpTransport::StreamableHttp(_)));
+        assert_eq!(
+            builder.client_info.protocol_version,
+            ProtocolVersion::V_2025_03_26
+        );
+        drop(env);
+
+        let _env = set_env(&[
+            ("CORAL_CONNECTION_URL", Some("http://localhost:5555/sse")),
+            ("CORAL_TRANSPORT", None),
+        ])
+        .await;
+        let builder = McpConnectionBuilder::from_coral_env();
+        assert!(matches!(builder.transport, McpTransport::Sse(_)));
+        assert_eq!(
+            builder.client_info.protocol_version,
+            ProtocolVersion::V_2024_11_05
+        );
+    }
+
+    #[tokio::test]
+    async fn headers_are_sent_to_streamable_http_servers() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .respond_with(ResponseTemplate::new(500))
+            .mount(&server)
+            .await;
+
+        let connection = McpConnectionBuilder::streamable_http(format!("{}/mcp", server.uri()))
+            .header("Authorization", "Bearer token")
+            .header("X-Gateway", "coral")
+            .connect();
+        let res = tokio::time::timeout(Duration::from_secs(10), connection)
+            .await
+            .unwrap();
+        assert!(res.is_err());
+
+        let requests = server.received_requests().await.unwrap();
+        assert!(!requests.is_empty());
+        for request in requests {
+            assert_eq!(
+                request.headers.get("authorization").unwrap(),
+                "Bearer token"
+            );
+            assert_eq!(request.headers.get("x-gateway").unwrap(), "coral");
+        }
+    }
+
+    #[tokio::test]
+    async fn headers_are_sent_to_sse_servers() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(500))
+            .mount(&server)
+            .await;
+
+        let connection = McpConnectionBuilder::sse(format!("{}/sse", server.uri()))
+            .header("Authorization", "Bearer token")
+            .header("X-Gateway", "coral")
+            .connect();
+        let res = tokio::time::timeout(Duration::from_secs(10), connection)
+            .await
+            .unwrap();
+        assert!(res.is_err());
+
+        let requests = server.received_requests().await.unwrap();
+        assert!(!requests.is_empty());
+        for request in requests {
+            assert_eq!(request.method, wiremock::http::Method::GET);
+            assert_eq!(
+                request.headers.get("authorization").unwrap(),
+                "Bearer token"
+            );
+            assert_eq!(request.headers.get("x-gateway").unwrap(), "coral");
+        }
+    }
+
+    fn resolved_message(id: &str, thread_id: &str, sender_id: &str) -> serde_json::Value {
+        json!({
+            "content": "hello",
+            "id": id,
+            "mentions": ["me"],
+            "senderId": sender_id,
+            "threadId": thread_id,
+            "threadName": "direct",
+            "timestamp": 0,
+        })
+    }
+
+    #[tokio::test]
+    async fn direct_messages_reuse_their_thread() {
+        let thread = json!({
+            "creatorId": "me",
+            "id": "thread-1",
+            "isClosed": false,
+            "messages": [],
+            "name": "direct-other",
+            "participants": ["me", "other"],
+        });
+        let sent = json!({
+            "result": "send_message_success",
+            "message": resolved_message("message-1", "thread-1", "me"),
+        });
+        let server = FakeMcpServer::new("coral")
+            .tool_result(
+                McpToolName::CoralCreateThread.to_string(),
+                json!({"result": "create_thread_success", "thread": thread}).to_string(),
+            )
+            .tool_result(McpToolName::CoralSendMessage.to_string(), sent.to_string())
+            .tool_result(McpToolName::CoralSendMessage.to_string(), sent.to_string());
+        let connection = server.connect().await;
+
+        let first = connection.send_direct_message("other", "a").await.unwrap();
+        let second = connection.send_direct_message("other", "b").await.unwrap();
+
+        assert_eq!(first.thread_id, "thread-1");
+        assert_eq!(second.thread_id, "thread-1");
+        assert_eq!(
+            server.tool_calls(),
+            [
+                McpToolName::CoralCreateThread.to_string(),
+                McpToolName::CoralSendMessage.to_string(),
+                McpToolName::CoralSendMessage.to_string(),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn wait_for_reply_skips_other_mentions() {
+        let wait_for_mentions = McpToolName::CoralWaitForMentions.to_string();
+        let server = FakeMcpServer::new("coral")
+            .tool_result(
+                &wait_for_mentions,
+                json!({
+                    "result": "wait_for_mentions_success",
+                    "messages": [
+                        resolved_message("message-1", "thread-2", "other"),
+                        resolved_message("message-2", "thread-1", "third"),
+                    ],
+                })
+                .to_string(),
+            )
+            .tool_result(
+                &wait_for_mentions,
+                json!({"result": "error_timeout"}).to_string(),
+            )
+            .tool_result(
+                &wait_for_mentions,
+                json!({
+                    "result": "wait_for_mentions_success",
+                    "messages": [resolved_message("message-3", "thread-1", "other")],
+                })
+                .to_string(),
+            );
+        let connection = server.connect().await;
+
+        let reply = connection
+            .wait_for_reply("thread-1", "other")
+            .await
+            .unwrap();
+
+        assert_eq!(reply.id, "message-3");
+        assert_eq!(server.tool_calls().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn invalid_headers_are_reported() {
+        let res = McpConnectionBuilder::streamable_http("http://localhost:1/mcp")
+            .header("Authorization", "Bearer\ntoken")
+            .connect()
+            .await;
+
+        assert!(matches!(res, Err(Error::InvalidMcpHeader(_))));
+    }
+
+    async fn prefixed_connection(name: &str) -> McpServerConnection {
+        let mut connection = FakeMcpServer::new(name).tool("search").connect().await;
+        connection.tool_prefix = Some(name.to_string());
+        connection
+    }
+
+    #[tokio::test]
+    async fn tool_prefix_keeps_tools_with_the_same_name_apart() {
+        let (one, two) = (
+            prefixed_connection("one").await,
+            prefixed_connection("two").await,
+        );
+
+        let names = |tools: Vec<McpConnectionTool>| {
+            tools.into_iter().map(|tool| tool.name).collect::<Vec<_>>()
+        };
+        assert_eq!(names(one.get_tools().await.unwrap()), vec!["one__search"]);
+        assert_eq!(names(two.get_tools().await.unwrap()), vec!["two__search"]);
+
+        let model = MockCompletionModel::new([tool_calls(&["two__search", "one__search"])]);
+        let mut agent = test_agent(model, []).mcp_server(one).mcp_server(two);
+        let result = agent
+            .run_completion(vec![Message::user("search both")])
+            .await
+            .unwrap();
+
+        assert_eq!(result.tools_called, vec!["two__search", "one__search"]);
+        let history = serde_json::to_string(&result.messages).unwrap();
+        let (two_output, one_output) = (
+            history.find("two search output").unwrap(),
+            history.find("one search output").unwrap(),
+        );
+        assert!(two_output < one_output);
+    }
+}
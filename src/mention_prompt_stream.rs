@@ -0,0 +1,68 @@
+use crate::api::generated::types::ResolvedMessage;
+use crate::completion_evaluated_prompt::CompletionEvaluatedPrompt;
+use crate::mcp_server::McpServerConnection;
+use futures::{Stream, stream};
+use std::time::Duration;
+use tokio::time::sleep;
+use tracing::warn;
+
+///
+/// Creates a prompt stream that only yields a prompt when another agent mentions this agent.
+///
+/// The Coral server is polled with [`McpServerConnection::wait_for_mentions`], which blocks until
+/// this agent is mentioned or the server's timeout is reached.  A poll that returns no mentions (or
+/// fails) is followed by a delay of `poll_interval`, which doubles with every further empty poll,
+/// up to `max_backoff`.  The delay is reset when a mention arrives.
+///
+/// Each yielded prompt is `prompt` followed by the messages that mentioned this agent.  The stream
+/// ends after `max_reps` prompts.  This is an event-driven alternative to
+/// [`crate::repeating_prompt_stream::repeating_prompt_stream`], which saves iterations and model
+/// calls while the agent is idle.
+pub fn mention_prompt_stream(
+    connection: McpServerConnection,
+    prompt: impl Into<CompletionEvaluatedPrompt>,
+    poll_interval: Duration,
+    max_backoff: Duration,
+    max_reps: usize,
+) -> impl Stream<Item = CompletionEvaluatedPrompt> {
+    stream::unfold(
+        (connection, prompt.into(), 0),
+        move |(connection, prompt, reps)| {
+            Box::pin(async move {
+                if reps >= max_reps {
+                    return None;
+                }
+
+                let mut backoff = Duration::ZERO;
+                let messages = loop {
+                    if !backoff.is_zero() {
+                        sleep(backoff).await;
+                    }
+
+                    match connection.wait_for_mentions().await {
+                        Ok(messages) if !messages.is_empty() => break messages,
+                        Ok(_) => {}
+                        Err(e) => warn!("Failed to wait for mentions: {e}"),
+                    }
+
+                    backoff = (backoff * 2).max(poll_interval).min(max_backoff);
+                };
+
+                let item = messages.iter().fold(prompt.clone(), |prompt, message| {
+                    prompt.string(format_mention(message))
+                });
+
+                Some((item, (connection, prompt, reps + 1)))
+            })
+        },
+    )
+}
+
+///
+/// Formats a message that mentioned this agent for use in a prompt
+fn format_mention(message: &ResolvedMessage) -> String {
+    format!(
+        "{} mentioned you in thread \"{}\" (thread ID: {}): {}",
+        message.sender_id, message.thread_name, message.thread_id, message.content
+    )
+}
@@ -0,0 +1,159 @@
+use futures::stream;
+use rig::OneOrMany;
+use rig::completion::{
+    AssistantContent, CompletionError, CompletionModel, CompletionRequest, CompletionResponse,
+    Message, Usage,
+};
+use rig::streaming::{RawStreamingChoice, StreamingCompletionResponse};
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+///
+/// A completion model that returns pre-determined responses, in order, without contacting a model
+/// provider.  This is intended for testing agents deterministically, for example, by replaying a
+/// [`crate::session_recorder::SessionRecording`].
+///
+/// Each completion request consumes one response.  A response can be limited to a request with
+/// particular messages, see [`MockCompletionModel::push_expected_response`].  When every response
+/// has been consumed, completion requests fail with [`CompletionError::ProviderError`].  Clones
+/// share the same queue of responses.
+#[derive(Clone, Default)]
+pub struct MockCompletionModel {
+    responses: Arc<Mutex<VecDeque<QueuedResponse>>>,
+}
+
+///
+/// A queued response with the messages it expects the completion request to have, if any
+type QueuedResponse = (Option<Vec<Message>>, MockResponse);
+
+///
+/// A queued response and the token usage to report with it, or a queued error
+type MockResponse = Result<(OneOrMany<AssistantContent>, Usage), CompletionError>;
+
+impl MockCompletionModel {
+    ///
+    /// Creates a new mock completion model that will return the given responses in order
+    pub fn new(responses: impl IntoIterator<Item = OneOrMany<AssistantContent>>) -> Self {
+        let model = Self::default();
+        for response in responses {
+            model.push_response(response, Usage::default());
+        }
+
+        model
+    }
+
+    ///
+    /// Adds a response to the end of the queue
+    pub fn push_response(&self, choice: OneOrMany<AssistantContent>, usage: Usage) {
+        self.responses
+            .lock()
+            .unwrap()
+            .push_back((None, Ok((choice, usage))));
+    }
+
+    ///
+    /// Adds a response to the end of the queue that is only returned for a completion request
+    /// with exactly these messages (the chat history followed by the prompt).  A completion
+    /// request with any other messages consumes the response and fails.
+    pub fn push_expected_response(
+        &self,
+        messages: Vec<Message>,
+        choice: OneOrMany<AssistantContent>,
+        usage: Usage,
+    ) {
+        self.responses
+            .lock()
+            .unwrap()
+            .push_back((Some(messages), Ok((choice, usage))));
+    }
+
+    ///
+    /// Adds an error to the end of the queue.  The completion request that consumes it will fail
+    /// with this error, which is useful for testing retries and fallbacks.
+    pub fn push_error(&self, error: CompletionError) {
+        self.responses.lock().unwrap().push_back((None, Err(error)));
+    }
+
+    ///
+    /// Adds a plain text response to the end of the queue
+    pub fn push_text(&self, text: impl Into<String>) {
+        self.push_response(
+            OneOrMany::one(AssistantContent::text(text.into())),
+            Usage::default(),
+        );
+    }
+
+    ///
+    /// The number of responses that have not been consumed yet
+    pub fn remaining(&self) -> usize {
+        self.responses.lock().unwrap().len()
+    }
+
+    ///
+    /// Takes the next response from the queue, checking the request against the messages the
+    /// response expects, if any
+    fn next_response(&self, request: &CompletionRequest) -> MockResponse {
+        let Some((expected, response)) = self.responses.lock().unwrap().pop_front() else {
+            return Err(CompletionError::ProviderError(
+                "mock completion model has no more responses".into(),
+            ));
+        };
+
+        match expected {
+            Some(expected) if !request.chat_history.iter().eq(expected.iter()) => {
+                Err(CompletionError::ProviderError(format!(
+                    "completion request does not match the expected messages, expected {} but got {}",
+                    serde_json::to_string(&expected).unwrap_or_default(),
+                    serde_json::to_string(&request.chat_history).unwrap_or_default(),
+                )))
+            }
+            _ => response,
+        }
+    }
+}
+
+impl CompletionModel for MockCompletionModel {
+    type Response = ();
+    type StreamingResponse = ();
+
+    async fn completion(
+        &self,
+        request: CompletionRequest,
+    ) -> Result<CompletionResponse<Self::Response>, CompletionError> {
+        let (choice, usage) = self.next_response(&request)?;
+        Ok(CompletionResponse {
+            choice,
+            usage,
+            raw_response: (),
+        })
+    }
+
+    async fn stream(
+        &self,
+        request: CompletionRequest,
+    ) -> Result<StreamingCompletionResponse<Self::StreamingResponse>, CompletionError> {
+        let (choice, _) = self.next_response(&request)?;
+        let chunks = choice
+            .into_iter()
+            .map(|content| match content {
+                AssistantContent::Text(text) => RawStreamingChoice::Message(text.text),
+                AssistantContent::ToolCall(tool_call) => RawStreamingChoice::ToolCall {
+                    id: tool_call.id,
+                    call_id: tool_call.call_id,
+                    name: tool_call.function.name,
+                    arguments: tool_call.function.arguments,
+                },
+                AssistantContent::Reasoning(reasoning) => RawStreamingChoice::Reasoning {
+                    id: reasoning.id,
+                    reasoning: reasoning.reasoning.join(""),
+                },
+            })
+            .chain(std::iter::once(RawStreamingChoice::FinalResponse(())))
+            .map(Ok)
+            .collect::<Vec<_>>();
+
+        Ok(StreamingCompletionResponse::stream(Box::pin(stream::iter(
+            chunks,
+        ))))
+    }
+}
@@ -1,28 +1,233 @@
 use crate::completion_evaluated_prompt::CompletionEvaluatedPrompt;
+use crate::retry::RetryJitter;
 use futures::{Stream, stream};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU32, Ordering};
 use std::time::Duration;
 use tokio::time::sleep;
 
+///
+/// Configures how the delay of a [`backoff_prompt_stream`] grows while the agent is idle
+#[derive(Debug, Clone, Copy)]
+pub struct PromptBackoff {
+    base_delay: Duration,
+    max_delay: Duration,
+    jitter: RetryJitter,
+}
+
+impl PromptBackoff {
+    ///
+    /// The delay between prompts is `base_delay` while the agent is working, and doubles for
+    /// every consecutive idle prompt iteration, up to `max_delay`
+    pub fn new(base_delay: Duration, max_delay: Duration) -> Self {
+        Self {
+            base_delay,
+            max_delay: max_delay.max(base_delay),
+            jitter: RetryJitter::None,
+        }
+    }
+
+    ///
+    /// Sets how randomness is added to the delay, so that many idle agents do not all prompt at
+    /// the same time.  Default is [`RetryJitter::None`].
+    pub fn jitter(mut self, jitter: RetryJitter) -> Self {
+        self.jitter = jitter;
+        self
+    }
+
+    ///
+    /// The delay before the next prompt after `idle` consecutive idle iterations, given the
+    /// previous delay
+    fn delay(&self, idle: u32, previous: Duration) -> Duration {
+        let delay = self
+            .base_delay
+            .saturating_mul(2u32.saturating_pow(idle))
+            .min(self.max_delay);
+
+        self.jitter
+            .apply(delay, previous, self.base_delay, self.max_delay)
+    }
+}
+
+///
+/// Tells a [`backoff_prompt_stream`] whether prompt iterations did any work.  Give this to
+/// [`crate::agent_loop::AgentLoop::prompt_feedback`], or call
+/// [`PromptFeedback::record_iteration`] from a custom loop.  Clones share their state.
+#[derive(Debug, Clone, Default)]
+pub struct PromptFeedback {
+    idle_iterations: Arc<AtomicU32>,
+}
+
+impl PromptFeedback {
+    ///
+    /// Records the outcome of a prompt iteration.  An iteration that used no tools is idle and
+    /// increases the delay before the next prompt, while one that used tools resets it.
+    pub fn record_iteration(&self, tools_used: u32) {
+        if tools_used == 0 {
+            let _ = self
+                .idle_iterations
+                .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |x| {
+                    Some(x.saturating_add(1))
+                });
+        } else {
+            self.idle_iterations.store(0, Ordering::Relaxed);
+        }
+    }
+
+    ///
+    /// The number of consecutive idle prompt iterations
+    pub fn idle_iterations(&self) -> u32 {
+        self.idle_iterations.load(Ordering::Relaxed)
+    }
+}
+
+///
+/// The same as [`repeating_prompt_stream`], except the delay between prompts backs off while the
+/// agent is idle.  Polling agents that run on a fixed delay keep prompting the model (and calling
+/// the Coral server) when there is nothing to do; with this stream, every consecutive prompt
+/// iteration that uses no tools doubles the delay up to a cap, and an iteration that uses tools
+/// resets it.  See [`PromptBackoff`].
+///
+/// The stream cannot see the outcome of prompt iterations, so the returned [`PromptFeedback`]
+/// must be given to the agent loop:
+///
+/// ```ignore
+/// let backoff = PromptBackoff::new(Duration::from_secs(1), Duration::from_secs(60))
+///     .jitter(RetryJitter::Full);
+/// let (feedback, prompts) = backoff_prompt_stream(prompt, backoff, usize::MAX);
+/// AgentLoop::new(agent, prompts)
+///     .prompt_feedback(feedback)
+///     .execute()
+///     .await?;
+/// ```
+pub fn backoff_prompt_stream(
+    prompt: impl Into<CompletionEvaluatedPrompt>,
+    backoff: PromptBackoff,
+    max_reps: usize,
+) -> (
+    PromptFeedback,
+    impl Stream<Item = CompletionEvaluatedPrompt>,
+) {
+    let feedback = PromptFeedback::default();
+    let stream = stream::unfold(
+        (prompt.into(), feedback.clone(), 0, backoff.base_delay),
+        move |(prompt, feedback, reps, previous)| async move {
+            if reps >= max_reps {
+                return None;
+            }
+
+            let delay = if reps > 0 {
+                let delay = backoff.delay(feedback.idle_iterations(), previous);
+                sleep(delay).await;
+                delay
+            } else {
+                previous
+            };
+
+            Some((prompt.clone(), (prompt, feedback, reps + 1, delay)))
+        },
+    );
+
+    (feedback, stream)
+}
+
 pub fn repeating_prompt_stream(
     prompt: impl Into<CompletionEvaluatedPrompt>,
     delay: Option<Duration>,
     max_reps: usize,
 ) -> impl Stream<Item = CompletionEvaluatedPrompt> {
+    repeating_prompt_stream_inner(
+        prompt.into(),
+        delay,
+        max_reps,
+        None::<fn(CompletionEvaluatedPrompt) -> CompletionEvaluatedPrompt>,
+        || false,
+    )
+}
+
+///
+/// The same as [`repeating_prompt_stream`], except the prompt repeats until `until` returns true
+/// instead of a fixed number of times.  `until` is checked before every repetition (after the
+/// delay), so the stream ends without yielding once it is true.
+///
+/// ```ignore
+/// let stop = Arc::new(AtomicBool::new(false));
+/// let stream = repeating_prompt_stream_until(prompt, None, {
+///     let stop = stop.clone();
+///     move || stop.load(Ordering::Relaxed)
+/// });
+/// ```
+pub fn repeating_prompt_stream_until(
+    prompt: impl Into<CompletionEvaluatedPrompt>,
+    delay: Option<Duration>,
+    until: impl Fn() -> bool,
+) -> impl Stream<Item = CompletionEvaluatedPrompt> {
+    repeating_prompt_stream_inner(
+        prompt.into(),
+        delay,
+        usize::MAX,
+        None::<fn(CompletionEvaluatedPrompt) -> CompletionEvaluatedPrompt>,
+        until,
+    )
+}
+
+///
+/// The same as [`repeating_prompt_stream`], except the final repetition's prompt is passed through
+/// `on_final` before it is yielded.  This gives the agent a chance to do something special on its
+/// last turn (e.g. summarise its work or hand off to another agent) instead of stopping abruptly.
+///
+/// ```ignore
+/// repeating_prompt_stream_with_final(prompt, None, 10, |prompt| {
+///     prompt.string("This is your final turn, wrap up any outstanding work.")
+/// })
+/// ```
+pub fn repeating_prompt_stream_with_final(
+    prompt: impl Into<CompletionEvaluatedPrompt>,
+    delay: Option<Duration>,
+    max_reps: usize,
+    on_final: impl FnOnce(CompletionEvaluatedPrompt) -> CompletionEvaluatedPrompt,
+) -> impl Stream<Item = CompletionEvaluatedPrompt> {
+    repeating_prompt_stream_inner(prompt.into(), delay, max_reps, Some(on_final), || false)
+}
+
+fn repeating_prompt_stream_inner<F, U>(
+    prompt: CompletionEvaluatedPrompt,
+    delay: Option<Duration>,
+    max_reps: usize,
+    on_final: Option<F>,
+    until: U,
+) -> impl Stream<Item = CompletionEvaluatedPrompt>
+where
+    F: FnOnce(CompletionEvaluatedPrompt) -> CompletionEvaluatedPrompt,
+    U: Fn() -> bool,
+{
     stream::unfold(
-        (prompt.into(), delay, max_reps, 0),
-        |(prompt, delay, max_reps, reps)| {
+        (prompt, delay, max_reps, 0, on_final, until),
+        |(prompt, delay, max_reps, reps, mut on_final, until)| {
             Box::pin(async move {
                 if reps >= max_reps {
                     return None;
                 }
 
-                if reps > 0 {
-                    if let Some(delay_duration) = delay {
-                        sleep(delay_duration).await;
-                    }
+                if reps > 0
+                    && let Some(delay_duration) = delay
+                {
+                    sleep(delay_duration).await;
                 }
 
-                Some((prompt.clone(), (prompt, delay, max_reps, reps + 1)))
+                if until() {
+                    return None;
+                }
+
+                let item = if reps + 1 == max_reps
+                    && let Some(on_final) = on_final.take()
+                {
+                    on_final(prompt.clone())
+                } else {
+                    prompt.clone()
+                };
+
+                Some((item, (prompt, delay, max_reps, reps + 1, on_final, until)))
             })
         },
     )
@@ -0,0 +1,72 @@
+use crate::error::Error;
+use crate::mcp_server::McpServerConnection;
+use rig::completion::ToolDefinition;
+use rig::tool::Tool;
+use rmcp::model::ResourceContents;
+use serde::Deserialize;
+use serde_json::json;
+
+///
+/// A tool that lets the model read resources from an MCP server on demand, instead of every
+/// resource being embedded in the preamble.  Pair this with
+/// [`crate::completion_evaluated_prompt::CompletionEvaluatedPrompt::resource_index`], which tells
+/// the model which resources exist without including their contents.
+///
+/// Add this tool to an agent with [`crate::agent::Agent::lazy_resources`].
+#[derive(Clone)]
+pub struct ReadResourceTool {
+    connection: McpServerConnection,
+}
+
+#[derive(Deserialize)]
+pub struct ReadResourceArgs {
+    uri: String,
+}
+
+impl ReadResourceTool {
+    ///
+    /// Creates a new tool that reads resources from the given MCP server
+    pub fn new(connection: McpServerConnection) -> Self {
+        Self { connection }
+    }
+}
+
+impl Tool for ReadResourceTool {
+    const NAME: &'static str = "read_resource";
+    type Error = Error;
+    type Args = ReadResourceArgs;
+    type Output = String;
+
+    async fn definition(&self, _prompt: String) -> ToolDefinition {
+        ToolDefinition {
+            name: Self::NAME.to_string(),
+            description: "Reads the contents of a resource by URI.  Only read resources that are \
+                needed to respond."
+                .to_string(),
+            parameters: json!({
+                "type": "object",
+                "properties": {
+                    "uri": {
+                        "type": "string",
+                        "description": "The URI of the resource to read"
+                    }
+                },
+                "required": ["uri"]
+            }),
+        }
+    }
+
+    async fn call(&self, args: Self::Args) -> Result<Self::Output, Self::Error> {
+        Ok(self
+            .connection
+            .read_resource(args.uri)
+            .await?
+            .into_iter()
+            .map(|contents| match contents {
+                ResourceContents::TextResourceContents { text, .. } => text,
+                ResourceContents::BlobResourceContents { blob, .. } => blob,
+            })
+            .collect::<Vec<_>>()
+            .join("\n"))
+    }
+}
@@ -0,0 +1,287 @@
+use rand::Rng;
+use rig::completion::CompletionError;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::warn;
+
+pub const DEFAULT_RETRY_BASE_DELAY: Duration = Duration::from_millis(500);
+pub const DEFAULT_RETRY_MAX_DELAY: Duration = Duration::from_secs(30);
+
+///
+/// A predicate deciding whether a completion error should be retried.  See
+/// [`RetryPolicy::retry_if`].
+pub type RetryPredicate = Arc<dyn Fn(&CompletionError) -> bool + Send + Sync>;
+
+///
+/// How randomness is added to the delay between retries.  Without jitter, agents that fail at the
+/// same time (for example, because the Coral server was briefly unavailable) also retry at the
+/// same time, which can cause synchronized retry storms.  See [`RetryPolicy::jitter`].
+///
+/// In the descriptions below, `delay` is the exponential delay `base_delay * 2^(n - 1)` capped at
+/// `max_delay`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum RetryJitter {
+    ///
+    /// No randomness, the delay before each retry is `delay`
+    #[default]
+    None,
+
+    ///
+    /// A random delay between zero and `delay`
+    Full,
+
+    ///
+    /// Half of `delay`, plus a random delay between zero and the other half
+    Equal,
+
+    ///
+    /// A random delay between `base_delay` and three times the previous delay, capped at
+    /// `max_delay`.  Delays are less correlated between agents than the other strategies, which
+    /// makes this well suited to many agents retrying against the same server.
+    Decorrelated,
+}
+
+impl RetryJitter {
+    ///
+    /// Adds randomness to `delay`, the exponential delay capped at `max_delay`.  `previous` is the
+    /// delay that was used last time, which is only used by [`RetryJitter::Decorrelated`].
+    pub(crate) fn apply(
+        self,
+        delay: Duration,
+        previous: Duration,
+        base_delay: Duration,
+        max_delay: Duration,
+    ) -> Duration {
+        let mut rng = rand::rng();
+        match self {
+            RetryJitter::None => delay,
+            RetryJitter::Full => rng.random_range(Duration::ZERO..=delay),
+            RetryJitter::Equal => delay / 2 + rng.random_range(Duration::ZERO..=delay / 2),
+            RetryJitter::Decorrelated => {
+                let upper = previous.saturating_mul(3).max(base_delay);
+                rng.random_range(base_delay..=upper).min(max_delay)
+            }
+        }
+    }
+}
+
+///
+/// Configures how a failed completion request is retried, see
+/// [`crate::agent::Agent::retry_policy`].  Only the request to the model is retried, tool calls
+/// are never run more than once.
+///
+/// The delay before retry `n` (starting at 1) is `base_delay * 2^(n - 1)`, capped at `max_delay`,
+/// with randomness added according to [`RetryPolicy::jitter`].
+#[derive(Clone)]
+pub struct RetryPolicy {
+    max_attempts: u32,
+    base_delay: Duration,
+    max_delay: Duration,
+    jitter: RetryJitter,
+    retry_if: RetryPredicate,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self::none()
+    }
+}
+
+impl RetryPolicy {
+    ///
+    /// Creates a retry policy that makes at most `max_attempts` attempts (including the first),
+    /// retrying errors that [`RetryPolicy::is_transient`] considers transient.
+    pub fn new(max_attempts: u32) -> Self {
+        Self {
+            max_attempts: max_attempts.max(1),
+            base_delay: DEFAULT_RETRY_BASE_DELAY,
+            max_delay: DEFAULT_RETRY_MAX_DELAY,
+            jitter: RetryJitter::default(),
+            retry_if: Arc::new(Self::is_transient),
+        }
+    }
+
+    ///
+    /// A retry policy that never retries.  This is the default.
+    pub fn none() -> Self {
+        Self::new(1)
+    }
+
+    ///
+    /// The delay before the first retry.  Default is [`DEFAULT_RETRY_BASE_DELAY`].
+    pub fn base_delay(mut self, base_delay: Duration) -> Self {
+        self.base_delay = base_delay;
+        self
+    }
+
+    ///
+    /// The maximum delay between two attempts.  Default is [`DEFAULT_RETRY_MAX_DELAY`].
+    pub fn max_delay(mut self, max_delay: Duration) -> Self {
+        self.max_delay = max_delay;
+        self
+    }
+
+    ///
+    /// Sets how randomness is added to the delay between retries.  Default is
+    /// [`RetryJitter::None`].
+    pub fn jitter(mut self, jitter: RetryJitter) -> Self {
+        self.jitter = jitter;
+        self
+    }
+
+    ///
+    /// Replaces the predicate deciding which errors are retried.  Default is
+    /// [`RetryPolicy::is_transient`].
+    pub fn retry_if(
+        mut self,
+        retry_if: impl Fn(&CompletionError) -> bool + Send + Sync + 'static,
+    ) -> Self {
+        self.retry_if = Arc::new(retry_if);
+        self
+    }
+
+    ///
+    /// Returns true for errors that are likely to succeed if retried: timeouts, connection errors,
+    /// rate limits (HTTP 429) and server errors (HTTP 5xx).  Errors reported by the provider are
+    /// considered transient unless they look like an authentication or permission error.
+    pub fn is_transient(error: &CompletionError) -> bool {
+        match error {
+            CompletionError::HttpError(e) => {
+                e.is_timeout()
+                    || e.is_connect()
+                    || e.status()
+                        .is_none_or(|status| status.as_u16() == 429 || status.is_server_error())
+            }
+            CompletionError::ProviderError(message) | CompletionError::ResponseError(message) => {
+                let message = message.to_lowercase();
+                ![
+                    "401",
+                    "403",
+                    "unauthorized",
+                    "forbidden",
+                    "authentication",
+                    "permission",
+                    "api key",
+                    "api_key",
+                ]
+                .iter()
+                .any(|pattern| message.contains(pattern))
+            }
+            _ => false,
+        }
+    }
+
+    ///
+    /// The delay before the given retry (starting at 1), given the delay before the previous retry
+    fn delay(&self, retry: u32, previous: Duration) -> Duration {
+        let delay = self
+            .base_delay
+            .saturating_mul(2u32.saturating_pow(retry - 1))
+            .min(self.max_delay);
+
+        self.jitter
+            .apply(delay, previous, self.base_delay, self.max_delay)
+    }
+
+    ///
+    /// Runs `attempt` until it succeeds, fails with an error that should not be retried, or the
+    /// maximum number of attempts has been made
+    pub(crate) async fn run<T, F>(
+        &self,
+        mut attempt: impl FnMut() -> F,
+    ) -> Result<T, CompletionError>
+    where
+        F: Future<Output = Result<T, CompletionError>>,
+    {
+        let mut attempts = 1;
+        let mut delay = self.base_delay;
+        loop {
+            match attempt().await {
+                Err(e) if attempts < self.max_attempts && (self.retry_if)(&e) => {
+                    delay = self.delay(attempts, delay);
+                    warn!(
+                        "Completion failed ({e}), retrying in {delay:?} [attempt {attempts}/{}]",
+                        self.max_attempts
+                    );
+
+                    tokio::time::sleep(delay).await;
+                    attempts += 1;
+                }
+                res => return res,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock_completion_model::MockCompletionModel;
+    use crate::test_util::test_agent;
+    use rig::completion::Message;
+
+    fn provider_error(message: &str) -> CompletionError {
+        CompletionError::ProviderError(message.to_string())
+    }
+
+    fn fast_policy(max_attempts: u32) -> RetryPolicy {
+        RetryPolicy::new(max_attempts).base_delay(Duration::from_millis(1))
+    }
+
+    #[tokio::test]
+    async fn retries_until_success() {
+        let model = MockCompletionModel::default();
+        model.push_error(provider_error("overloaded"));
+        model.push_error(provider_error("overloaded"));
+        model.push_text("ok");
+
+        let mut agent = test_agent(model.clone(), []).retry_policy(fast_policy(3));
+        let res = agent
+            .run_completion(vec![Message::user("go")])
+            .await
+            .unwrap();
+
+        assert_eq!(res.texts, vec!["ok".to_string()]);
+        assert_eq!(model.remaining(), 0);
+    }
+
+    #[tokio::test]
+    async fn gives_up_after_max_attempts() {
+        let model = MockCompletionModel::default();
+        model.push_error(provider_error("overloaded"));
+        model.push_error(provider_error("overloaded"));
+        model.push_text("ok");
+
+        let mut agent = test_agent(model.clone(), []).retry_policy(fast_policy(2));
+        let res = agent.run_completion(vec![Message::user("go")]).await;
+
+        assert!(res.is_err());
+        assert_eq!(model.remaining(), 1);
+    }
+
+    #[tokio::test]
+    async fn does_not_retry_permanent_errors() {
+        let model = MockCompletionModel::default();
+        model.push_error(provider_error("401 unauthorized"));
+        model.push_text("ok");
+
+        let mut agent = test_agent(model.clone(), []).retry_policy(fast_policy(3));
+        let res = agent.run_completion(vec![Message::user("go")]).await;
+
+        assert!(res.is_err());
+        assert_eq!(model.remaining(), 1);
+    }
+
+    #[test]
+    fn delay_doubles_up_to_max() {
+        let policy = RetryPolicy::new(5)
+            .base_delay(Duration::from_millis(10))
+            .max_delay(Duration::from_millis(25));
+
+        let delays = (1..=3)
+            .map(|retry| policy.delay(retry, Duration::ZERO))
+            .collect::<Vec<_>>();
+
+        assert_eq!(delays, [10, 20, 25].map(Duration::from_millis).to_vec());
+    }
+}
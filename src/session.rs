@@ -0,0 +1,195 @@
+use crate::agent_options::AgentOptionsBuilder;
+use crate::api::generated::Client;
+use crate::api::generated::types::{
+    AgentGraphRequest, CustomTool, GraphAgentRequest, PublicRegistryAgent, SessionRequest,
+};
+use crate::error::Error;
+use std::collections::{HashMap, HashSet};
+
+impl Client {
+    ///
+    /// Validates a session request without creating a session.
+    ///
+    /// The Coral server has no dry-run mode for `create_session`, so the request is checked
+    /// client-side against the agents available on the server (see
+    /// [`validate_agent_graph`]).  This is useful for checking agent graphs in CI without
+    /// allocating real sessions.  Note that a request passing validation can still be rejected by
+    /// the server, for example, if a runtime is unavailable.
+    pub async fn validate_session(&self, request: &SessionRequest) -> Result<(), Error> {
+        let registry = self
+            .get_available_agents()
+            .await
+            .map_err(|e| Error::RegistryError(Box::new(e)))?
+            .into_inner();
+
+        let errors = validate_agent_graph(&request.agent_graph_request, &registry);
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(Error::InvalidSession(errors))
+        }
+    }
+}
+
+///
+/// Checks an agent graph against a registry, returning every problem found.  An empty list means
+/// the agent graph is valid.  The following is checked:
+/// - agent names are unique and not empty
+/// - every agent exists in the registry with the requested version
+/// - every agent's options are defined, have the correct type, and that no required option is
+///   missing
+/// - every group member is an agent in the graph
+/// - every custom tool an agent has access to is defined in the graph
+pub fn validate_agent_graph(
+    graph: &AgentGraphRequest,
+    registry: &[PublicRegistryAgent],
+) -> Vec<String> {
+    let mut errors = Vec::new();
+
+    if graph.agents.is_empty() {
+        errors.push("the agent graph contains no agents".to_string());
+    }
+
+    let mut names = HashSet::new();
+    for agent in &graph.agents {
+        let name = agent.name.as_str();
+        if name.is_empty() {
+            errors.push(format!("agent \"{}\" has an empty name", agent.id.name));
+        } else if !names.insert(name) {
+            errors.push(format!("agent name \"{name}\" is used more than once"));
+        }
+
+        match registry
+            .iter()
+            .find(|x| x.id.name == agent.id.name && x.id.version == agent.id.version)
+        {
+            Some(definition) => {
+                let options = agent.options.iter().try_fold(
+                    AgentOptionsBuilder::from_definitions(definition.options.clone()),
+                    |builder, (option, value)| builder.set(option.clone(), value.clone()),
+                );
+
+                if let Err(e) = options.and_then(|options| options.build()) {
+                    errors.push(format!("agent \"{name}\": {e}"));
+                }
+            }
+            None => errors.push(format!(
+                "agent \"{name}\": {}:{} is not available in the registry",
+                agent.id.name, agent.id.version
+            )),
+        }
+
+        for tool in &agent.custom_tool_access {
+            if !graph.custom_tools.contains_key(tool) {
+                errors.push(format!(
+                    "agent \"{name}\": custom tool \"{tool}\" is not defined"
+                ));
+            }
+        }
+    }
+
+    errors.extend(group_errors(graph, &names));
+    errors
+}
+
+///
+/// Checks that every group member is one of the named agents
+fn group_errors(graph: &AgentGraphRequest, names: &HashSet<&str>) -> Vec<String> {
+    graph
+        .groups
+        .iter()
+        .flatten()
+        .filter(|member| !names.contains(member.as_str()))
+        .map(|member| format!("group member \"{member}\" is not an agent in the graph"))
+        .collect()
+}
+
+impl AgentGraphRequest {
+    ///
+    /// Creates a builder for an agent graph, see [`AgentGraphBuilder`]
+    pub fn builder() -> AgentGraphBuilder {
+        AgentGraphBuilder::default()
+    }
+}
+
+///
+/// Builds an [`AgentGraphRequest`], checking that every group member is an agent in the graph.
+///
+/// A group is a set of agents, referenced by their names in the graph, that can communicate with
+/// each other.  An agent can be a member of more than one group.  All blocking agents in a group
+/// (see [`GraphAgentRequest::blocking`]) must be instantiated before the group can communicate.
+///
+/// ```ignore
+/// let graph = AgentGraphRequest::builder()
+///     .add_agent(researcher)
+///     .add_agent(writer)
+///     .group(["researcher", "writer"])
+///     .build()?;
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct AgentGraphBuilder {
+    agents: Vec<GraphAgentRequest>,
+    custom_tools: HashMap<String, CustomTool>,
+    groups: Vec<Vec<String>>,
+}
+
+impl AgentGraphBuilder {
+    ///
+    /// Adds an agent to the graph.  The agent is referenced in groups by its
+    /// [`GraphAgentRequest::name`].
+    pub fn add_agent(mut self, agent: GraphAgentRequest) -> Self {
+        self.agents.push(agent);
+        self
+    }
+
+    ///
+    /// Defines a custom tool that agents in the graph can be given access to
+    pub fn custom_tool(mut self, name: impl Into<String>, tool: CustomTool) -> Self {
+        self.custom_tools.insert(name.into(), tool);
+        self
+    }
+
+    ///
+    /// Adds a group containing the named agents
+    pub fn group<I, S>(mut self, members: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.groups
+            .push(members.into_iter().map(Into::into).collect());
+        self
+    }
+
+    ///
+    /// Builds the agent graph.  Returns [`Error::InvalidSession`] listing every group member that
+    /// is not an agent in the graph, and every empty group.
+    pub fn build(self) -> Result<AgentGraphRequest, Error> {
+        let graph = AgentGraphRequest {
+            agents: self.agents,
+            custom_tools: self.custom_tools,
+            groups: self.groups,
+        };
+
+        let names = graph
+            .agents
+            .iter()
+            .map(|agent| agent.name.as_str())
+            .collect::<HashSet<_>>();
+
+        let mut errors = graph
+            .groups
+            .iter()
+            .enumerate()
+            .filter(|(_, group)| group.is_empty())
+            .map(|(i, _)| format!("group {i} has no members"))
+            .collect::<Vec<_>>();
+
+        errors.extend(group_errors(&graph, &names));
+        if errors.is_empty() {
+            Ok(graph)
+        } else {
+            Err(Error::InvalidSession(errors))
+        }
+    }
+}
@@ -0,0 +1,296 @@
+use crate::error::Error;
+use crate::mock_completion_model::MockCompletionModel;
+use rig::OneOrMany;
+use rig::completion::{AssistantContent, Message, Usage};
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+///
+/// Records every completion an [`crate::agent::Agent`] makes: the prompt, the model's response and
+/// every tool call with its result.  Attach a recorder with
+/// [`crate::agent::Agent::session_recorder`], run the agent as normal, then save the recording.
+///
+/// A saved recording can be replayed deterministically by running an agent with the model from
+/// [`SessionRecording::mock_model`] and the tool outputs from [`SessionRecording::tool_replay`],
+/// which enables golden-file testing of agent behaviour without a live model provider or live
+/// tools.
+///
+/// Clones share the same recording.
+#[derive(Clone, Default)]
+pub struct SessionRecorder {
+    recording: Arc<Mutex<SessionRecording>>,
+}
+
+///
+/// A recorded agent session.  See [`SessionRecorder`].
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct SessionRecording {
+    /// Every completion made during the session, in order
+    pub completions: Vec<RecordedCompletion>,
+}
+
+///
+/// One recorded completion
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RecordedCompletion {
+    /// Every message sent with the completion request, ending with the prompt
+    pub messages: Vec<Message>,
+
+    /// The response from the completion model
+    pub response: OneOrMany<AssistantContent>,
+
+    /// The token usage reported by the completion model
+    pub usage: Usage,
+
+    /// The tool calls made as a result of this completion, in order
+    pub tool_calls: Vec<RecordedToolCall>,
+}
+
+///
+/// One recorded tool call
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RecordedToolCall {
+    /// The name of the tool
+    pub name: String,
+
+    /// The arguments passed to the tool
+    pub arguments: serde_json::Value,
+
+    /// The output of the tool
+    pub output: String,
+}
+
+impl SessionRecorder {
+    ///
+    /// Creates a new, empty session recorder
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    ///
+    /// Records a completion
+    pub(crate) fn record(&self, completion: RecordedCompletion) {
+        self.recording.lock().unwrap().completions.push(completion);
+    }
+
+    ///
+    /// Returns a copy of everything recorded so far
+    pub fn recording(&self) -> SessionRecording {
+        self.recording.lock().unwrap().clone()
+    }
+
+    ///
+    /// Saves everything recorded so far to a JSON file
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<(), Error> {
+        self.recording().save(path)
+    }
+}
+
+impl SessionRecording {
+    ///
+    /// Loads a recording from a JSON file created by [`SessionRecording::save`]
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, Error> {
+        let json = std::fs::read(path).map_err(Error::RecordingIoError)?;
+        serde_json::from_slice(&json).map_err(Error::RecordingFormatError)
+    }
+
+    ///
+    /// Saves this recording to a JSON file
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<(), Error> {
+        let json = serde_json::to_vec_pretty(self).map_err(Error::RecordingFormatError)?;
+        std::fs::write(path, json).map_err(Error::RecordingIoError)
+    }
+
+    ///
+    /// Creates a [`MockCompletionModel`] that returns the recorded responses in order.  Each
+    /// response is only returned for a completion request with the same messages as the recorded
+    /// request, so an agent that diverges from the recording fails instead of silently receiving
+    /// responses meant for other prompts.
+    pub fn mock_model(&self) -> MockCompletionModel {
+        let model = MockCompletionModel::default();
+        for completion in &self.completions {
+            model.push_expected_response(
+                completion.messages.clone(),
+                completion.response.clone(),
+                completion.usage,
+            );
+        }
+
+        model
+    }
+
+    ///
+    /// Creates a [`ToolReplay`] that returns the recorded tool outputs in order.  Give it to an
+    /// agent with [`crate::agent::Agent::tool_replay`] alongside [`Self::mock_model`] to replay
+    /// the session without running any tools.
+    pub fn tool_replay(&self) -> ToolReplay {
+        ToolReplay {
+            tool_calls: Arc::new(Mutex::new(
+                self.completions
+                    .iter()
+                    .flat_map(|completion| completion.tool_calls.iter().cloned())
+                    .collect(),
+            )),
+        }
+    }
+}
+
+///
+/// The recorded tool calls of a [`SessionRecording`], returned in place of running tools.  See
+/// [`SessionRecording::tool_replay`].
+///
+/// Clones share the same recorded tool calls.
+#[derive(Clone, Default)]
+pub struct ToolReplay {
+    tool_calls: Arc<Mutex<VecDeque<RecordedToolCall>>>,
+}
+
+impl ToolReplay {
+    ///
+    /// Returns the output of the next recorded tool call.  Fails if the tool call doesn't match
+    /// the recorded tool call, or if every recorded tool call has already been replayed.
+    pub(crate) fn next_output(&self, name: &str, arguments: &str) -> Result<String, Error> {
+        let Some(recorded) = self.tool_calls.lock().unwrap().pop_front() else {
+            return Err(Error::ReplayMismatch(format!(
+                "tool \"{name}\" was called after every recorded tool call was replayed"
+            )));
+        };
+
+        let arguments = serde_json::from_str::<serde_json::Value>(arguments)
+            .map_err(Error::RecordingFormatError)?;
+        if recorded.name != name || recorded.arguments != arguments {
+            return Err(Error::ReplayMismatch(format!(
+                "tool \"{name}\" was called with {arguments}, but the recording has tool \"{}\" called with {}",
+                recorded.name, recorded.arguments
+            )));
+        }
+
+        Ok(recorded.output)
+    }
+
+    ///
+    /// The number of recorded tool calls that have not been replayed yet
+    pub fn remaining(&self) -> usize {
+        self.tool_calls.lock().unwrap().len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{TestTool, test_agent, tool_calls};
+
+    fn responses() -> MockCompletionModel {
+        MockCompletionModel::new([
+            tool_calls(&["a", "b"]),
+            OneOrMany::one(AssistantContent::text("done")),
+        ])
+    }
+
+    #[tokio::test]
+    async fn replay_reproduces_recorded_session() {
+        let (a, b) = (TestTool::new("a"), TestTool::new("b"));
+        let recorder = SessionRecorder::new();
+        let mut agent =
+            test_agent(responses(), [a.clone(), b.clone()]).session_recorder(recorder.clone());
+
+        let first = agent
+            .run_completion(vec![Message::user("question")])
+            .await
+            .unwrap();
+        let recorded = agent.run_completion(first.messages).await.unwrap();
+        assert_eq!((a.calls(), b.calls()), (1, 1));
+
+        let path =
+            std::env::temp_dir().join(format!("coral-rs-recording-{}.json", std::process::id()));
+        recorder.save(&path).unwrap();
+        let recording = SessionRecording::load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(recording.completions.len(), 2);
+        assert_eq!(
+            recording.completions[0].messages,
+            vec![Message::user("question")]
+        );
+        assert_eq!(
+            recording.completions[1].messages.len(),
+            4,
+            "the prompt, the tool calls and a result for each tool call"
+        );
+
+        let model = recording.mock_model();
+        let tool_replay = recording.tool_replay();
+        let (a, b) = (TestTool::new("a"), TestTool::new("b"));
+        let mut agent =
+            test_agent(model.clone(), [a.clone(), b.clone()]).tool_replay(tool_replay.clone());
+
+        let first = agent
+            .run_completion(vec![Message::user("question")])
+            .await
+            .unwrap();
+        let replayed = agent.run_completion(first.messages).await.unwrap();
+
+        assert_eq!(replayed.messages, recorded.messages);
+        assert_eq!(replayed.texts, vec!["done".to_string()]);
+        assert_eq!((a.calls(), b.calls()), (0, 0), "tools are not run");
+        assert_eq!((model.remaining(), tool_replay.remaining()), (0, 0));
+    }
+
+    #[tokio::test]
+    async fn replay_rejects_a_different_prompt() {
+        let recorder = SessionRecorder::new();
+        let mut agent = test_agent(responses(), [TestTool::new("a"), TestTool::new("b")])
+            .session_recorder(recorder.clone());
+        agent
+            .run_completion(vec![Message::user("question")])
+            .await
+            .unwrap();
+
+        let recording = recorder.recording();
+        let a = TestTool::new("a");
+        let mut agent = test_agent(recording.mock_model(), [a.clone(), TestTool::new("b")])
+            .tool_replay(recording.tool_replay());
+
+        assert!(
+            agent
+                .run_completion(vec![Message::user("another question")])
+                .await
+                .is_err()
+        );
+        assert_eq!(a.calls(), 0);
+    }
+
+    #[test]
+    fn tool_replay_rejects_a_different_tool_call() {
+        let recording = SessionRecording {
+            completions: vec![RecordedCompletion {
+                messages: vec![Message::user("question")],
+                response: tool_calls(&["a"]),
+                usage: Usage::new(),
+                tool_calls: vec![RecordedToolCall {
+                    name: "a".to_string(),
+                    arguments: serde_json::json!({}),
+                    output: "a output".to_string(),
+                }],
+            }],
+        };
+
+        assert!(matches!(
+            recording.tool_replay().next_output("b", "{}"),
+            Err(Error::ReplayMismatch(_))
+        ));
+        assert!(matches!(
+            recording.tool_replay().next_output("a", r#"{"x":1}"#),
+            Err(Error::ReplayMismatch(_))
+        ));
+
+        let tool_replay = recording.tool_replay();
+        assert_eq!(tool_replay.next_output("a", "{}").unwrap(), "a output");
+        assert!(matches!(
+            tool_replay.next_output("a", "{}"),
+            Err(Error::ReplayMismatch(_))
+        ));
+    }
+}
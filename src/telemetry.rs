@@ -1,13 +1,46 @@
 use crate::api::generated::Client;
 use crate::api::generated::types::{
-    OpenAiMessage, RouteException, Telemetry, TelemetryMessages, TelemetryPost, TelemetryTarget,
+    JsonElement, OpenAiMessage, RouteException, Telemetry, TelemetryMessages, TelemetryPost,
+    TelemetryTarget,
 };
-use progenitor::progenitor_client::Error as ProgenitorError;
+use crate::error::AgentError;
+use flate2::Compression;
+use flate2::write::GzEncoder;
+use progenitor::progenitor_client::{ClientInfo, Error as ProgenitorError, encode_path};
+use regex::Regex;
+use reqwest::header::{ACCEPT, CONTENT_ENCODING, CONTENT_TYPE};
 use rig::completion::{CompletionModel, Document};
+use ring::hmac;
 use serde::Serialize;
+use std::collections::VecDeque;
+use std::io::Write;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 use thiserror::Error;
+use tokio::sync::Notify;
+use tokio::task::JoinHandle;
 use tracing::warn;
 
+///
+/// The default number of attempts made to send telemetry.  See
+/// [`crate::agent::Agent::telemetry_attempts`].
+pub const DEFAULT_TELEMETRY_ATTEMPTS: u32 = 3;
+
+///
+/// The header containing the signature of signed telemetry.  See
+/// [`crate::agent::Agent::telemetry_signing_key`].
+pub const TELEMETRY_SIGNATURE_HEADER: &str = "X-Coral-Signature";
+
+///
+/// The text that redacted parts of telemetry are replaced with.  See
+/// [`crate::agent::Agent::telemetry_redact`].
+pub const REDACTED: &str = "***";
+
+///
+/// The delay between attempts to send telemetry
+pub const TELEMETRY_RETRY_DELAY: Duration = Duration::from_secs(1);
+
 ///
 /// Telemetry is debugging information attached to Coral messages. The telemetry data should
 /// provide all relevant data that influenced a language model's completion response.
@@ -18,8 +51,20 @@ pub(crate) struct TelemetryRequest<'a, M: CompletionModel> {
     telemetry_mode: TelemetryMode,
     agent: &'a rig::agent::Agent<M>,
     model_description: String,
+    compression: bool,
+    strict: bool,
+    signing_key: Option<hmac::Key>,
+    redactions: Vec<Regex>,
+    attempts: u32,
+    response_format: Option<serde_json::Value>,
+    error: Option<AgentError>,
 }
 
+///
+/// The key in the telemetry's additional parameters under which the [`AgentError`] that
+/// terminated an agent is sent.  See [`crate::agent_loop::AgentLoop::report_fatal_error`].
+pub const AGENT_ERROR_PARAM: &str = "agentError";
+
 #[derive(Serialize, Copy, Clone)]
 pub enum TelemetryMode {
     ///
@@ -47,13 +92,25 @@ pub(crate) struct TelemetryIdentifier {
 #[derive(Debug, Error)]
 pub(crate) enum Error {
     #[error("failed to send telemetry {0}")]
-    Request(ProgenitorError<RouteException>),
+    Request(Box<ProgenitorError<RouteException>>),
 
     #[error("no targets provided")]
     EmptyTargets,
 
     #[error("no messages provided")]
     EmptyMessages,
+
+    #[error("messages could not be converted to the requested telemetry format")]
+    Conversion,
+
+    #[error("failed to serialize telemetry {0}")]
+    Serialize(serde_json::Error),
+
+    #[error("failed to compress telemetry {0}")]
+    Compression(std::io::Error),
+
+    #[error("failed to send telemetry {0}")]
+    RawRequest(reqwest::Error),
 }
 
 impl<'a, M: CompletionModel> TelemetryRequest<'a, M> {
@@ -71,6 +128,13 @@ impl<'a, M: CompletionModel> TelemetryRequest<'a, M> {
             telemetry_mode: TelemetryMode::OpenAI,
             agent,
             model_description: model_description.into(),
+            compression: false,
+            strict: false,
+            signing_key: None,
+            redactions: Vec::new(),
+            attempts: DEFAULT_TELEMETRY_ATTEMPTS,
+            response_format: None,
+            error: None,
         }
     }
 
@@ -79,6 +143,61 @@ impl<'a, M: CompletionModel> TelemetryRequest<'a, M> {
         self
     }
 
+    ///
+    /// If true, the telemetry body will be gzip compressed before it is sent
+    pub(crate) fn compression(mut self, compression: bool) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    ///
+    /// If true, a failure to convert messages into the requested format is an error, rather than
+    /// falling back to [`TelemetryMode::Generic`]
+    pub(crate) fn strict(mut self, strict: bool) -> Self {
+        self.strict = strict;
+        self
+    }
+
+    ///
+    /// If set, the telemetry body is signed with this key, see [`PreparedTelemetry::send_raw`]
+    pub(crate) fn signing_key(mut self, signing_key: Option<hmac::Key>) -> Self {
+        self.signing_key = signing_key;
+        self
+    }
+
+    ///
+    /// Matches of these patterns are replaced with [`REDACTED`] in the preamble and messages
+    /// (including tool arguments) of the telemetry
+    pub(crate) fn redact(mut self, redactions: Vec<Regex>) -> Self {
+        self.redactions = redactions;
+        self
+    }
+
+    ///
+    /// The maximum number of attempts made to send this telemetry.  Only network errors and server
+    /// errors (HTTP 5xx) are retried, other errors mean the telemetry was rejected and would be
+    /// rejected again.  Zero is treated as 1.
+    pub(crate) fn attempts(mut self, attempts: u32) -> Self {
+        self.attempts = attempts.max(1);
+        self
+    }
+
+    ///
+    /// The structured output response format the completion was constrained to, included in the
+    /// telemetry's additional parameters
+    pub(crate) fn response_format(mut self, response_format: Option<serde_json::Value>) -> Self {
+        self.response_format = response_format;
+        self
+    }
+
+    ///
+    /// The error that terminated the agent, included in the telemetry's additional parameters as
+    /// [`AGENT_ERROR_PARAM`]
+    pub(crate) fn error(mut self, error: AgentError) -> Self {
+        self.error = Some(error);
+        self
+    }
+
     ///
     /// Formats telemetry messages in OpenAI format.  Note that OpenAI's message type only provides
     /// try_into; a generic -> openai conversion can fail.  Any conversion failure here will result
@@ -113,24 +232,93 @@ impl<'a, M: CompletionModel> TelemetryRequest<'a, M> {
             .collect()
     }
 
+    ///
+    /// Replaces every match of the redaction patterns in a string with [`REDACTED`]
+    fn redact_str(redactions: &[Regex], text: &str) -> String {
+        redactions.iter().fold(text.to_string(), |text, pattern| {
+            pattern.replace_all(&text, REDACTED).into_owned()
+        })
+    }
+
+    ///
+    /// Redacts every string in a JSON value.  Object keys are left alone.
+    fn redact_json(redactions: &[Regex], value: &mut serde_json::Value) {
+        match value {
+            serde_json::Value::String(text) => *text = Self::redact_str(redactions, text),
+            serde_json::Value::Array(values) => values
+                .iter_mut()
+                .for_each(|value| Self::redact_json(redactions, value)),
+            serde_json::Value::Object(map) => map
+                .values_mut()
+                .for_each(|value| Self::redact_json(redactions, value)),
+            _ => {}
+        }
+    }
+
+    ///
+    /// Redacts the preamble and messages of formatted telemetry.  Messages are redacted through
+    /// their JSON representation, which covers message text and tool arguments in both formats.
+    fn redact_post(redactions: &[Regex], mut post: TelemetryPost) -> Result<TelemetryPost, Error> {
+        if redactions.is_empty() {
+            return Ok(post);
+        }
+
+        post.data.preamble = post
+            .data
+            .preamble
+            .map(|preamble| Self::redact_str(redactions, &preamble));
+
+        let mut messages = serde_json::to_value(&post.data.messages).map_err(Error::Serialize)?;
+        Self::redact_json(redactions, &mut messages);
+        post.data.messages = serde_json::from_value(messages).map_err(Error::Serialize)?;
+
+        Ok(post)
+    }
+
     ///
     /// Formats the Telemetry struct into data that the Coral server expects
-    async fn format(self) -> TelemetryPost {
-        TelemetryPost {
+    async fn format(self) -> Result<TelemetryPost, Error> {
+        let redactions = self.redactions.clone();
+        let post = self.format_unredacted().await?;
+        Self::redact_post(&redactions, post)
+    }
+
+    ///
+    /// Formats the Telemetry struct without applying [`TelemetryRequest::redact`]
+    async fn format_unredacted(self) -> Result<TelemetryPost, Error> {
+        Ok(TelemetryPost {
             targets: self.id.targets.clone(),
             data: Telemetry {
                 // additional_params: self.agent.additional_params.clone(),
-                additional_params: Default::default(),
+                additional_params: self
+                    .response_format
+                    .clone()
+                    .map(|format| ("response_format", format))
+                    .into_iter()
+                    .chain(
+                        self.error
+                            .as_ref()
+                            .and_then(|error| serde_json::to_value(error).ok())
+                            .map(|error| (AGENT_ERROR_PARAM, error)),
+                    )
+                    .filter_map(|(name, value)| match value {
+                        serde_json::Value::Object(value) => {
+                            Some((name.to_string(), JsonElement(value)))
+                        }
+                        _ => None,
+                    })
+                    .collect(),
                 max_tokens: self.agent.max_tokens.map(|t| t as i64),
                 model_description: self.model_description.clone(),
                 preamble: Some(self.agent.preamble.clone()),
                 resources: Self::convert_documents(self.agent.static_context.clone()),
-                temperature: self.agent.temperature.clone(),
+                temperature: self.agent.temperature,
                 tools: Self::convert_documents(
                     self.agent.tools.documents().await.unwrap_or_default(),
                 ),
                 messages: match self.telemetry_mode {
                     TelemetryMode::OpenAI => match self.messages_openai() {
+                        None if self.strict => return Err(Error::Conversion),
                         None => {
                             warn!(
                                 "OpenAI message format requested for telemetry but model response could not convert.  Falling back to generic format."
@@ -143,12 +331,19 @@ impl<'a, M: CompletionModel> TelemetryRequest<'a, M> {
                     TelemetryMode::None => panic!("cannot send telemetry in None mode"),
                 },
             },
-        }
+        })
     }
 
     ///
     /// Serializes the contained telemetry information and sends it to the Coral server
     pub(crate) async fn send(self) -> Result<(), Error> {
+        self.prepare().await?.send().await
+    }
+
+    ///
+    /// Formats the contained telemetry information so that it can be sent later, without a
+    /// reference to the agent
+    pub(crate) async fn prepare(self) -> Result<PreparedTelemetry, Error> {
         if self.id.targets.is_empty() {
             return Err(Error::EmptyTargets);
         }
@@ -157,14 +352,385 @@ impl<'a, M: CompletionModel> TelemetryRequest<'a, M> {
             return Err(Error::EmptyMessages);
         }
 
-        let url = self.url.clone();
-        let session_id = self.id.session_id.clone();
-        let data = self.format().await;
-        Client::new(url.as_str())
-            .add_telemetry(session_id.as_str(), &data)
+        Ok(PreparedTelemetry {
+            url: self.url.clone(),
+            session_id: self.id.session_id.clone(),
+            compression: self.compression,
+            signing_key: self.signing_key.clone(),
+            attempts: self.attempts,
+            data: self.format().await?,
+        })
+    }
+}
+
+///
+/// Formatted telemetry, ready to be sent to the Coral server
+pub(crate) struct PreparedTelemetry {
+    url: String,
+    session_id: String,
+    compression: bool,
+    signing_key: Option<hmac::Key>,
+    attempts: u32,
+    data: TelemetryPost,
+}
+
+impl Error {
+    ///
+    /// Returns true for errors that are likely to succeed if retried: network errors and server
+    /// errors
+    fn is_transient(&self) -> bool {
+        match self {
+            Error::Request(e) => match e.as_ref() {
+                ProgenitorError::CommunicationError(_) => true,
+                e => e.status().is_some_and(|status| status.is_server_error()),
+            },
+            Error::RawRequest(e) => e.status().is_none_or(|status| status.is_server_error()),
+            _ => false,
+        }
+    }
+}
+
+impl PreparedTelemetry {
+    ///
+    /// Sends this telemetry to the Coral server, retrying transient failures
+    pub(crate) async fn send(&self) -> Result<(), Error> {
+        let mut attempt = 1;
+        loop {
+            match self.send_once().await {
+                Err(e) if attempt < self.attempts && e.is_transient() => {
+                    warn!(
+                        "Failed to send telemetry ({e}), retrying in {TELEMETRY_RETRY_DELAY:?} [attempt {attempt}/{}]",
+                        self.attempts
+                    );
+
+                    tokio::time::sleep(TELEMETRY_RETRY_DELAY).await;
+                    attempt += 1;
+                }
+                res => return res,
+            }
+        }
+    }
+
+    ///
+    /// Makes a single attempt to send this telemetry to the Coral server
+    async fn send_once(&self) -> Result<(), Error> {
+        let client = Client::new(self.url.as_str());
+        if self.compression || self.signing_key.is_some() {
+            return self.send_raw(&client).await;
+        }
+
+        client
+            .add_telemetry(self.session_id.as_str(), &self.data)
             .await
-            .map_err(Error::Request)?;
+            .map_err(|e| Error::Request(Box::new(e)))?;
 
         Ok(())
     }
+
+    ///
+    /// Sends telemetry to the same endpoint as [`Client::add_telemetry`], but with a body that is
+    /// serialized here so that it can be gzip compressed (message histories containing images or
+    /// documents can be several megabytes of JSON, which compresses well) and signed.
+    ///
+    /// The signature is an HMAC-SHA256 of the body as sent (after compression), hex encoded in the
+    /// [`TELEMETRY_SIGNATURE_HEADER`] header as `sha256=<signature>`.
+    ///
+    /// The generated client has no way to change the body or headers of a request, so the path and
+    /// headers are repeated here.  The `send_raw_matches_generated_request` test fails if they no
+    /// longer match the request [`Client::add_telemetry`] makes.
+    async fn send_raw(&self, client: &Client) -> Result<(), Error> {
+        let mut body = serde_json::to_vec(&self.data).map_err(Error::Serialize)?;
+        if self.compression {
+            let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+            encoder.write_all(&body).map_err(Error::Compression)?;
+            body = encoder.finish().map_err(Error::Compression)?;
+        }
+
+        let mut request = client
+            .client()
+            .post(format!(
+                "{}/api/v1/telemetry/{}",
+                client.baseurl(),
+                encode_path(self.session_id.as_str())
+            ))
+            .header("api-version", Client::api_version())
+            .header(ACCEPT, "application/json")
+            .header(CONTENT_TYPE, "application/json");
+
+        if self.compression {
+            request = request.header(CONTENT_ENCODING, "gzip");
+        }
+
+        if let Some(key) = &self.signing_key {
+            let signature = hmac::sign(key, &body)
+                .as_ref()
+                .iter()
+                .map(|byte| format!("{byte:02x}"))
+                .collect::<String>();
+
+            request = request.header(TELEMETRY_SIGNATURE_HEADER, format!("sha256={signature}"));
+        }
+
+        request
+            .body(body)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map_err(Error::RawRequest)?;
+
+        Ok(())
+    }
+}
+
+///
+/// What a [`TelemetryQueue`] does when telemetry is queued while the queue is full.  See
+/// [`crate::agent::Agent::telemetry_queue`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TelemetryDropPolicy {
+    ///
+    /// Wait until there is space in the queue.  No telemetry is lost, but completions are delayed
+    /// while the Coral server is slow to accept telemetry.
+    Block,
+
+    ///
+    /// Drop the oldest queued telemetry to make space
+    DropOldest,
+
+    ///
+    /// Drop the telemetry being queued
+    DropNewest,
+}
+
+///
+/// A bounded queue of telemetry, sent to the Coral server by a background task.  The background
+/// task is aborted when the queue is dropped, discarding any telemetry that has not been sent, so
+/// the queue should be drained first, see [`TelemetryQueue::drain`].
+pub(crate) struct TelemetryQueue {
+    shared: Arc<TelemetryQueueShared>,
+    task: JoinHandle<()>,
+}
+
+struct TelemetryQueueShared {
+    items: Mutex<VecDeque<PreparedTelemetry>>,
+    capacity: usize,
+    policy: TelemetryDropPolicy,
+    item_added: Notify,
+    space_freed: Notify,
+    drained: Notify,
+    sending: AtomicBool,
+    dropped: AtomicU64,
+}
+
+impl TelemetryQueue {
+    pub(crate) fn new(capacity: usize, policy: TelemetryDropPolicy) -> Self {
+        let shared = Arc::new(TelemetryQueueShared {
+            items: Mutex::new(VecDeque::with_capacity(capacity)),
+            capacity: capacity.max(1),
+            policy,
+            item_added: Notify::new(),
+            space_freed: Notify::new(),
+            drained: Notify::new(),
+            sending: AtomicBool::new(false),
+            dropped: AtomicU64::new(0),
+        });
+
+        let task = tokio::spawn(Self::run(shared.clone()));
+        Self { shared, task }
+    }
+
+    ///
+    /// Sends queued telemetry, in order, until the queue is dropped
+    async fn run(shared: Arc<TelemetryQueueShared>) {
+        loop {
+            let telemetry = {
+                let mut items = shared.items.lock().unwrap();
+                let telemetry = items.pop_front();
+                shared.sending.store(telemetry.is_some(), Ordering::SeqCst);
+                telemetry
+            };
+
+            match telemetry {
+                Some(telemetry) => {
+                    shared.space_freed.notify_one();
+                    if let Err(e) = telemetry.send().await {
+                        warn!("Error sending telemetry: {e}");
+                    }
+                }
+                None => {
+                    shared.drained.notify_waiters();
+                    shared.item_added.notified().await
+                }
+            }
+        }
+    }
+
+    ///
+    /// Waits until every queued telemetry post has been sent (or has failed to send)
+    pub(crate) async fn drain(&self) {
+        let shared = &self.shared;
+        loop {
+            let drained = shared.drained.notified();
+            tokio::pin!(drained);
+            drained.as_mut().enable();
+
+            {
+                let items = shared.items.lock().unwrap();
+                if items.is_empty() && !shared.sending.load(Ordering::SeqCst) {
+                    return;
+                }
+            }
+
+            drained.await;
+        }
+    }
+
+    ///
+    /// Adds telemetry to the queue, applying the drop policy if the queue is full
+    pub(crate) async fn push(&self, telemetry: PreparedTelemetry) {
+        let shared = &self.shared;
+        loop {
+            {
+                let mut items = shared.items.lock().unwrap();
+                if items.len() < shared.capacity {
+                    items.push_back(telemetry);
+                    shared.item_added.notify_one();
+                    return;
+                }
+
+                match shared.policy {
+                    TelemetryDropPolicy::Block => {}
+                    TelemetryDropPolicy::DropOldest => {
+                        items.pop_front();
+                        items.push_back(telemetry);
+                        self.record_drop();
+                        return;
+                    }
+                    TelemetryDropPolicy::DropNewest => {
+                        self.record_drop();
+                        return;
+                    }
+                }
+            }
+
+            shared.space_freed.notified().await;
+        }
+    }
+
+    ///
+    /// The number of telemetry posts dropped because the queue was full
+    pub(crate) fn dropped(&self) -> u64 {
+        self.shared.dropped.load(Ordering::Relaxed)
+    }
+
+    fn record_drop(&self) {
+        let dropped = self.shared.dropped.fetch_add(1, Ordering::Relaxed) + 1;
+        warn!("Telemetry queue is full, dropped telemetry ({dropped} dropped so far)");
+    }
+}
+
+impl Drop for TelemetryQueue {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::generated::types::Telemetry;
+    use flate2::read::GzDecoder;
+    use std::collections::HashMap;
+    use std::io::Read;
+    use wiremock::matchers::{method, path_regex};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    fn prepared(url: &str) -> PreparedTelemetry {
+        PreparedTelemetry {
+            url: url.to_string(),
+            session_id: "session".to_string(),
+            compression: false,
+            signing_key: None,
+            attempts: 1,
+            data: TelemetryPost {
+                data: Telemetry {
+                    additional_params: HashMap::new(),
+                    max_tokens: None,
+                    messages: TelemetryMessages::Generic(Vec::new()),
+                    model_description: "test".to_string(),
+                    preamble: None,
+                    resources: Vec::new(),
+                    temperature: None,
+                    tools: Vec::new(),
+                },
+                targets: Vec::new(),
+            },
+        }
+    }
+
+    #[tokio::test]
+    async fn drain_waits_for_queued_telemetry() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path_regex("^/api/v1/telemetry/session$"))
+            .respond_with(ResponseTemplate::new(200).set_delay(Duration::from_millis(50)))
+            .expect(3)
+            .mount(&server)
+            .await;
+
+        let queue = TelemetryQueue::new(4, TelemetryDropPolicy::Block);
+        for _ in 0..3 {
+            queue.push(prepared(&server.uri())).await;
+        }
+
+        queue.drain().await;
+        assert_eq!(server.received_requests().await.unwrap().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn drain_returns_immediately_when_empty() {
+        let queue = TelemetryQueue::new(4, TelemetryDropPolicy::Block);
+        tokio::time::timeout(Duration::from_secs(1), queue.drain())
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn send_raw_matches_generated_request() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .respond_with(ResponseTemplate::new(200))
+            .mount(&server)
+            .await;
+
+        let mut generated = prepared(&server.uri());
+        generated.session_id = "session id/1".to_string();
+        generated.send_once().await.unwrap();
+
+        let mut raw = prepared(&server.uri());
+        raw.session_id = generated.session_id.clone();
+        raw.compression = true;
+        raw.signing_key = Some(hmac::Key::new(hmac::HMAC_SHA256, b"key"));
+        raw.send_once().await.unwrap();
+
+        let requests = server.received_requests().await.unwrap();
+        let (generated, raw) = (&requests[0], &requests[1]);
+        assert_eq!(raw.method, generated.method);
+        assert_eq!(raw.url, generated.url);
+        for header in ["api-version", "accept", "content-type"] {
+            assert_eq!(
+                raw.headers.get(header),
+                generated.headers.get(header),
+                "{header}"
+            );
+        }
+
+        assert_eq!(raw.headers.get("content-encoding").unwrap(), "gzip");
+        assert!(raw.headers.contains_key(TELEMETRY_SIGNATURE_HEADER));
+
+        let mut body = Vec::new();
+        GzDecoder::new(raw.body.as_slice())
+            .read_to_end(&mut body)
+            .unwrap();
+        assert_eq!(body, generated.body);
+    }
 }
@@ -0,0 +1,324 @@
+use crate::agent::Agent;
+use crate::mcp_server::McpServerConnection;
+use crate::mock_completion_model::MockCompletionModel;
+use rig::OneOrMany;
+use rig::completion::{AssistantContent, ToolDefinition};
+use rig::tool::Tool;
+use rmcp::model::{
+    AnnotateAble, CallToolRequestParam, CallToolResult, ClientInfo, Content, ListResourcesResult,
+    ListToolsResult, PaginatedRequestParam, RawResource, ReadResourceRequestParam,
+    ReadResourceResult, ResourceContents, ServerCapabilities, ServerInfo,
+};
+use rmcp::service::RequestContext;
+use rmcp::{ErrorData, RoleServer, ServerHandler, ServiceExt};
+use std::collections::{HashMap, VecDeque};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+use tokio::sync::{Mutex, MutexGuard};
+
+///
+/// Held by tests that read or change environment variables, see [`set_env`]
+static ENV_LOCK: Mutex<()> = Mutex::const_new(());
+
+///
+/// Sets (or removes, if None) environment variables for the duration of a test.  The returned
+/// guard must be held until the test no longer depends on them, so that tests using environment
+/// variables do not run at the same time.
+pub(crate) async fn set_env(vars: &[(&str, Option<&str>)]) -> MutexGuard<'static, ()> {
+    let guard = ENV_LOCK.lock().await;
+    for (name, value) in vars {
+        // SAFETY: every test that reads or changes the environment holds ENV_LOCK
+        unsafe {
+            match value {
+                Some(value) => std::env::set_var(name, value),
+                None => std::env::remove_var(name),
+            }
+        }
+    }
+
+    guard
+}
+
+///
+/// A tool for tests that counts how many times it was called.  It can be made to fail a number of
+/// times before succeeding, or to take a while to return.  Clones share the same call count.
+#[derive(Clone)]
+pub(crate) struct TestTool {
+    name: String,
+    calls: Arc<AtomicUsize>,
+    failures: usize,
+    delay: Duration,
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("test tool failed")]
+pub(crate) struct TestToolError;
+
+impl TestTool {
+    pub(crate) fn new(name: impl Into<String>) -> Self {
+        Self {
+            name: name.into(),
+            calls: Arc::default(),
+            failures: 0,
+            delay: Duration::ZERO,
+        }
+    }
+
+    ///
+    /// The first `failures` calls fail
+    pub(crate) fn failing(mut self, failures: usize) -> Self {
+        self.failures = failures;
+        self
+    }
+
+    ///
+    /// Every call sleeps for `delay` before returning
+    pub(crate) fn delay(mut self, delay: Duration) -> Self {
+        self.delay = delay;
+        self
+    }
+
+    ///
+    /// The number of times the tool has been called
+    pub(crate) fn calls(&self) -> usize {
+        self.calls.load(Ordering::SeqCst)
+    }
+}
+
+impl Tool for TestTool {
+    const NAME: &'static str = "test";
+    type Error = TestToolError;
+    type Args = serde_json::Value;
+    type Output = String;
+
+    fn name(&self) -> String {
+        self.name.clone()
+    }
+
+    async fn definition(&self, _prompt: String) -> ToolDefinition {
+        ToolDefinition {
+            name: self.name.clone(),
+            description: format!("The {} test tool", self.name),
+            parameters: serde_json::json!({ "type": "object" }),
+        }
+    }
+
+    async fn call(&self, _args: Self::Args) -> Result<Self::Output, Self::Error> {
+        let call = self.calls.fetch_add(1, Ordering::SeqCst) + 1;
+        tokio::time::sleep(self.delay).await;
+        if call <= self.failures {
+            return Err(TestToolError);
+        }
+
+        Ok(format!("{} output", self.name))
+    }
+}
+
+///
+/// A model response calling each of the named tools, with ids `call-0`, `call-1`, ...
+pub(crate) fn tool_calls(names: &[&str]) -> OneOrMany<AssistantContent> {
+    OneOrMany::many(
+        names
+            .iter()
+            .enumerate()
+            .map(|(i, name)| {
+                AssistantContent::tool_call(format!("call-{i}"), *name, serde_json::json!({}))
+            })
+            .collect::<Vec<_>>(),
+    )
+    .expect("at least one tool call")
+}
+
+///
+/// An agent using the mock model and the given tools
+pub(crate) fn test_agent(
+    model: MockCompletionModel,
+    tools: impl IntoIterator<Item = TestTool>,
+) -> Agent<MockCompletionModel> {
+    let mut builder = rig::agent::AgentBuilder::new(model);
+    for tool in tools {
+        builder = builder.tool(tool);
+    }
+
+    Agent::new(builder.build())
+}
+
+///
+/// An MCP server for tests with the given resources and tools, connected in-process.  Every tool
+/// returns `"{server} {tool} output"` unless a result was queued with
+/// [`FakeMcpServer::tool_result`].  Resource reads take [`FakeMcpServer::read_delay`], and the
+/// largest number of reads that were in progress at the same time is recorded.
+#[derive(Clone)]
+pub(crate) struct FakeMcpServer {
+    name: String,
+    resources: Vec<(String, String)>,
+    tools: Vec<String>,
+    tool_results: Arc<std::sync::Mutex<HashMap<String, VecDeque<String>>>>,
+    tool_calls: Arc<std::sync::Mutex<Vec<String>>>,
+    read_delay: Duration,
+    active_reads: Arc<AtomicUsize>,
+    max_active_reads: Arc<AtomicUsize>,
+}
+
+impl FakeMcpServer {
+    pub(crate) fn new(name: impl Into<String>) -> Self {
+        Self {
+            name: name.into(),
+            resources: Vec::new(),
+            tools: Vec::new(),
+            tool_results: Arc::default(),
+            tool_calls: Arc::default(),
+            read_delay: Duration::ZERO,
+            active_reads: Arc::default(),
+            max_active_reads: Arc::default(),
+        }
+    }
+
+    pub(crate) fn resource(mut self, uri: impl Into<String>, text: impl Into<String>) -> Self {
+        self.resources.push((uri.into(), text.into()));
+        self
+    }
+
+    pub(crate) fn tool(mut self, name: impl Into<String>) -> Self {
+        self.tools.push(name.into());
+        self
+    }
+
+    ///
+    /// Queues the result of the next call to the named tool, which is added to this server if it
+    /// was not already
+    pub(crate) fn tool_result(mut self, name: impl Into<String>, text: impl Into<String>) -> Self {
+        let name = name.into();
+        if !self.tools.contains(&name) {
+            self.tools.push(name.clone());
+        }
+
+        self.tool_results
+            .lock()
+            .unwrap()
+            .entry(name)
+            .or_default()
+            .push_back(text.into());
+        self
+    }
+
+    pub(crate) fn read_delay(mut self, read_delay: Duration) -> Self {
+        self.read_delay = read_delay;
+        self
+    }
+
+    ///
+    /// The largest number of resource reads that were in progress at the same time
+    pub(crate) fn max_concurrent_reads(&self) -> usize {
+        self.max_active_reads.load(Ordering::SeqCst)
+    }
+
+    ///
+    /// The names of the tools that were called, in order
+    pub(crate) fn tool_calls(&self) -> Vec<String> {
+        self.tool_calls.lock().unwrap().clone()
+    }
+
+    ///
+    /// Starts this server and connects to it
+    pub(crate) async fn connect(&self) -> McpServerConnection {
+        let (client, server) = tokio::io::duplex(64 * 1024);
+        let handler = self.clone();
+        tokio::spawn(async move {
+            if let Ok(service) = handler.serve(server).await {
+                let _ = service.waiting().await;
+            }
+        });
+
+        let running_service = ClientInfo::default()
+            .serve(client)
+            .await
+            .expect("fake MCP server initializes");
+
+        McpServerConnection::new(running_service, false, false, self.name.clone())
+    }
+}
+
+impl ServerHandler for FakeMcpServer {
+    fn get_info(&self) -> ServerInfo {
+        ServerInfo {
+            capabilities: ServerCapabilities::builder()
+                .enable_tools()
+                .enable_resources()
+                .build(),
+            ..Default::default()
+        }
+    }
+
+    async fn list_resources(
+        &self,
+        _request: Option<PaginatedRequestParam>,
+        _context: RequestContext<RoleServer>,
+    ) -> Result<ListResourcesResult, ErrorData> {
+        Ok(ListResourcesResult::with_all_items(
+            self.resources
+                .iter()
+                .map(|(uri, _)| RawResource::new(uri, uri).no_annotation())
+                .collect(),
+        ))
+    }
+
+    async fn read_resource(
+        &self,
+        request: ReadResourceRequestParam,
+        _context: RequestContext<RoleServer>,
+    ) -> Result<ReadResourceResult, ErrorData> {
+        let active = self.active_reads.fetch_add(1, Ordering::SeqCst) + 1;
+        self.max_active_reads.fetch_max(active, Ordering::SeqCst);
+        tokio::time::sleep(self.read_delay).await;
+        self.active_reads.fetch_sub(1, Ordering::SeqCst);
+
+        match self.resources.iter().find(|(uri, _)| *uri == request.uri) {
+            Some((uri, text)) => Ok(ReadResourceResult {
+                contents: vec![ResourceContents::text(text, uri)],
+            }),
+            None => Err(ErrorData::resource_not_found(request.uri, None)),
+        }
+    }
+
+    async fn list_tools(
+        &self,
+        _request: Option<PaginatedRequestParam>,
+        _context: RequestContext<RoleServer>,
+    ) -> Result<ListToolsResult, ErrorData> {
+        Ok(ListToolsResult::with_all_items(
+            self.tools
+                .iter()
+                .map(|name| {
+                    rmcp::model::Tool::new(
+                        name.clone(),
+                        format!("The {name} test tool"),
+                        Arc::new(serde_json::Map::new()),
+                    )
+                })
+                .collect(),
+        ))
+    }
+
+    async fn call_tool(
+        &self,
+        request: CallToolRequestParam,
+        _context: RequestContext<RoleServer>,
+    ) -> Result<CallToolResult, ErrorData> {
+        self.tool_calls
+            .lock()
+            .unwrap()
+            .push(request.name.to_string());
+
+        let text = self
+            .tool_results
+            .lock()
+            .unwrap()
+            .get_mut(request.name.as_ref())
+            .and_then(VecDeque::pop_front)
+            .unwrap_or_else(|| format!("{} {} output", self.name, request.name));
+
+        Ok(CallToolResult::success(vec![Content::text(text)]))
+    }
+}
@@ -0,0 +1,133 @@
+use crate::error::Error;
+use serde::Serialize;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::Path;
+use tracing::warn;
+
+///
+/// One prompt iteration of an [`crate::agent_loop::AgentLoop`], as a fine-tuning example
+#[derive(Debug, Clone)]
+pub struct TrainingExample {
+    /// The evaluated preamble the iteration finished with
+    pub system: String,
+
+    /// The evaluated loop prompt that started the iteration
+    pub prompt: String,
+
+    /// The text of the final completion in the iteration
+    pub response: String,
+
+    /// The number of completions made during the iteration
+    pub tool_iterations: usize,
+
+    /// The number of tools used during the iteration
+    pub tools_used: u32,
+}
+
+///
+/// A predicate deciding whether a [`TrainingExample`] should be exported.  See
+/// [`TrainingDataExporter::filter`].
+pub type TrainingExampleFilter = Box<dyn Fn(&TrainingExample) -> bool + Send>;
+
+///
+/// Writes prompt iterations as fine-tuning examples, one JSON object per line.  Each line uses
+/// the common chat format:
+///
+/// ```json
+/// {"messages":[{"role":"system","content":"..."},{"role":"user","content":"..."},{"role":"assistant","content":"..."}]}
+/// ```
+///
+/// Attach an exporter to an agent loop with
+/// [`crate::agent_loop::AgentLoop::training_data_exporter`].  Iterations that end without any
+/// response text are never exported.
+pub struct TrainingDataExporter {
+    sink: Box<dyn Write + Send>,
+    filter: Option<TrainingExampleFilter>,
+}
+
+#[derive(Serialize)]
+struct ChatExample<'a> {
+    messages: [ChatMessage<'a>; 3],
+}
+
+#[derive(Serialize)]
+struct ChatMessage<'a> {
+    role: &'static str,
+    content: &'a str,
+}
+
+impl TrainingDataExporter {
+    ///
+    /// Creates a new exporter that writes examples to the given sink
+    pub fn new(sink: impl Write + Send + 'static) -> Self {
+        Self {
+            sink: Box::new(sink),
+            filter: None,
+        }
+    }
+
+    ///
+    /// Creates a new exporter that appends examples to a file.  The file is created if it does not
+    /// exist.
+    pub fn append_to_file(path: impl AsRef<Path>) -> Result<Self, Error> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(Error::TrainingDataIoError)?;
+
+        Ok(Self::new(file))
+    }
+
+    ///
+    /// Only export iterations for which the predicate returns true.  This can be used to keep only
+    /// "good" iterations, for example, iterations that finished without hitting the tool quota.
+    pub fn filter(mut self, filter: impl Fn(&TrainingExample) -> bool + Send + 'static) -> Self {
+        self.filter = Some(Box::new(filter));
+        self
+    }
+
+    ///
+    /// Writes one example, if it passes the filter.  Failing to write generates a warning.
+    pub(crate) fn export(&mut self, example: &TrainingExample) {
+        if example.response.is_empty() {
+            return;
+        }
+
+        if let Some(filter) = &self.filter
+            && !filter(example)
+        {
+            return;
+        }
+
+        let line = ChatExample {
+            messages: [
+                ChatMessage {
+                    role: "system",
+                    content: &example.system,
+                },
+                ChatMessage {
+                    role: "user",
+                    content: &example.prompt,
+                },
+                ChatMessage {
+                    role: "assistant",
+                    content: &example.response,
+                },
+            ],
+        };
+
+        let res = serde_json::to_vec(&line)
+            .map_err(std::io::Error::from)
+            .and_then(|mut json| {
+                json.push(b'\n');
+                self.sink.write_all(&json)
+            })
+            .and_then(|_| self.sink.flush());
+
+        if let Err(e) = res {
+            warn!("Failed to write training example: {e}");
+        }
+    }
+}